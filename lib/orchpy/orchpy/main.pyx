@@ -5,12 +5,13 @@ from __future__ import unicode_literals
 #cython.boundscheck=False
 cimport cython
 from cpython cimport array
-from libc.stdint cimport uint16_t
+from libc.stdint cimport uint16_t, int32_t, int64_t, uint64_t
 import array
 import cprotobuf
 import numpy as np
 import orchpy.unison as unison
 import orchpy.protos_pb as pb
+import traceback
 import types
 
 # see http://python-future.org/stdlib_incompatibilities.html
@@ -109,44 +110,130 @@ cpdef deserialize_args(args, types):
       result.append(unison.deserialize(args.data[-elem - 1], arg_type))
   return result
 
+cpdef serialize_resources(resources):
+  """Serialize a dictionary like {"CPU": 2, "GPU": 1}, None means default resources."""
+  if resources is None:
+    return b""
+  result = pb.Resources()
+  for (name, quantity) in resources.items():
+    resource = result.resource.add()
+    resource.name = name
+    resource.quantity = quantity
+  return result.SerializeToString()
+
 cdef struct Slice:
   size_t size
   char* ptr
 
-cdef extern void* orchestra_create_context(const char* server_addr, uint16_t reply_port, uint16_t publish_port, const char* client_addr, uint16_t client_port)
-cdef extern size_t orchestra_register_function(void* context, const char* name)
-cdef extern size_t orchestra_step(void* context)
+cdef extern void* orchestra_create_context(const char* server_addr, uint16_t reply_port, uint16_t publish_port, const char* client_addr, uint16_t client_port, size_t num_slots, const char* resources, size_t resourceslen)
+cdef extern void* orchestra_create_driver_context(const char* server_addr, uint16_t reply_port, uint16_t publish_port, const char* client_addr, uint16_t client_port)
+cdef extern int64_t orchestra_register_function(void* context, const char* name, const char* resources, size_t resourceslen, int pure)
+cdef extern int64_t orchestra_step(void* context)
 cdef extern Slice orchestra_get_args(void* context)
 cdef extern size_t orchestra_function_index(void* context)
-cdef extern size_t orchestra_call(void* context, const char* name, const char* args, size_t argslen)
-cdef extern void orchestra_map(void* context, char* name, char* args, size_t argslen, size_t* retlist)
+cdef extern int64_t orchestra_call(void* context, const char* name, const char* args, size_t argslen, int32_t priority, const char* resources, size_t resourceslen)
+cdef extern int orchestra_map(void* context, char* name, char* args, size_t argslen, size_t* retlist, int32_t priority, const char* resources, size_t resourceslen)
+cdef extern int64_t orchestra_register_manifest(void* context, const char* manifest, size_t manifestlen)
 cdef extern void orchestra_store_result(void* context, size_t objref, char* data, size_t datalen)
+cdef extern void orchestra_finish(void* context, size_t objref)
+cdef extern void orchestra_fail(void* context, size_t objref, const char* error)
 cdef extern size_t orchestra_get_obj_len(void* Context, size_t objref)
 cdef extern char* orchestra_get_obj_ptr(void* context, size_t objref)
-cdef extern size_t orchestra_pull(void* context, size_t objref)
+cdef extern int64_t orchestra_create_actor(void* context, const char* name, const char* args, size_t argslen, size_t* result)
+cdef extern int64_t orchestra_call_actor(void* context, uint64_t actorid, const char* name, const char* args, size_t argslen)
+cdef extern int64_t orchestra_actor_id(void* context)
+cdef extern int orchestra_put_name(void* context, const char* name, size_t objref, int overwrite)
+cdef extern int64_t orchestra_get_name(void* context, const char* name)
+cdef extern Slice orchestra_list_names(void* context, const char* prefix)
+cdef extern int64_t orchestra_pull(void* context, size_t objref)
+cdef extern int64_t orchestra_wait(void* context, size_t objref)
 cdef extern size_t orchestra_push(void* context)
+cdef extern size_t orchestra_put(void* context, char* data, size_t datalen)
 cdef extern void orchestra_debug_info(void* context)
+cdef extern void orchestra_set_tracing(void* context, int enabled)
+cdef extern int orchestra_write_trace(void* context, const char* filename)
+cdef extern int orchestra_write_server_trace(void* context, const char* filename)
+cdef extern Slice orchestra_export_graph(void* context, const char* format)
+cdef extern Slice orchestra_lineage(void* context, size_t objref, size_t depth, int descendants)
+cdef extern void orchestra_release(void* context, size_t objref)
+cdef extern void orchestra_set_dedup(void* context, int enabled)
 cdef extern void orchestra_destroy_context(void* context)
 
 cdef class Context:
   cdef void* context
   cdef public list functions
   cdef public list arg_types
+  cdef public dict actors
 
   def __cinit__(self):
     self.context = NULL
     self.functions = []
     self.arg_types = []
+    self.actors = {} # instances of the actors that live on this worker by actor id
+
+  def connect(self, server_addr, reply_port, publish_port, client_addr, client_port, num_slots=1, resources=None):
+    res = serialize_resources(resources)
+    self.context = orchestra_create_context(server_addr, reply_port, publish_port, client_addr, client_port, num_slots, res, len(res))
 
-  def connect(self, server_addr, reply_port, publish_port, client_addr, client_port):
-    self.context = orchestra_create_context(server_addr, reply_port, publish_port, client_addr, client_port)
+  def connect_driver(self, server_addr, reply_port, publish_port, client_addr, client_port):
+    """Connect as a driver. A driver submits calls and gets their results, but it is not a worker:
+    the server never assigns calls to it."""
+    self.context = orchestra_create_driver_context(server_addr, reply_port, publish_port, client_addr, client_port)
 
   def close(self):
+    """Disconnect from the server. Calls that are still queued are dropped and the objects this
+    client created are freed, unless they are bound to a name or used by other clients."""
     orchestra_destroy_context(self.context)
 
   def debug_info(self):
     orchestra_debug_info(self.context)
 
+  def set_tracing(self, enabled):
+    """Record a timeline of the calls evaluated here from now on."""
+    orchestra_set_tracing(self.context, 1 if enabled else 0)
+
+  def write_trace(self, filename):
+    """Write the timeline of the calls evaluated here as Chrome trace JSON, see set_tracing."""
+    if orchestra_write_trace(self.context, filename) != 0:
+      raise Exception("Could not write trace to " + str(filename))
+
+  def write_server_trace(self, filename):
+    """Write the timeline of all calls recorded by the server as Chrome trace JSON."""
+    if orchestra_write_server_trace(self.context, filename) != 0:
+      raise Exception("Could not write trace to " + str(filename))
+
+  def set_dedup(self, enabled):
+    """Share the data of objects with the same content, here and across workers."""
+    orchestra_set_dedup(self.context, 1 if enabled else 0)
+
+  def export_graph(self, format=b"json"):
+    """Return the computation graph as dot, json or graphml text."""
+    slice = orchestra_export_graph(self.context, format)
+    if slice.size == 0:
+      raise Exception("Could not export the graph as " + str(format))
+    return PyBytes_FromStringAndSize(slice.ptr, slice.size)
+
+  def lineage(self, objref, depth=0, descendants=False):
+    """Return the name of the function that computed objref (None if it was pushed) and the
+    objects it was computed from, or the objects computed from it if descendants is set,
+    following at most depth calls (0 follows all of them)."""
+    slice = orchestra_lineage(self.context, objref.get_id(), depth, 1 if descendants else 0)
+    if slice.size == 0:
+      raise Exception("Object " + str(objref.get_id()) + " does not exist")
+    lineage = pb.Lineage()
+    lineage.ParseFromString(PyBytes_FromStringAndSize(slice.ptr, slice.size))
+    return (lineage.fnname or None, [ObjRef(o) for o in lineage.objrefs])
+
+  def release(self, objref):
+    """Tell the server that objref is no longer used, so its lineage can be pruned."""
+    orchestra_release(self.context, objref.get_id())
+
+  def ancestors(self, objref, depth=0):
+    return self.lineage(objref, depth)[1]
+
+  def descendants(self, objref, depth=0):
+    return self.lineage(objref, depth, descendants=True)[1]
+
   cpdef get_object(self, ObjRef objref, type):
     index = objref.get_id()
     ptr = orchestra_get_obj_ptr(self.context, index)
@@ -155,104 +242,271 @@ cdef class Context:
     return unison.deserialize(data, type)
 
   def main_loop(self):
-    cdef size_t objref = 0
+    """Evaluate the calls assigned to this worker until the server shuts it down."""
+    cdef int64_t objref = 0
     while True:
       objref = orchestra_step(self.context)
-      fnidx = orchestra_function_index(self.context)
-      slice = orchestra_get_args(self.context)
-      data = PyBytes_FromStringAndSize(slice.ptr, slice.size)
-      func = self.functions[fnidx]
-      args = pb.Args()
-      args.ParseFromString(data)
-      result = func(args)
-      orchestra_store_result(self.context, objref, result, len(result))
+      if objref < 0:
+        self.close()
+        return
+      self.evaluate(objref)
+
+  def evaluate(self, objref):
+    """Evaluate the call computing objref that was just handed out and report its result."""
+    fnidx = orchestra_function_index(self.context)
+    slice = orchestra_get_args(self.context)
+    data = PyBytes_FromStringAndSize(slice.ptr, slice.size)
+    func = self.functions[fnidx]
+    args = pb.Args()
+    args.ParseFromString(data)
+    actorid = self.actor_id()
+    try:
+      if actorid is None:
+        result = func(args)
+      else:
+        # constructors and methods of actors get the instance of their actor and return it
+        (self.actors[actorid], result) = func(self.actors.get(actorid), args)
+    except Exception:
+      error = traceback.format_exc()
+      orchestra_fail(self.context, objref, error.encode("utf-8", "replace"))
+      return
+    orchestra_store_result(self.context, objref, result, len(result))
+    orchestra_finish(self.context, objref)
 
   """Args is serialized version of the arguments."""
-  def call(self, func_name, module_name, arglist):
+  def call(self, func_name, module_name, arglist, priority=0, resources=None):
     args = serialize_args(arglist).SerializeToString()
-    return ObjRef(orchestra_call(self.context, module_name + "." + func_name, args, len(args)))
+    res = serialize_resources(resources)
+    objref = orchestra_call(self.context, module_name + "." + func_name, args, len(args), priority, res, len(res))
+    if objref < 0:
+      raise Exception("Function " + module_name + "." + func_name + " is not registered by any worker")
+    return ObjRef(objref)
 
-  def map(self, func, arglist):
+  def map(self, func, arglist, priority=0, resources=None):
     arraytype = bytes_to_native_str(b'L')
     args = serialize_args(arglist).SerializeToString()
+    res = serialize_resources(resources)
     cdef array.array result = array.array(arraytype, len(arglist) * [0]) # TODO(pcmoritz) This might be slow
-    orchestra_map(self.context, func.name, args, len(args), <size_t*>result.data.as_voidptr)
+    if orchestra_map(self.context, func.name, args, len(args), <size_t*>result.data.as_voidptr, priority, res, len(res)) != 0:
+      raise Exception("Function " + func.name + " is not registered by any worker")
     retlist = []
     for elem in result:
       retlist.append(ObjRef(elem))
     return retlist
 
+  def create_actor(self, class_name, module_name, arglist):
+    """Create an actor on some worker by calling the remote constructor, return the actor id."""
+    args = serialize_args(arglist).SerializeToString()
+    cdef size_t result = 0
+    actorid = orchestra_create_actor(self.context, module_name + "." + class_name, args, len(args), &result)
+    if actorid < 0:
+      raise Exception("Constructor " + module_name + "." + class_name + " is not registered by any worker")
+    return actorid
+
+  def call_actor(self, actorid, class_name, module_name, method_name, arglist):
+    """Call a remote method on an actor, calls on the same actor are evaluated in order."""
+    args = serialize_args(arglist).SerializeToString()
+    name = module_name + "." + class_name + "." + method_name
+    objref = orchestra_call_actor(self.context, actorid, name, args, len(args))
+    if objref < 0:
+      raise Exception("Actor " + str(actorid) + " does not exist or its worker has been drained")
+    return ObjRef(objref)
+
+  def actor_id(self):
+    """The actor the function that is currently evaluated is called on or None."""
+    actorid = orchestra_actor_id(self.context)
+    return None if actorid < 0 else actorid
+
   """Register a function that can be called remotely."""
-  def register(self, func_name, module_name, function, *args):
-    fnid = orchestra_register_function(self.context, module_name + "." + func_name)
+  def register(self, func_name, module_name, function, *args, resources=None, pure=False):
+    res = serialize_resources(resources)
+    fnid = orchestra_register_function(self.context, module_name + "." + func_name, res, len(res), 1 if pure else 0)
+    if fnid < 0:
+      raise Exception("Drivers can't register functions")
     assert(fnid == len(self.functions))
     self.functions.append(function)
     self.arg_types.append(args)
 
+  def register_manifest(self, module_name, functions):
+    """Register several functions that can be called remotely in one message. functions is a list
+    of (func_name, function, types, resources, pure) tuples."""
+    manifest = pb.Manifest()
+    for (func_name, function, types, resources, pure) in functions:
+      signature = manifest.functions.add()
+      signature.fnname = module_name + "." + func_name
+      if resources is not None:
+        signature.resources.ParseFromString(serialize_resources(resources))
+      signature.pure = pure
+      if len(types) == 0 or types[-1] is not None: # None at the end repeats the type before it
+        signature.arity = len(types)
+      for arg_type in types:
+        if arg_type is not None:
+          signature.arg_types.append(getattr(arg_type, "__name__", str(arg_type)))
+    data = manifest.SerializeToString()
+    fnid = orchestra_register_manifest(self.context, data, len(data))
+    if fnid < 0:
+      raise Exception("Drivers can't register functions")
+    assert(fnid == len(self.functions))
+    for (func_name, function, types, resources, pure) in functions:
+      self.functions.append(function)
+      self.arg_types.append(tuple(types))
+
+  def put_name(self, name, objref, overwrite=False):
+    """Bind a name to an object so other drivers can find it."""
+    if orchestra_put_name(self.context, name, objref.get_id(), 1 if overwrite else 0) != 0:
+      raise Exception("Name " + str(name) + " is already bound")
+
+  def get_name(self, name):
+    """Return the object bound to a name or None."""
+    objref = orchestra_get_name(self.context, name)
+    return None if objref < 0 else ObjRef(objref)
+
+  def list_names(self, prefix=b""):
+    """Return a dictionary of all names starting with prefix and their objects."""
+    slice = orchestra_list_names(self.context, prefix)
+    names = pb.Names()
+    names.ParseFromString(PyBytes_FromStringAndSize(slice.ptr, slice.size))
+    return dict((info.name, ObjRef(info.objref)) for info in names.name_info)
+
   def pull(self, type, objref):
+    failed = Exception("Object " + str(objref.get_id()) + " failed, the call computing it or one of its arguments raised an error")
     objref = orchestra_pull(self.context, objref.get_id())
+    if objref < 0:
+      raise failed
+    # the slot of the call that pulls is lent, calls assigned to it meanwhile are evaluated here
+    ready = orchestra_wait(self.context, objref)
+    while ready != objref:
+      if ready < 0:
+        raise failed
+      self.evaluate(ready)
+      ready = orchestra_wait(self.context, objref)
     return self.get_object(ObjRef(objref), type)
 
   def push(self, obj):
     buf = bytearray()
     unison.serialize(buf, obj)
-    objref = orchestra_push(self.context)
-    orchestra_store_result(self.context, objref, buf, len(buf))
-    return ObjRef(objref)
+    return ObjRef(orchestra_put(self.context, buf, len(buf)))
 
 context = Context()
 
-def distributed(types, return_type):
+def get_arguments(args, types, name):
+  """Deserialize the arguments of a call of the function name, objects are taken from the local store."""
+  arguments = []
+  protoargs = deserialize_args(args, types)
+  for (i, proto) in enumerate(protoargs):
+    if type(proto) == ObjRef:
+      if i < len(types) - 1:
+        arguments.append(context.get_object(proto, types[i]))
+      elif i == len(types) - 1 and types[-1] is not None:
+        arguments.append(context.get_object(proto, types[i]))
+      elif types[-1] is None:
+        arguments.append(context.get_object(proto, types[-2]))
+      else:
+        raise Exception("Passed in " + str(len(args)) + " arguments to function " + name + ", which takes only " + str(len(types)) + " arguments.")
+    else:
+      arguments.append(proto)
+  return arguments
+
+def serialize_result(result, return_type, name):
+  if unison.unison_type(result) != return_type:
+    raise Exception("Return type of " + name + " does not match the return type specified in the decorator, was expecting " + str(return_type) + " but received " + str(unison.unison_type(result)))
+  buf = bytearray()
+  unison.serialize(buf, result)
+  return memoryview(buf).tobytes()
+
+def distributed(types, return_type, resources=None, pure=False):
     def distributed_decorator(func):
         # deserialize arguments, execute function and serialize result
         def func_executor(args):
-            arguments = []
-            protoargs = deserialize_args(args, types)
-            for (i, proto) in enumerate(protoargs):
-              if type(proto) == ObjRef:
-                if i < len(types) - 1:
-                  arguments.append(context.get_object(proto, types[i]))
-                elif i == len(types) - 1 and types[-1] is not None:
-                  arguments.append(context.get_object(proto, types[i]))
-                elif types[-1] is None:
-                  arguments.append(context.get_object(proto, types[-2]))
-                else:
-                  raise Exception("Passed in " + str(len(args)) + " arguments to function " + func.__name__ + ", which takes only " + str(len(types)) + " arguments.")
-              else:
-                arguments.append(proto)
-            buf = bytearray()
-            result = func(*arguments)
-            if unison.unison_type(result) != return_type:
-              raise Exception("Return type of " + func.func_name + " does not match the return type specified in the @distributed decorator, was expecting " + str(return_type) + " but received " + str(unison.unison_type(result)))
-            unison.serialize(buf, result)
-            return memoryview(buf).tobytes()
+            result = func(*get_arguments(args, types, func.__name__))
+            return serialize_result(result, return_type, func.__name__)
         # for remotely executing the function
-        def func_call(*args, typecheck=False):
+        def func_call(*args, typecheck=False, priority=0, resources=None):
           if typecheck:
             check_types(args, func_call.types)
-          return context.call(func_call.func_name, func_call.module_name, args)
+          return context.call(func_call.func_name, func_call.module_name, args, priority, resources)
         func_call.func_name = func.__name__.encode() # why do we call encode()?
         func_call.module_name = func.__module__.encode() # why do we call encode()?
         func_call.is_distributed = True
         func_call.executor = func_executor
         func_call.types = types
+        func_call.resources = resources
+        func_call.pure = pure
         return func_call
     return distributed_decorator
 
+def method(types, return_type):
+  """Mark a method of an actor class as callable on the actor's instance."""
+  def method_decorator(func):
+    func.is_method = True
+    func.types = types
+    func.return_type = return_type
+    return func
+  return method_decorator
+
+class ActorHandle(object):
+  """Refers to an actor that lives on one worker."""
+  def __init__(self, actorid, class_name, module_name):
+    self.actorid = actorid
+    self.class_name = class_name
+    self.module_name = module_name
+
+  def call(self, method_name, *args):
+    """Call a method decorated with @method, calls are evaluated in the order they are made."""
+    return context.call_actor(self.actorid, self.class_name, self.module_name, method_name.encode(), args)
+
+def actor(types):
+  """Turn a class whose constructor takes arguments of the given types into an actor. Calling the
+  result creates an instance on some worker and returns an ActorHandle."""
+  def actor_decorator(cls):
+    def constructor_executor(instance, args):
+      instance = cls(*get_arguments(args, types, cls.__name__))
+      return (instance, serialize_result(context.actor_id(), int, cls.__name__))
+    def method_executor(func):
+      def executor(instance, args):
+        result = func(instance, *get_arguments(args, func.types, func.__name__))
+        return (instance, serialize_result(result, func.return_type, func.__name__))
+      return executor
+    def actor_create(*args):
+      actorid = context.create_actor(actor_create.func_name, actor_create.module_name, args)
+      return ActorHandle(actorid, actor_create.func_name, actor_create.module_name)
+    actor_create.func_name = cls.__name__.encode()
+    actor_create.module_name = cls.__module__.encode()
+    actor_create.is_distributed = True
+    actor_create.executor = constructor_executor
+    actor_create.types = types
+    actor_create.resources = None
+    actor_create.pure = False
+    # methods are registered as "module.Class.method"
+    actor_create.methods = [(name.encode(), method_executor(val), val.types) for (name, val) in cls.__dict__.items() if getattr(val, "is_method", False)]
+    return actor_create
+  return actor_decorator
+
+def manifest_entries(name, val):
+  """The functions to register for a distributed function or actor, see Context.register_manifest."""
+  entries = [(name.encode(), val.executor, val.types, val.resources, val.pure)]
+  for (method_name, executor, types) in getattr(val, "methods", []):
+    entries.append((name.encode() + b"." + method_name, executor, types, None, False))
+  return entries
+
 def register_current():
+  functions = []
   for (name, val) in globals().items():
     try:
       if val.is_distributed:
-        context.register(name.encode(), __name__, val.executor, *val.types)
+        functions.extend(manifest_entries(name, val))
     except AttributeError:
       pass
+  context.register_manifest(__name__, functions)
 
 def register_distributed(module):
+    functions = []
     moduledir = dir(module)
     for name in moduledir:
         val = getattr(module, name)
         try:
             if val.is_distributed:
-                context.register(name.encode(), module.__name__, val.executor, *val.types)
+                functions.extend(manifest_entries(name, val))
         except AttributeError:
             pass
+    context.register_manifest(module.__name__, functions)
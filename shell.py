@@ -16,7 +16,7 @@ parser.add_argument('shell_port', type=int, help='the port at which the client l
 
 if __name__ == '__main__':
   args = parser.parse_args()
-  op.context.connect(args.server_address, args.server_port, args.publish_port, args.client_address, args.shell_port)
+  op.context.connect_driver(args.server_address, args.server_port, args.publish_port, args.client_address, args.shell_port)
 
   def test_dot(d1, d2, d3):
       print "testing dot with d1 = " + str(d1) + ", d2 = " + str(d2) + ", d3 = " + str(d3)
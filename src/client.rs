@@ -1,12 +1,13 @@
-use std::collections::HashMap;
-use zmq;
-use zmq::{Socket};
+use std::collections::{HashMap, HashSet};
 
 use comm;
-use utils::{ObjRef, WorkerID, receive_message, send_message, receive_subscription, send_ack, receive_ack, connect_socket, to_zmq_socket_addr};
+use trace::{Trace, now_us, worker_pid};
+use utils::{ObjRef, WorkerID, ActorID, ResourceMap, receive_message, send_message, send_ack, receive_ack, resources_to_proto, content_hash};
+use transport::{MessageSource, MessageTransport, Network};
 use std::thread;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::MutexGuard;
 use std::str::FromStr;
 use std::net::IpAddr;
@@ -17,43 +18,61 @@ use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 
 pub type FnRef = usize; // Index of locally registered function
-pub type ObjStore = HashMap<ObjRef, Vec<u8>>; // collection of objects stored on the client
+pub type ObjStore = HashMap<ObjRef, Arc<Vec<u8>>>; // collection of objects stored on the client, objects with the same content share their data
+
+const HEARTBEAT_INTERVAL: u32 = 1000; // milliseconds between two heartbeats, see `server::HEARTBEAT_INTERVAL`
 
 pub enum Event {
     Obj(ObjRef), // a new object becomes available
     Invoke(comm::Call), // a new job request
+    Shutdown, // the server shut down this worker
+    Failed(ObjRef), // an object this client pulled never becomes available
+    Free(ObjRef), // the server deleted an object
     Debug(comm::Message) // for debugging purposes
 }
 
+/// A call that was assigned to this worker. It can be evaluated once all its dependencies arrived.
 #[derive(Clone, PartialEq)]
-pub enum State {
-    Processing {
-        call: comm::Call,
-        deps: Vec<ObjRef> // sorted
-    },
-    Waiting
+pub struct Task {
+    call: comm::Call,
+    deps: Vec<ObjRef>, // sorted
+    timing: comm::TaskTiming
 }
 
 pub struct Context {
-    zmq_ctx: zmq::Context,
+    network: Network,
 
     objects: Arc<Mutex<ObjStore>>, // mapping from objrefs to data
     functions: HashMap<String, FnRef>, // mapping from function name to interpreter-local function reference
     types: HashMap<String, i32>, // mapping from type name to type id
 
-    state: State, // Some(call) if function call has just been evaluated and None otherwise
+    tasks: Vec<Task>, // calls assigned to this worker that are waiting for their dependencies, in order of arrival
+    running: Vec<Task>, // calls that have been handed out by client_step and are not finished yet
+    trace: Trace, // timeline of the calls evaluated on this worker, only recorded if enabled
+    num_slots: usize, // number of calls the server may assign to this worker at the same time
+    blocked: Vec<(ObjRef, ObjRef)>, // running calls that lent their slot while pulling an object, as (result, pulled object), innermost last
+    owed: usize, // slots that were lent and may still be in use, each finished call gives one back
+    shutdown: bool, // the server shut down this worker, it gets no more calls
+    closed: Arc<AtomicBool>, // the session is closed, stops the heartbeats
+    failed: HashSet<ObjRef>, // pulled objects that never become available
+    dedup: bool, // send content hashes of stored objects so the server can find duplicates
+    hashes: HashMap<ObjRef, Vec<u8>>, // content hashes of the objects stored by this client
+    pushed: HashSet<ObjRef>, // objects allocated by `push_remote_object` whose data is not stored yet
+    contents: HashMap<Vec<u8>, ObjRef>, // an object stored by this client for each content hash
     function: FnRef, // function that is currently active
+    actor: Option<ActorID>, // actor the currently active function is called on
     pub args: Vec<u8>, // serialized version of the Args datastructure
+    pub result: Vec<u8>, // serialized answer of the last query that returns a protocol buffer
 
     notify_main: Receiver<Event>, // reply thread signals main thread
-    request: Socket,
+    request: Box<MessageTransport>,
     workerid: WorkerID
 }
 
 impl Context {
-    pub fn start_reply_thread(zmq_ctx: &mut zmq::Context, client_addr: &str, notify_main: Sender<Event>, objects: Arc<Mutex<ObjStore>>) {
-        let mut reply = zmq_ctx.socket(zmq::REP).unwrap();
-        reply.bind(client_addr).unwrap();
+    pub fn start_reply_thread(network: &Network, client_port: u16, notify_main: Sender<Event>, objects: Arc<Mutex<ObjStore>>) {
+        let localhost = IpAddr::from_str("0.0.0.0").unwrap();
+        let (mut reply, _) = network.bind_reply(&localhost, Some(client_port));
 
         thread::spawn(move || {
             loop {
@@ -63,7 +82,7 @@ impl Context {
                         let blob = msg.get_blob();
                         let objref = blob.get_objref();
                         {
-                            objects.lock().unwrap().insert(objref, blob.get_data().to_vec());
+                            objects.lock().unwrap().insert(objref, Arc::new(blob.get_data().to_vec()));
                         }
                         send_ack(&mut reply);
                         notify_main.send(Event::Obj(objref)).unwrap();
@@ -80,23 +99,50 @@ impl Context {
             }
         });
     }
-    pub fn new(server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_addr: &IpAddr, client_port: u16) -> Context {
-        let mut zmq_ctx = zmq::Context::new();
-
-        let mut request = zmq_ctx.socket(zmq::REQ).unwrap();
-        request.connect(&to_zmq_socket_addr(server_addr, reply_port)[..]).unwrap();
+    /// Connect to the server and register as a worker that accepts up to `num_slots` calls at the
+    /// same time, 0 counts as 1. If `resources` is `None`, the worker has one CPU per slot.
+    pub fn new(server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_addr: &IpAddr, client_port: u16, num_slots: usize, resources: Option<ResourceMap>) -> Context {
+        return Context::with_network(Network::tcp(), server_addr, reply_port, publish_port, client_addr, client_port, num_slots, resources);
+    }
 
-        let (reply_sender, reply_receiver) = mpsc::channel(); // TODO: rename this
+    /// Connect to the server and register as a driver. A driver submits calls, pushes objects
+    /// and pulls results, but the server never assigns calls to it.
+    pub fn new_driver(server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_addr: &IpAddr, client_port: u16) -> Context {
+        return Context::driver_with_network(Network::tcp(), server_addr, reply_port, publish_port, client_addr, client_port);
+    }
 
-        info!("connecting to server...");
+    /// Like `new`, but the connections are created by `network`. With `Protocol::Inproc`, it must
+    /// use the context of the server, with `Protocol::Memory` it must be a clone of the server's.
+    pub fn with_network(network: Network, server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_addr: &IpAddr, client_port: u16, num_slots: usize, resources: Option<ResourceMap>) -> Context {
+        let num_slots = if num_slots > 0 { num_slots } else { 1 }; // the server does the same
         let mut reg = comm::Message::new();
         reg.set_field_type(comm::MessageType::REGISTER_CLIENT);
-        reg.set_address(to_zmq_socket_addr(client_addr, client_port));
+        reg.set_address(network.address(client_addr, client_port));
+        reg.set_num_slots(num_slots as u64);
+        if let Some(resources) = resources {
+            reg.set_resources(resources_to_proto(&resources));
+        }
+        return Context::register(network, server_addr, reply_port, publish_port, client_port, num_slots, reg);
+    }
+
+    /// Like `new_driver`, but the connections are created by `network`.
+    pub fn driver_with_network(network: Network, server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_addr: &IpAddr, client_port: u16) -> Context {
+        let mut reg = comm::Message::new();
+        reg.set_field_type(comm::MessageType::REGISTER_DRIVER);
+        reg.set_address(network.address(client_addr, client_port));
+        return Context::register(network, server_addr, reply_port, publish_port, client_port, 0, reg);
+    }
+
+    /// Send the registration message `reg` to the server and start the threads of the client.
+    fn register(network: Network, server_addr: &IpAddr, reply_port: u16, publish_port: u16, client_port: u16, num_slots: usize, mut reg: comm::Message) -> Context {
+        let mut request = network.connect_request(&network.address(server_addr, reply_port)[..]);
 
+        let (reply_sender, reply_receiver) = mpsc::channel(); // TODO: rename this
+
+        info!("connecting to server...");
         let objects = Arc::new(Mutex::new(HashMap::new()));
 
-        let localhost = IpAddr::from_str("0.0.0.0").unwrap();
-        Context::start_reply_thread(&mut zmq_ctx, &to_zmq_socket_addr(&localhost, client_port)[..], reply_sender.clone(), objects.clone());
+        Context::start_reply_thread(&network, client_port, reply_sender.clone(), objects.clone());
 
         thread::sleep_ms(10);
 
@@ -113,24 +159,27 @@ impl Context {
 
         // let (main_sender, main_receiver) = mpsc::channel();
         // let (network_sender, network_receiver) = mpsc::channel();
-        let mut clients: HashMap<String, Socket> = HashMap::new(); // other clients that are part of the cluster
+        let mut clients: HashMap<String, Box<MessageTransport>> = HashMap::new(); // other clients that are part of the cluster
+
+        let closed = Arc::new(AtomicBool::new(false));
+        Context::start_heartbeat_thread(&network, &network.address(server_addr, reply_port), workerid, closed.clone());
 
         let thread_objects = objects.clone();
         let server_addr = server_addr.clone();
+        let thread_network = network.clone();
 
         thread::spawn(move || {
-            let mut zmq_ctx = zmq::Context::new();
-            let mut subscriber = Context::connect_network_thread(&mut zmq_ctx, workerid, &server_addr, setup_port, publish_port);
+            let network = thread_network;
+            let mut subscriber = Context::connect_network_thread(&network, workerid, &server_addr, setup_port, publish_port);
 
             loop {
-                let msg = receive_subscription(&mut subscriber);
+                let msg = receive_message(&mut subscriber);
 
                 match msg.get_field_type() {
                     comm::MessageType::REGISTER_CLIENT => {
                         // push onto workers
                         info!("connecting to client {}", msg.get_address());
-                        let mut other = zmq_ctx.socket(zmq::REQ).unwrap();
-                        other.connect(msg.get_address()).ok().unwrap();
+                        let other = network.connect_request(msg.get_address());
                         clients.insert(msg.get_address().into(), other);
                         // new code: create new thread, insert it here
                     }
@@ -141,48 +190,86 @@ impl Context {
                         let mut blob = comm::Blob::new();
                         blob.set_objref(objref);
                         let data = {
-                            let objs : MutexGuard<HashMap<ObjRef, Vec<u8>>> = thread_objects.lock().unwrap();
-                            objs.get(&objref).and_then(|data| Some(data.to_vec())).expect("data not available on this client")
+                            let objs : MutexGuard<ObjStore> = thread_objects.lock().unwrap();
+                            objs.get(&objref).and_then(|data| Some(data.to_vec()))
+                        };
+                        let data = match data {
+                            Some(data) => data,
+                            None => {
+                                // the object has been freed in the meantime
+                                error!("object {} should be delivered to {} but is not available on this client", objref, msg.get_address());
+                                continue;
+                            }
                         };
                         blob.set_data(data);
                         answer.set_blob(blob);
-                        let target = &mut clients.get_mut(msg.get_address()).expect("target client not found"); // shouldn't happen
+                        if !clients.contains_key(msg.get_address()) {
+                            // drivers are not connected to the other clients up front
+                            info!("connecting to client {} on demand", msg.get_address());
+                            let other = network.connect_request(msg.get_address());
+                            clients.insert(msg.get_address().into(), other);
+                        }
+                        let target = clients.get_mut(msg.get_address()).unwrap();
                         send_message(target, &mut answer);
                         receive_ack(target);
                     },
                     comm::MessageType::DEBUG => {
                         reply_sender.send(Event::Debug(msg)).unwrap();
                     },
+                    comm::MessageType::FREE => {
+                        thread_objects.lock().unwrap().remove(&msg.get_objref());
+                        reply_sender.send(Event::Free(msg.get_objref())).unwrap(); // forget its content hash
+                    },
+                    comm::MessageType::SHUTDOWN => {
+                        info!("worker {} shut down by the server", workerid);
+                        reply_sender.send(Event::Shutdown).unwrap(); // keep serving deliveries until the main thread closed the session
+                    },
+                    comm::MessageType::FAILED => {
+                        reply_sender.send(Event::Failed(msg.get_objref())).unwrap();
+                    },
                     _ => {}
                 }
             }
         });
 
         return Context {
-            zmq_ctx: zmq_ctx,
+            network: network,
             objects: objects.clone(), functions: HashMap::new(), types: HashMap::new(),
-            state: State::Waiting, function: 0, args: Vec::new(),
+            tasks: Vec::new(), running: Vec::new(), trace: Trace::new(false), num_slots: num_slots, blocked: Vec::new(), owed: 0, shutdown: false, closed: closed, failed: HashSet::new(), dedup: false, hashes: HashMap::new(), pushed: HashSet::new(), contents: HashMap::new(), function: 0, actor: None, args: Vec::new(), result: Vec::new(),
             notify_main: reply_receiver,
             request: request,
             workerid: workerid
         }
     }
 
-    fn connect_network_thread(zmq_ctx: &mut zmq::Context, workerid: WorkerID, server_addr: &IpAddr, setup_port: u16, subscriber_port: u16) -> Socket {
-        let mut subscriber = zmq_ctx.socket(zmq::SUB).unwrap();
+    /// Tell the server that this client is still there until its session is closed.
+    fn start_heartbeat_thread(network: &Network, server_addr: &str, workerid: WorkerID, closed: Arc<AtomicBool>) {
+        let mut request = network.connect_request(server_addr);
+        thread::spawn(move || {
+            while !closed.load(Ordering::Relaxed) {
+                let mut msg = comm::Message::new();
+                msg.set_field_type(comm::MessageType::HEARTBEAT);
+                msg.set_workerid(workerid as u64);
+                send_message(&mut request, &mut msg);
+                receive_ack(&mut request);
+                thread::sleep_ms(HEARTBEAT_INTERVAL);
+            }
+        });
+    }
+
+    fn connect_network_thread(network: &Network, workerid: WorkerID, server_addr: &IpAddr, setup_port: u16, subscriber_port: u16) -> Box<MessageSource> {
         info!("subscriber_port {}", subscriber_port);
-        connect_socket(&mut subscriber, server_addr, subscriber_port);
-        subscriber.set_subscribe(format!("{:0>#07}", workerid).as_bytes()).unwrap();
+        let mut subscriber = network.subscribe(&network.address(server_addr, subscriber_port)[..], workerid);
 
-        let mut setup = zmq_ctx.socket(zmq::REQ).unwrap();
-        connect_socket(&mut setup, server_addr, setup_port);
+        let mut setup = network.connect_request(&network.address(server_addr, setup_port)[..]);
         info!("setup_port {}", setup_port);
         thread::sleep_ms(10);
         // set up sub/pub socket
-        let mut msg = zmq::Message::new().unwrap();
-        subscriber.recv(&mut msg, 0).unwrap();
+        receive_message(&mut subscriber);
 
-        setup.send(b"joining", 0).unwrap();
+        let mut joining = comm::Message::new();
+        joining.set_field_type(comm::MessageType::HELLO);
+        send_message(&mut setup, &mut joining);
 
         info!("accepted server invitation");
 
@@ -190,26 +277,84 @@ impl Context {
     }
 
     pub fn add_object<'b>(self: &'b mut Context, objref: ObjRef, data: Vec<u8>) {
-        self.objects.lock().unwrap().insert(objref, data);
+        let mut objects = self.objects.lock().unwrap();
+        if !self.dedup {
+            objects.insert(objref, Arc::new(data));
+            return;
+        }
+        let hash = content_hash(&data[..]);
+        self.hashes.insert(objref, hash.clone());
+        let existing = self.contents.get(&hash).and_then(|other| objects.get(other)).map(|other| other.clone());
+        match existing {
+            Some(ref other) if other[..] == data[..] => {
+                objects.insert(objref, other.clone()); // share the data of the stored object
+            },
+            _ => {
+                objects.insert(objref, Arc::new(data));
+                self.contents.insert(hash, objref);
+            }
+        }
+    }
+
+    /// Send content hashes with stored objects, so the server can let objects with the same
+    /// content share their data.
+    pub fn set_dedup<'b>(self: &'b mut Context, enabled: bool) {
+        self.dedup = enabled;
     }
 
-    pub fn add_function<'b>(self: &'b mut Context, name: String) -> usize {
+    /// Register a function with the server. Each call of the function needs `resources`, or one
+    /// CPU if they are `None`. Fails if this client is a driver.
+    pub fn add_function<'b>(self: &'b mut Context, name: String, resources: Option<ResourceMap>, pure: bool) -> Result<FnRef, String> {
         info!("registering function {}", name);
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+        msg.set_fnname(name.to_string());
+        msg.set_workerid(self.workerid as u64);
+        if let Some(resources) = resources {
+            msg.set_resources(resources_to_proto(&resources));
+        }
+        msg.set_pure(pure);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+
         let idx = self.functions.len();
         self.functions.insert(name.to_string(), idx);
+        return Ok(idx);
+    }
+
+    /// Register all functions of `manifest` with the server in one message. The functions get
+    /// consecutive indices in the order of the manifest, return the index of the first one. Fails
+    /// if this client is a driver.
+    pub fn add_manifest<'b>(self: &'b mut Context, manifest: comm::Manifest) -> Result<FnRef, String> {
+        info!("registering {} functions", manifest.get_functions().len());
+        let names: Vec<String> = manifest.get_functions().iter().map(|signature| signature.get_fnname().to_string()).collect();
 
         let mut msg = comm::Message::new();
-        msg.set_field_type(comm::MessageType::REGISTER_FUNCTION);
-        msg.set_fnname(name.to_string());
+        msg.set_field_type(comm::MessageType::REGISTER_MANIFEST);
         msg.set_workerid(self.workerid as u64);
+        msg.set_manifest(manifest);
         send_message(&mut self.request, &mut msg);
-        receive_ack(&mut self.request);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
 
-        return idx;
+        let first = self.functions.len();
+        for name in names {
+            let idx = self.functions.len();
+            self.functions.insert(name, idx);
+        }
+        return Ok(first);
     }
     pub fn get_function<'b>(self: &'b Context) -> FnRef {
         return self.function;
     }
+    pub fn get_actor<'b>(self: &'b Context) -> Option<ActorID> {
+        return self.actor;
+    }
     // TODO: Make this more efficient, i.e. use only one lookup
     pub fn get_obj_len<'b>(self: &'b Context, objref: ObjRef) -> Option<usize> {
         self.objects.lock().unwrap().get(&objref).and_then(|data| Some(data[..].len()))
@@ -224,37 +369,147 @@ impl Context {
     pub fn get_type<'b>(self: &'b mut Context, name: String) -> Option<i32> {
         return self.types.get(&name).and_then(|&num| Some(num));
     }
-    pub fn remote_call_function<'b>(self: &'b mut Context, name: String, args: comm::Args) -> ObjRef {
+    /// Submit a call of the function `name`. Fails if no worker registered the function.
+    pub fn remote_call_function<'b>(self: &'b mut Context, name: String, args: comm::Args, priority: i32, resources: Option<ResourceMap>) -> Result<ObjRef, String> {
         let mut msg = comm::Message::new();
         msg.set_field_type(comm::MessageType::INVOKE);
+        msg.set_workerid(self.workerid as u64);
         let mut call = comm::Call::new();
         call.set_name(name);
         call.set_args(args);
+        call.set_priority(priority);
+        if let Some(resources) = resources {
+            call.set_resources(resources_to_proto(&resources));
+        }
         msg.set_call(call);
         send_message(&mut self.request, &mut msg);
         let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
         let result = answer.get_call().get_result();
         assert!(result.len() == 1);
-        return result[0];
+        return Ok(result[0]);
     }
     // TODO: Remove duplication between remote_call_function and remote_call_map
-    pub fn remote_call_map<'b>(self: &'b mut Context, name: String, args: comm::Args) -> Vec<ObjRef> {
+    pub fn remote_call_map<'b>(self: &'b mut Context, name: String, args: comm::Args, priority: i32, resources: Option<ResourceMap>) -> Result<Vec<ObjRef>, String> {
         let mut msg = comm::Message::new();
         msg.set_field_type(comm::MessageType::INVOKE);
+        msg.set_workerid(self.workerid as u64);
         let mut call = comm::Call::new();
         call.set_field_type(comm::Call_Type::MAP_CALL);
         call.set_name(name);
         call.set_args(args);
+        call.set_priority(priority);
+        if let Some(resources) = resources {
+            call.set_resources(resources_to_proto(&resources));
+        }
         msg.set_call(call);
         send_message(&mut self.request, &mut msg);
         let answer = receive_message(&mut self.request);
-        return answer.get_call().get_result().to_vec(); // TODO: get rid of this copy
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        return Ok(answer.get_call().get_result().to_vec()); // TODO: get rid of this copy
+    }
+    /// Create an actor by calling the constructor `name` on some worker. Return the id of the actor
+    /// and the objref of the constructor's result. Fails if no worker registered the constructor.
+    pub fn remote_create_actor<'b>(self: &'b mut Context, name: String, args: comm::Args) -> Result<(ActorID, ObjRef), String> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::INVOKE);
+        msg.set_workerid(self.workerid as u64);
+        let mut call = comm::Call::new();
+        call.set_field_type(comm::Call_Type::ACTOR_CREATE);
+        call.set_name(name);
+        call.set_args(args);
+        msg.set_call(call);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        let result = answer.get_call().get_result();
+        assert!(result.len() == 1);
+        return Ok((answer.get_call().get_actorid(), result[0]));
+    }
+    /// Call the method `name` on an actor. Calls on the same actor are evaluated in the order they
+    /// are submitted. Fails if the actor doesn't exist or its worker has been drained.
+    pub fn remote_call_actor<'b>(self: &'b mut Context, actorid: ActorID, name: String, args: comm::Args) -> Result<ObjRef, String> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::INVOKE);
+        msg.set_workerid(self.workerid as u64);
+        let mut call = comm::Call::new();
+        call.set_field_type(comm::Call_Type::ACTOR_CALL);
+        call.set_actorid(actorid);
+        call.set_name(name);
+        call.set_args(args);
+        msg.set_call(call);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        let result = answer.get_call().get_result();
+        assert!(result.len() == 1);
+        return Ok(result[0]);
+    }
+    /// Bind `name` to an object on the server, so other clients can look it up.
+    pub fn put_name<'b>(self: &'b mut Context, name: String, objref: ObjRef, overwrite: bool) -> Result<(), String> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::PUT_NAME);
+        msg.set_name(name);
+        msg.set_objref(objref);
+        msg.set_overwrite(overwrite);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        return Ok(());
+    }
+    pub fn get_name<'b>(self: &'b mut Context, name: String) -> Option<ObjRef> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::GET_NAME);
+        msg.set_workerid(self.workerid as u64);
+        msg.set_name(name);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return None;
+        }
+        return Some(answer.get_objref());
+    }
+    pub fn list_names<'b>(self: &'b mut Context, prefix: String) -> comm::Names {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::LIST_NAMES);
+        msg.set_name(prefix);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        return answer.get_names().clone();
+    }
+    /// Pull an object and block until it arrived, or return an error if it never becomes
+    /// available. The slot of a running call is not lent to other calls meanwhile, use
+    /// `request_object` and `wait_for_object` for that.
+    pub fn pull_remote_object<'b>(self: &'b mut Context, objref: ObjRef) -> Result<ObjRef, String> {
+        let objref = match self.request_object(objref, false) {
+            Ok(objref) => objref,
+            Err(error) => return Err(error)
+        };
+        match self.wait_for_object(objref) {
+            Ok(ready) => assert!(ready == objref),
+            Err(error) => return Err(error)
+        }
+        return Ok(objref);
     }
-    pub fn pull_remote_object<'b>(self: &'b mut Context, objref: ObjRef) -> ObjRef {
+    /// Ask the server for an object and return the objref of the object that will be delivered, the
+    /// server may send an object with the same content instead. If `lend` is set and a call is
+    /// running, the server may assign other calls to its slot until the object arrived. Return an
+    /// error if the object failed.
+    pub fn request_object<'b>(self: &'b mut Context, objref: ObjRef, lend: bool) -> Result<ObjRef, String> {
         {
             let objects = self.objects.lock().unwrap();
             if objects.contains_key(&objref) {
-                return objref;
+                return Ok(objref);
             }
         }
         let mut msg = comm::Message::new();
@@ -262,28 +517,105 @@ impl Context {
         msg.set_objref(objref);
         msg.set_workerid(self.workerid as u64);
         send_message(&mut self.request, &mut msg);
-        receive_ack(&mut self.request);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        let objref = if answer.has_objref() { answer.get_objref() } else { objref };
+        if self.objects.lock().unwrap().contains_key(&objref) {
+            return Ok(objref);
+        }
+        // the innermost running call is the one that pulls
+        let running = self.running.last().map(|task| task.call.get_result()[0]);
+        match running {
+            Some(result) if lend => {
+                self.notify_blocked(comm::MessageType::BLOCKED, result);
+                self.blocked.push((result, objref));
+            },
+            _ => {}
+        }
+        return Ok(objref);
+    }
+    /// Wait for an object requested with `request_object` and return its objref once it arrived.
+    /// If the slot of the pulling call was lent and a call assigned to this worker meanwhile is
+    /// ready, it is handed out like in `client_step` and its result is returned instead. This
+    /// client is the only one that can evaluate that call, so the caller must evaluate it, finish it
+    /// and wait again. Return an error if the server reports that the object failed.
+    pub fn wait_for_object<'b>(self: &'b mut Context, objref: ObjRef) -> Result<ObjRef, String> {
+        let lent = self.blocked.last().map_or(false, |&(_, pulled)| pulled == objref);
         loop {
-            // println!("looping");
-            match self.notify_main.recv().unwrap() {
-                Event::Obj(pushedref) => {
-                    if pushedref == objref {
-                        return objref;
-                    }
+            let failed = self.failed.contains(&objref);
+            if failed || self.objects.lock().unwrap().contains_key(&objref) {
+                if lent {
+                    let (result, _) = self.blocked.pop().unwrap();
+                    self.notify_blocked(comm::MessageType::UNBLOCKED, result);
+                    self.owed += 1; // the slot is still in use if a call was assigned to it
+                }
+                if failed {
+                    return Err(format!("object {} failed, it never becomes available", objref));
                 }
-                _ => {}
+                return Ok(objref);
             }
+            if lent {
+                match self.start_ready_task() {
+                    Some(result) => return Ok(result),
+                    None => {}
+                }
+            }
+            let event = self.notify_main.recv().unwrap();
+            self.handle_event(event);
         }
     }
-    pub fn push_remote_object<'b>(self: &'b mut Context) -> ObjRef {
+    /// Tell the server that the call computing `result` is blocked or continues.
+    fn notify_blocked<'b>(self: &'b mut Context, msgtype: comm::MessageType, result: ObjRef) {
         let mut msg = comm::Message::new();
-        msg.set_field_type(comm::MessageType::PUSH);
+        msg.set_field_type(msgtype);
+        msg.set_objref(result);
         msg.set_workerid(self.workerid as u64);
         send_message(&mut self.request, &mut msg);
+        receive_ack(&mut self.request);
+    }
+    /// Allocate an object whose data is stored later by `store_result`, the server learns about
+    /// the object only then.
+    pub fn push_remote_object<'b>(self: &'b mut Context) -> ObjRef {
+        let objref = self.alloc_object();
+        self.pushed.insert(objref);
+        return objref;
+    }
+    /// Store the result of a call or the data of a pushed object on this client.
+    pub fn store_result<'b>(self: &'b mut Context, objref: ObjRef, data: Vec<u8>) {
+        self.add_object(objref, data);
+        if self.pushed.remove(&objref) {
+            self.announce_object(objref);
+        }
+    }
+    /// Store `data` on this client and register it with the server. The server learns about the
+    /// object only after the data is stored, so it can be delivered right away.
+    pub fn put_object<'b>(self: &'b mut Context, data: Vec<u8>) -> ObjRef {
+        let objref = self.alloc_object();
+        self.add_object(objref, data);
+        self.announce_object(objref);
+        return objref;
+    }
+    fn alloc_object<'b>(self: &'b mut Context) -> ObjRef {
+        let mut alloc = comm::Message::new();
+        alloc.set_field_type(comm::MessageType::ALLOC);
+        alloc.set_workerid(self.workerid as u64);
+        send_message(&mut self.request, &mut alloc);
         let answer = receive_message(&mut self.request);
-        let result = answer.get_call().get_result();
-        assert!(result.len() == 1);
-        return result[0];
+        return answer.get_objref();
+    }
+    /// Tell the server that this client stored an object.
+    fn announce_object<'b>(self: &'b mut Context, objref: ObjRef) {
+        let mut stored = comm::Message::new();
+        stored.set_field_type(comm::MessageType::STORED);
+        stored.set_workerid(self.workerid as u64);
+        stored.set_objref(objref);
+        if let Some(hash) = self.hashes.get(&objref) {
+            stored.set_hash(hash.clone());
+        }
+        send_message(&mut self.request, &mut stored);
+        receive_ack(&mut self.request);
     }
     pub fn pull_debug_info<'b>(self: &'b mut Context) -> comm::Message {
         let mut msg = comm::Message::new();
@@ -296,90 +628,265 @@ impl Context {
                 Event::Debug(msg) => {
                     return msg;
                 },
-                _ => {}
+                event => {
+                    self.handle_event(event);
+                }
             }
         }
     }
-    pub fn finish_request<'b>(self: &'b mut Context) {
-        match self.state.clone() { // TODO: remove the clone
-            State::Processing{call, deps: _} => {
-                let mut done = comm::Message::new();
-                done.set_field_type(comm::MessageType::DONE);
-                done.set_call(call.clone());
-                done.set_workerid(self.workerid as u64);
-                send_message(&mut self.request, &mut done);
-                receive_ack(&mut self.request);
-                self.state = State::Waiting;
-            }
-            State::Waiting => {}
+    /// Tell the server that the call computing `objref` has been evaluated.
+    pub fn finish_request<'b>(self: &'b mut Context, objref: ObjRef) {
+        let idx = self.running.iter().position(|task| task.call.get_result()[0] == objref).expect("call is not running");
+        let mut task = self.running.remove(idx);
+        self.give_back_slot();
+        task.timing.set_finished(now_us());
+        self.record_task(&task);
+        let mut done = comm::Message::new();
+        done.set_field_type(comm::MessageType::DONE);
+        done.set_call(task.call);
+        done.set_timing(task.timing);
+        done.set_workerid(self.workerid as u64);
+        if let Some(hash) = self.hashes.get(&objref) {
+            done.set_hash(hash.clone());
+        }
+        send_message(&mut self.request, &mut done);
+        receive_ack(&mut self.request);
+    }
+
+    /// Tell the server that the call computing `objref` raised an error, its result will never be stored.
+    pub fn fail_request<'b>(self: &'b mut Context, objref: ObjRef, error: String) {
+        let idx = self.running.iter().position(|task| task.call.get_result()[0] == objref).expect("call is not running");
+        let task = self.running.remove(idx);
+        self.give_back_slot();
+        let mut failed = comm::Message::new();
+        failed.set_field_type(comm::MessageType::FAILED);
+        failed.set_call(task.call);
+        failed.set_error(error);
+        failed.set_workerid(self.workerid as u64);
+        send_message(&mut self.request, &mut failed);
+        receive_ack(&mut self.request);
+    }
+
+    /// A running call is done. Each finished call gives back one slot that was lent, and the call
+    /// it interrupted, if any, is the current call again.
+    fn give_back_slot<'b>(self: &'b mut Context) {
+        if self.owed > 0 {
+            self.owed -= 1;
+        }
+        let current = self.running.last().map(|task| task.call.clone());
+        if let Some(call) = current {
+            self.set_current(&call);
+        }
+    }
+
+    /// Make `call` the call that `get_function` and `get_actor` describe.
+    fn set_current<'b>(self: &'b mut Context, call: &comm::Call) {
+        self.function = self.functions.get(call.get_name()).expect("function not found").clone();
+        self.actor = match call.get_field_type() {
+            comm::Call_Type::ACTOR_CREATE | comm::Call_Type::ACTOR_CALL => Some(call.get_actorid()),
+            _ => None
+        };
+    }
+
+    /// Add a finished call to the timeline of this worker.
+    fn record_task<'b>(self: &'b mut Context, task: &Task) {
+        let pid = worker_pid(self.workerid);
+        let objref = task.call.get_result()[0].to_string();
+        self.trace.instant("received", "task", task.timing.get_received(), pid, vec![("objref", objref.clone())]);
+        self.trace.instant("args ready", "task", task.timing.get_ready(), pid, vec![("objref", objref.clone())]);
+        self.trace.complete(task.call.get_name(), "task", task.timing.get_started(), task.timing.get_finished(), pid, vec![("objref", objref)]);
+    }
+
+    /// Record a timeline of the calls evaluated on this worker from now on. It grows with each call
+    /// until the worker exits.
+    pub fn set_tracing<'b>(self: &'b mut Context, enabled: bool) {
+        self.trace.set_enabled(enabled);
+    }
+
+    /// Return the timeline of the calls evaluated on this worker in the Chrome trace format.
+    pub fn trace_json<'b>(self: &'b Context) -> String {
+        return self.trace.to_json();
+    }
+
+    /// Request the timeline of all calls from the server in the Chrome trace format.
+    pub fn server_trace_json<'b>(self: &'b mut Context) -> String {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::TRACE);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        return answer.get_trace().into();
+    }
+
+    /// Ask the server for the objects `objref` was computed from or, if `descendants` is set, that
+    /// were computed from it, following at most `depth` calls (0 follows all of them).
+    pub fn lineage<'b>(self: &'b mut Context, objref: ObjRef, depth: u64, descendants: bool) -> Result<comm::Lineage, String> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::LINEAGE);
+        msg.set_objref(objref);
+        msg.set_depth(depth);
+        msg.set_descendants(descendants);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
         }
+        return Ok(answer.get_lineage().clone());
     }
 
-    pub fn client_step<'b>(self: &'b mut Context) -> ObjRef {
+    /// Tell the server that this client no longer uses `objref`. Its lineage may be pruned once no
+    /// client uses it anymore.
+    pub fn release<'b>(self: &'b mut Context, objref: ObjRef) {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::RELEASE);
+        msg.set_workerid(self.workerid as u64);
+        msg.set_objref(objref);
+        send_message(&mut self.request, &mut msg);
+        receive_ack(&mut self.request);
+    }
+
+    /// Tell the server that this client disconnects. Its queued calls are dropped and the objects
+    /// it created are freed, unless they are bound to a name or used by other clients.
+    pub fn close_session<'b>(self: &'b mut Context) {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::CLOSE_SESSION);
+        msg.set_workerid(self.workerid as u64);
+        self.closed.store(true, Ordering::Relaxed);
+        send_message(&mut self.request, &mut msg);
+        receive_ack(&mut self.request);
+    }
+
+    /// Request the computation graph from the server in the given format (dot, json or graphml).
+    pub fn export_graph<'b>(self: &'b mut Context, format: String) -> Result<String, String> {
+        let mut msg = comm::Message::new();
+        msg.set_field_type(comm::MessageType::DUMP_GRAPH);
+        msg.set_format(format);
+        send_message(&mut self.request, &mut msg);
+        let answer = receive_message(&mut self.request);
+        if answer.has_error() {
+            return Err(answer.get_error().into());
+        }
+        return Ok(answer.get_graph().into());
+    }
+
+    /// Finish all calls that have been handed out by `client_step`.
+    pub fn finish_all_requests<'b>(self: &'b mut Context) {
+        while self.running.len() > 0 {
+            let objref = self.running[0].call.get_result()[0];
+            self.finish_request(objref);
+        }
+    }
+
+    /// Hand out the first assigned call whose dependencies are all available, blocking until there
+    /// is one. Return the objref of its result, or None once the server shut down this worker; the
+    /// worker should close its session then.
+    pub fn client_step<'b>(self: &'b mut Context) -> Option<ObjRef> {
         loop {
-            match self.notify_main.recv().unwrap() {
-                Event::Obj(objref) => {
-                    // TODO: Make this more efficient:
-                    // START
-                    let mut acc = comm::Message::new();
-                    acc.set_field_type(comm::MessageType::ACC);
-                    acc.set_workerid(self.workerid as u64);
-                    acc.set_objref(objref);
-                    send_message(&mut self.request, &mut acc);
-                    let answer = receive_message(&mut self.request);
-                    // END
-                    // if all elements for the current call are satisfied, evaluate it
-                    match self.state {
-                        State::Waiting => {},
-                        State::Processing {call: _, ref mut deps} => {
-                            match deps.binary_search(&objref) {
-                                Ok(idx) => {
-                                    deps.remove(idx); // TODO: use more efficient data structure
-                                }
-                                _ => {}
+            match self.start_ready_task() {
+                Some(objref) => return Some(objref),
+                None => {}
+            }
+            if self.shutdown {
+                return None;
+            }
+            let event = self.notify_main.recv().unwrap();
+            self.handle_event(event);
+        }
+    }
+
+    /// Hand out the first assigned call whose dependencies are all available and return the objref
+    /// of its result, or None if no call is ready.
+    fn start_ready_task<'b>(self: &'b mut Context) -> Option<ObjRef> {
+        let idx = match self.tasks.iter().position(|task| task.deps.len() == 0) {
+            Some(idx) => idx,
+            None => return None
+        };
+        let mut task = self.tasks.remove(idx);
+        task.timing.set_started(now_us());
+        // serializing args datastructure
+        self.args.clear();
+        task.call.get_args().write_to_writer(&mut self.args).unwrap();
+        // calling the function
+        self.set_current(&task.call);
+        let result = task.call.get_result();
+        assert!(result.len() == 1);
+        let objref = result[0];
+        self.running.push(task);
+        return Some(objref);
+    }
+
+    /// Process a delivered object or a newly assigned call. Return the objref if an object was
+    /// delivered.
+    fn handle_event<'b>(self: &'b mut Context, event: Event) -> Option<ObjRef> {
+        match event {
+            Event::Obj(objref) => {
+                // TODO: Make this more efficient:
+                // START
+                let mut acc = comm::Message::new();
+                acc.set_field_type(comm::MessageType::ACC);
+                acc.set_workerid(self.workerid as u64);
+                acc.set_objref(objref);
+                acc.set_size(self.get_obj_len(objref).unwrap_or(0) as u64);
+                send_message(&mut self.request, &mut acc);
+                let answer = receive_message(&mut self.request);
+                // END
+                for task in self.tasks.iter_mut() {
+                    match task.deps.binary_search(&objref) {
+                        Ok(idx) => {
+                            task.deps.remove(idx); // TODO: use more efficient data structure
+                            if task.deps.len() == 0 {
+                                task.timing.set_ready(now_us());
                             }
                         }
+                        _ => {}
                     }
-                },
-                Event::Invoke(call) => {
-                    info!("starting to evaluate {:?}", call.get_name());
-                    assert!(self.state == State::Waiting);
-                    let mut args = vec!();
-                    {
-                        let objects = self.objects.lock().unwrap();
-                        for elem in call.get_args().get_objrefs() {
-                            if *elem >= 0 {
-                                let objref = *elem as u64;
-                                if !objects.contains_key(&objref) {
-                                    args.push(objref);
-                                }
+                }
+                return Some(objref);
+            },
+            Event::Shutdown => {
+                self.shutdown = true;
+                return None;
+            },
+            Event::Failed(objref) => {
+                self.failed.insert(objref);
+                return None;
+            },
+            Event::Free(objref) => {
+                if let Some(hash) = self.hashes.remove(&objref) {
+                    if self.contents.get(&hash) == Some(&objref) {
+                        self.contents.remove(&hash);
+                    }
+                }
+                return None;
+            },
+            Event::Invoke(call) => {
+                info!("starting to evaluate {:?}", call.get_name());
+                // each blocked call lends its slot, and a lent slot may still be in use after the call continued
+                assert!(self.tasks.len() + self.running.len() < self.num_slots + self.blocked.len() + self.owed);
+                let mut args = vec!();
+                {
+                    let objects = self.objects.lock().unwrap();
+                    for elem in call.get_args().get_objrefs() {
+                        if *elem >= 0 {
+                            let objref = *elem as u64;
+                            if !objects.contains_key(&objref) {
+                                args.push(objref);
                             }
                         }
                     }
-                    args.sort();
-                    args.dedup();
-                    info!("need args {:?}", args);
-                    self.state = State::Processing{call: call.clone(), deps: args};
-                    // if all elements for the current call are satisfied, evaluate it
-                },
-                _ => {}
-            }
-
-            match self.state {
-                State::Waiting => {},
-                State::Processing {ref call, ref deps} => {
-                    if deps.len() == 0 {
-                        // serializing args datastructure
-                        self.args.clear();
-                        call.get_args().write_to_writer(&mut self.args).unwrap();
-                        // calling the function
-                        let name = call.get_name().to_string();
-                        self.function = self.functions.get(&name).expect("function not found").clone();
-                        let result = call.get_result();
-                        assert!(result.len() == 1);
-                        return result[0]
-                    }
                 }
+                args.sort();
+                args.dedup();
+                info!("need args {:?}", args);
+                let mut timing = comm::TaskTiming::new();
+                timing.set_received(now_us());
+                if args.len() == 0 {
+                    timing.set_ready(timing.get_received());
+                }
+                self.tasks.push(Task {call: call, deps: args, timing: timing});
+                return None;
+            },
+            _ => {
+                return None;
             }
         }
     }
@@ -0,0 +1,103 @@
+// A server and its workers running as threads of one process, connected over ZeroMQ's inproc://
+// transport or in-memory channels. This needs no free ports and no separate processes, which makes
+// it convenient for tests and local experiments.
+
+use client::Context;
+use server::Server;
+use utils::{ResourceMap, SharedContext, Protocol};
+use transport::Network;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+// With inproc:// and in memory the ports only name the endpoints. The server picks setup ports at random from
+// 2048 upwards, so client ports stay below that.
+const REPLY_PORT: u16 = 1;
+const PUBLISH_PORT: u16 = 2;
+const SETUP_PORT: u16 = 3;
+const FIRST_CLIENT_PORT: u16 = 1024;
+
+pub struct LocalCluster {
+    network: Network, // shares the context of the server, all clients connect through it
+    next_port: u16 // port of the next client that connects
+}
+
+impl LocalCluster {
+    /// Start a server in a background thread. It runs until the process exits.
+    pub fn new() -> LocalCluster {
+        return LocalCluster::with_network(Network::new(SharedContext::new(), Protocol::Inproc));
+    }
+
+    /// Like `new`, but the server and the clients are connected by `network`, which must use
+    /// `Protocol::Inproc` or `Protocol::Memory`.
+    pub fn with_network(network: Network) -> LocalCluster {
+        let server_network = network.clone();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut server = Server::with_network(server_network, PUBLISH_PORT);
+            let mut socket = server.bind(REPLY_PORT);
+            ready_sender.send(()).unwrap(); // inproc:// sockets must be bound before others connect
+            server.serve(&mut socket, SETUP_PORT);
+        });
+        ready_receiver.recv().unwrap();
+        return LocalCluster { network: network, next_port: FIRST_CLIENT_PORT };
+    }
+
+    /// Start a server and connect `num_workers` clients with one slot each. The caller drives the
+    /// returned contexts, for example by moving each of them to its own thread.
+    pub fn start(num_workers: usize) -> (LocalCluster, Vec<Context>) {
+        let mut cluster = LocalCluster::new();
+        let mut workers = Vec::new();
+        for _ in 0..num_workers {
+            workers.push(cluster.connect(1, None));
+        }
+        return (cluster, workers);
+    }
+
+    /// Connect a new client that accepts up to `num_slots` calls at the same time. If `resources`
+    /// is `None`, the client has one CPU per slot.
+    pub fn connect<'b>(self: &'b mut LocalCluster, num_slots: usize, resources: Option<ResourceMap>) -> Context {
+        let port = self.next_port;
+        assert!(port < 2048, "too many clients in the local cluster");
+        self.next_port += 1;
+        let localhost = IpAddr::from_str("127.0.0.1").unwrap();
+        return Context::with_network(self.network.clone(), &localhost, REPLY_PORT, PUBLISH_PORT, &localhost, port, num_slots, resources);
+    }
+
+    /// Connect a new driver, which submits calls but never gets any assigned.
+    pub fn connect_driver<'b>(self: &'b mut LocalCluster) -> Context {
+        let port = self.next_port;
+        assert!(port < 2048, "too many clients in the local cluster");
+        self.next_port += 1;
+        let localhost = IpAddr::from_str("127.0.0.1").unwrap();
+        return Context::driver_with_network(self.network.clone(), &localhost, REPLY_PORT, PUBLISH_PORT, &localhost, port);
+    }
+}
+
+#[cfg(test)]
+fn check_cluster(mut cluster: LocalCluster) {
+    let mut workers = vec![cluster.connect(1, None), cluster.connect(1, None)];
+    let objref = workers[0].put_object(vec![1, 2, 3]);
+    let pulled = workers[1].pull_remote_object(objref).unwrap();
+    assert_eq!(workers[1].get_obj_len(pulled), Some(3));
+    let mut driver = cluster.connect_driver();
+    driver.put_name("numbers".into(), objref, false).unwrap();
+    assert_eq!(driver.get_name("numbers".into()), Some(objref));
+    // the driver is not connected to the workers up front
+    let pulled = driver.pull_remote_object(objref).unwrap();
+    assert_eq!(driver.get_obj_len(pulled), Some(3));
+    let pushed = driver.put_object(vec![4, 5]);
+    let pulled = workers[0].pull_remote_object(pushed).unwrap();
+    assert_eq!(workers[0].get_obj_len(pulled), Some(2));
+}
+
+#[test]
+fn test_local_cluster() {
+    check_cluster(LocalCluster::new());
+}
+
+#[test]
+fn test_memory_cluster() {
+    check_cluster(LocalCluster::with_network(Network::memory()));
+}
@@ -0,0 +1,185 @@
+#![feature(ip_addr)]
+#![feature(convert)]
+
+#[macro_use]
+extern crate log;
+extern crate argparse;
+extern crate env_logger;
+extern crate rand;
+extern crate protobuf;
+extern crate crypto;
+extern crate zmq;
+
+pub mod comm;
+pub mod utils;
+pub mod transport;
+
+use argparse::{ArgumentParser, Store, StoreTrue};
+use std::net::IpAddr;
+use std::process;
+use std::str::FromStr;
+use utils::{send_message, receive_message, Protocol, resources_from_proto, json_string};
+use transport::{MessageTransport, Network};
+
+/// Send a request of type `msgtype` to the server and return the answer.
+fn request(socket: &mut Box<MessageTransport>, msgtype: comm::MessageType, workerid: Option<u64>) -> comm::Message {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(msgtype);
+    if let Some(workerid) = workerid {
+        msg.set_workerid(workerid);
+    }
+    return send_request(socket, msg);
+}
+
+/// Send a prepared request to the server and return the answer.
+fn send_request(socket: &mut Box<MessageTransport>, mut msg: comm::Message) -> comm::Message {
+    send_message(socket, &mut msg);
+    let answer = receive_message(socket);
+    if answer.has_error() {
+        println!("error: {}", answer.get_error());
+        process::exit(1);
+    }
+    return answer;
+}
+
+fn format_resources(resources: &comm::Resources) -> String {
+    let mut resources: Vec<(String, f64)> = resources_from_proto(resources).into_iter().collect();
+    resources.sort_by(|a, b| a.0.cmp(&b.0));
+    return resources.iter().map(|&(ref name, quantity)| format!("{}={}", name, quantity)).collect::<Vec<String>>().join(",");
+}
+
+fn format_ids(ids: &[u64]) -> String {
+    return ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(",");
+}
+
+fn json_ids(ids: &[u64]) -> String {
+    return format!("[{}]", format_ids(ids));
+}
+
+fn json_call(call: &comm::Call) -> String {
+    return format!("{{\"name\":{},\"args\":{},\"result\":{},\"priority\":{}}}",
+        json_string(call.get_name()),
+        json_string(&format!("{:?}", call.get_args().get_objrefs())),
+        json_ids(call.get_result()),
+        call.get_priority());
+}
+
+fn print_workers(answer: &comm::Message, json: bool) {
+    if json {
+        let workers: Vec<String> = answer.get_workers().iter().map(|worker| {
+            format!("{{\"workerid\":{},\"address\":{},\"num_slots\":{},\"resources\":{},\"draining\":{},\"driver\":{}}}",
+                worker.get_workerid(), json_string(worker.get_address()), worker.get_num_slots(),
+                json_string(&format_resources(worker.get_resources())), worker.get_draining(), worker.get_driver())
+        }).collect();
+        println!("[{}]", workers.join(",\n "));
+        return;
+    }
+    println!("{:<6} {:<28} {:<6} {:<30} {}", "ID", "ADDRESS", "SLOTS", "RESOURCES", "STATE");
+    for worker in answer.get_workers() {
+        let state = if worker.get_driver() { "driver" } else if worker.get_draining() { "draining" } else { "active" };
+        println!("{:<6} {:<28} {:<6} {:<30} {}", worker.get_workerid(), worker.get_address(), worker.get_num_slots(), format_resources(worker.get_resources()), state);
+    }
+}
+
+fn print_scheduler_info(answer: &comm::Message, json: bool) {
+    let info = answer.get_scheduler_info();
+    if json {
+        println!("{{\"worker_queue\":{},", json_ids(info.get_worker_queue()));
+        println!(" \"job_queue\":[{}],", info.get_job_queue().iter().map(json_call).collect::<Vec<String>>().join(","));
+        println!(" \"actor_queue\":[{}],", info.get_actor_queue().iter().map(json_call).collect::<Vec<String>>().join(","));
+        println!(" \"running\":{},", json_ids(info.get_running()));
+        println!(" \"pull_queue\":[{}],", info.get_pull_queue().iter().map(|pull| format!("{{\"workerid\":{},\"objref\":{}}}", pull.get_workerid(), pull.get_objref())).collect::<Vec<String>>().join(","));
+        println!(" \"objtable\":{{{}}},", info.get_objtable().iter().map(|obj| format!("\"{}\":{}", obj.get_objref(), json_ids(obj.get_workerid()))).collect::<Vec<String>>().join(","));
+        println!(" \"fntable\":{{{}}}}}", info.get_fntable().iter().map(|f| format!("{}:{}", json_string(f.get_fnname()), json_ids(f.get_workerid()))).collect::<Vec<String>>().join(","));
+        return;
+    }
+    println!("worker queue: {}", format_ids(info.get_worker_queue()));
+    println!("job queue:");
+    for call in info.get_job_queue().iter().chain(info.get_actor_queue().iter()) {
+        println!("  {:<30} {:?} -> {} (priority {})", call.get_name(), call.get_args().get_objrefs(), format_ids(call.get_result()), call.get_priority());
+    }
+    println!("running: {}", format_ids(info.get_running()));
+    println!("pull queue:");
+    for pull in info.get_pull_queue() {
+        println!("  object {} for worker {}", pull.get_objref(), pull.get_workerid());
+    }
+    println!("{:<10} {}", "OBJREF", "WORKERS");
+    for obj in info.get_objtable() {
+        println!("{:<10} {}", obj.get_objref(), format_ids(obj.get_workerid()));
+    }
+    println!("{:<30} {}", "FUNCTION", "WORKERS");
+    for f in info.get_fntable() {
+        println!("{:<30} {}", f.get_fnname(), format_ids(f.get_workerid()));
+    }
+}
+
+fn main() {
+    let mut server_addr = "127.0.0.1".to_string();
+    let mut server_port = 0;
+    let mut command = String::new();
+    let mut workerid = -1;
+    let mut json = false;
+    let mut format = "dot".to_string();
+    let mut weight = 1.0;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Inspect and manage a running Orchestra server");
+        ap.refer(&mut server_addr).add_argument("server_addr", Store, "address of the server");
+        ap.refer(&mut server_port).add_argument("server_port", Store, "port for incoming requests of the server");
+        ap.refer(&mut command).add_argument("command", Store, "one of workers, info, graph, critical-path, drain, shutdown, weight");
+        ap.refer(&mut workerid).add_argument("workerid", Store, "worker to drain or shut down, or client whose weight is set");
+        ap.refer(&mut json).add_option(&["--json"], StoreTrue, "print JSON instead of tables");
+        ap.refer(&mut format).add_option(&["--format"], Store, "format of the graph: dot, json or graphml");
+        ap.refer(&mut weight).add_option(&["--weight"], Store, "share of the workers the client gets relative to other clients");
+        ap.parse_args_or_exit();
+    }
+    env_logger::init().unwrap();
+    let server_addr = IpAddr::from_str(&server_addr).unwrap(); // TODO: Proper error handling
+    let mut socket = Network::tcp().connect_request(&Protocol::Tcp.address(&server_addr, server_port));
+
+    match &command[..] {
+        "workers" => {
+            let answer = request(&mut socket, comm::MessageType::LIST_WORKERS, None);
+            print_workers(&answer, json);
+        },
+        "info" => {
+            let answer = request(&mut socket, comm::MessageType::SCHEDULER_INFO, None);
+            print_scheduler_info(&answer, json);
+        },
+        "graph" => {
+            let mut msg = comm::Message::new();
+            msg.set_field_type(comm::MessageType::DUMP_GRAPH);
+            msg.set_format(if json { "json".into() } else { format });
+            let answer = send_request(&mut socket, msg);
+            print!("{}", answer.get_graph());
+        },
+        "critical-path" => {
+            let answer = request(&mut socket, comm::MessageType::CRITICAL_PATH, None);
+            print!("{}", answer.get_graph());
+        },
+        "drain" | "shutdown" => {
+            if workerid < 0 {
+                println!("{} needs a workerid", command);
+                process::exit(2);
+            }
+            let msgtype = if command == "drain" { comm::MessageType::DRAIN } else { comm::MessageType::SHUTDOWN };
+            request(&mut socket, msgtype, Some(workerid as u64));
+            info!("{} of worker {} requested", command, workerid);
+        },
+        "weight" => {
+            if workerid < 0 {
+                println!("weight needs a workerid");
+                process::exit(2);
+            }
+            let mut msg = comm::Message::new();
+            msg.set_field_type(comm::MessageType::SET_WEIGHT);
+            msg.set_workerid(workerid as u64);
+            msg.set_weight(weight);
+            send_request(&mut socket, msg);
+        },
+        _ => {
+            println!("unknown command {}, use one of workers, info, graph, critical-path, drain, shutdown, weight", command);
+            process::exit(2);
+        }
+    }
+}
@@ -1,13 +1,19 @@
-use petgraph::{Graph, Directed};
+use petgraph::{Graph, Directed, EdgeDirection};
 use petgraph::graph::NodeIndex;
-use utils::ObjRef;
+use std::cmp;
+use std::collections::{HashMap, HashSet, BTreeSet};
+use utils::{ObjRef, ObjTable, json_string};
 
-pub type Host = u64;
+/// Assumed bandwidth between workers in bytes per microsecond, used to estimate transfer costs.
+const BANDWIDTH: f32 = 100.0;
+
+/// Minimum number of released objects before the graph is pruned.
+const MIN_PRUNE: usize = 1024;
 
 // A node in the computation graph, can be a data node (Obj), a function call node (Op), a Map node
 // or a Reduce node. An opid is a pointer into the ops vector of the computation graph.
 #[derive(Hash, PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Debug)]
-enum Node<'a> {
+enum Node {
     Map {
         opid: usize
     },
@@ -18,59 +24,462 @@ enum Node<'a> {
         opid: usize
     },
     Obj {
-        objref: ObjRef,
-        hosts: &'a [Host]
+        objref: ObjRef
+    }
+}
+
+impl Node {
+    fn kind(self: &Node) -> &'static str {
+        match *self {
+            Node::Map { .. } => "Map",
+            Node::Reduce { .. } => "Reduce",
+            Node::Op { .. } => "Op",
+            Node::Obj { .. } => "Obj"
+        }
     }
 }
 
-pub struct CompGraph<'a> {
-    objs: Vec<NodeIndex>, // mapping from objrefs to nodes in the graph
-    ops: Vec<String>, // names of operations
-    graph: Graph<Node<'a>, f32, Directed> // computation graph
+pub struct CompGraph {
+    objs: Vec<Option<NodeIndex>>, // mapping from objrefs to nodes in the graph, None once pruned
+    ops: Vec<String>, // names of operations, each name is stored once
+    opids: HashMap<String, usize>, // index of each name in ops
+    sizes: HashMap<ObjRef, u64>, // size of objects in bytes, as far as they have been reported
+    timings: HashMap<ObjRef, (u64, u64)>, // start and end of the calls that computed objects
+    durations: HashMap<usize, (u64, u64)>, // total duration and number of finished calls per operation name
+    users: HashMap<ObjRef, HashSet<u64>>, // sessions that use each object and haven't released it
+    released: HashSet<ObjRef>, // objects no client uses anymore that are still in the graph
+    released_since_prune: usize, // number of objects released since the graph was last pruned
+    pruned: HashMap<usize, usize>, // number of pruned calls per operation name
+    sessions: HashMap<ObjRef, u64>, // session that created each object, the call computing it belongs to the same session
+    session_objs: HashMap<u64, BTreeSet<ObjRef>>, // objects created by each session
+    graph: Graph<Node, f32, Directed> // computation graph
 }
 
-impl<'a> CompGraph<'a> {
-    pub fn new() -> CompGraph<'a> {
+impl CompGraph {
+    pub fn new() -> CompGraph {
         return CompGraph {
             graph: Graph::new(),
             objs: Vec::new(),
-            ops: Vec::new()
+            ops: Vec::new(),
+            opids: HashMap::new(),
+            sizes: HashMap::new(),
+            timings: HashMap::new(),
+            durations: HashMap::new(),
+            users: HashMap::new(),
+            released: HashSet::new(),
+            released_since_prune: 0,
+            pruned: HashMap::new(),
+            sessions: HashMap::new(),
+            session_objs: HashMap::new()
         };
     }
-    pub fn add_obj(self: &mut CompGraph<'a>) -> (ObjRef, NodeIndex) {
+    pub fn add_obj(self: &mut CompGraph) -> (ObjRef, NodeIndex) {
         let objref = self.objs.len() as ObjRef;
-        let obj = self.graph.add_node(Node::Obj{objref: objref, hosts: &[]});
-        self.objs.push(obj);
+        let obj = self.graph.add_node(Node::Obj{objref: objref});
+        self.objs.push(Some(obj));
         return (objref, obj);
     }
-    pub fn add_op<'b>(self: &mut CompGraph<'a>, name: String, args: &'b [ObjRef], result: ObjRef) {
-        self.ops.push(name); // TODO: only store unique names
-        let func = self.graph.add_node(Node::Op {opid: self.ops.len() - 1});
-        let res = self.graph.add_node(Node::Obj {objref: result, hosts: &[]});
+    /// Index of an operation name in ops, the name is added if it is new.
+    fn intern(self: &mut CompGraph, name: String) -> usize {
+        if let Some(opid) = self.opids.get(&name) {
+            return *opid;
+        }
+        self.ops.push(name.clone());
+        self.opids.insert(name, self.ops.len() - 1);
+        return self.ops.len() - 1;
+    }
+    /// Node of an object. An object that has been pruned gets a new node without lineage.
+    fn obj_node(self: &mut CompGraph, objref: ObjRef) -> NodeIndex {
+        if let Some(obj) = self.objs[objref as usize] {
+            return obj;
+        }
+        let obj = self.graph.add_node(Node::Obj{objref: objref});
+        self.objs[objref as usize] = Some(obj);
+        return obj;
+    }
+    pub fn add_op<'b>(self: &mut CompGraph, name: String, args: &'b [ObjRef], result: ObjRef) {
+        let opid = self.intern(name);
+        let func = self.graph.add_node(Node::Op {opid: opid});
         for arg in args {
-            self.graph.add_edge(self.objs[*arg as usize], func, 0.0);
+            let cost = self.transfer_cost(*arg);
+            let obj = self.obj_node(*arg);
+            self.graph.add_edge(obj, func, cost);
         }
+        let res = self.obj_node(result);
         self.graph.add_edge(func, res, 0.0);
     }
-    pub fn add_map<'b>(self: &mut CompGraph<'a>, name: String, args: &'b [ObjRef], results: &'b [ObjRef]) {
-        assert!(args.len() == results.len());
-        self.ops.push(name); // TODO: only store unique names
-        let map = self.graph.add_node(Node::Map {opid: self.ops.len() - 1});
-        for i in 0..args.len() {
-            let res = self.graph.add_node(Node::Obj {objref: results[i], hosts: &[]});
-            self.graph.add_edge(self.objs[args[i] as usize], map, 0.0);
-            self.graph.add_edge(map, self.objs[results[i] as usize], 0.0);
+    /// Add a map over the objects `args`. Elements passed by value have a result but no argument.
+    pub fn add_map<'b>(self: &mut CompGraph, name: String, args: &'b [ObjRef], results: &'b [ObjRef]) {
+        assert!(args.len() <= results.len());
+        let opid = self.intern(name);
+        let map = self.graph.add_node(Node::Map {opid: opid});
+        for arg in args {
+            let cost = self.transfer_cost(*arg);
+            let obj = self.obj_node(*arg);
+            self.graph.add_edge(obj, map, cost);
+        }
+        for result in results {
+            let res = self.obj_node(*result);
+            self.graph.add_edge(map, res, 0.0);
         }
     }
-    pub fn add_reduce<'b>(self: &mut CompGraph<'a>, name: String, args: &'b [ObjRef], result: ObjRef) {
-        self.ops.push(name); // TODO: only store unique names
-        let reduce = self.graph.add_node(Node::Reduce {opid: self.ops.len() - 1});
-        let res = self.graph.add_node(Node::Obj {objref: result, hosts: &[]});
+    pub fn add_reduce<'b>(self: &mut CompGraph, name: String, args: &'b [ObjRef], result: ObjRef) {
+        let opid = self.intern(name);
+        let reduce = self.graph.add_node(Node::Reduce {opid: opid});
         for arg in args {
-            self.graph.add_edge(self.objs[*arg as usize], reduce, 0.0);
+            let cost = self.transfer_cost(*arg);
+            let obj = self.obj_node(*arg);
+            self.graph.add_edge(obj, reduce, cost);
         }
+        let res = self.obj_node(result);
         self.graph.add_edge(reduce, res, 0.0);
     }
+    /// Whether the lineage of `objref` is still in the graph.
+    pub fn contains(self: &CompGraph, objref: ObjRef) -> bool {
+        return self.objs.get(objref as usize).map_or(false, |obj| obj.is_some());
+    }
+    /// Objects that `objref` was computed from or, if `descendants` is set, that were computed from
+    /// it, following at most `depth` calls; a depth of 0 follows all of them. The arguments of a
+    /// map are all related to all of its results.
+    pub fn lineage(self: &CompGraph, objref: ObjRef, depth: u64, descendants: bool) -> Vec<ObjRef> {
+        let direction = if descendants { EdgeDirection::Outgoing } else { EdgeDirection::Incoming };
+        let mut visited = HashSet::new();
+        let mut frontier: Vec<NodeIndex> = self.objs[objref as usize].into_iter().collect();
+        let mut level = 0;
+        while frontier.len() > 0 && (depth == 0 || level < depth) {
+            let mut next = Vec::new();
+            for obj in frontier {
+                for op in self.graph.neighbors_directed(obj, direction) {
+                    for other in self.graph.neighbors_directed(op, direction) {
+                        if visited.insert(other) {
+                            next.push(other);
+                        }
+                    }
+                }
+            }
+            frontier = next;
+            level += 1;
+        }
+        let mut result = Vec::new();
+        for idx in visited {
+            if let Node::Obj { objref } = *self.graph.node_weight(idx).unwrap() {
+                result.push(objref);
+            }
+        }
+        result.sort();
+        return result;
+    }
+    /// Name of the call that computed `objref`, None if a client stored the object.
+    pub fn producer(self: &CompGraph, objref: ObjRef) -> Option<&str> {
+        if let Some(obj) = self.objs[objref as usize] {
+            for op in self.graph.neighbors_directed(obj, EdgeDirection::Incoming) {
+                return self.op_name(op);
+            }
+        }
+        return None;
+    }
+    /// Tag an object and the call computing it with the session that created them.
+    pub fn set_session(self: &mut CompGraph, objref: ObjRef, session: u64) {
+        if let Some(previous) = self.sessions.insert(objref, session) {
+            if let Some(objrefs) = self.session_objs.get_mut(&previous) {
+                objrefs.remove(&objref);
+            }
+        }
+        self.session_objs.entry(session).or_insert(BTreeSet::new()).insert(objref);
+        self.add_user(objref, session);
+    }
+    /// The session that created an object.
+    pub fn session(self: &CompGraph, objref: ObjRef) -> Option<u64> {
+        return self.sessions.get(&objref).cloned();
+    }
+    /// The objects created by a session, in the order they were created.
+    pub fn session_objects(self: &CompGraph, session: u64) -> Vec<ObjRef> {
+        return self.session_objs.get(&session).map_or(vec![], |objrefs| objrefs.iter().cloned().collect());
+    }
+    /// Whether a call of another session takes `objref` as an argument.
+    pub fn used_by_other_session(self: &CompGraph, objref: ObjRef, session: u64) -> bool {
+        if let Some(obj) = self.objs[objref as usize] {
+            for op in self.graph.neighbors(obj) {
+                for result in self.graph.neighbors(op) {
+                    if let Node::Obj { objref } = *self.graph.node_weight(result).unwrap() {
+                        if self.session(objref) != Some(session) {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        return false;
+    }
+    /// Record the size of an object in bytes.
+    /// The edges from the object to the calls using it get the cost of transferring it.
+    pub fn set_size(self: &mut CompGraph, objref: ObjRef, size: u64) {
+        let obj = match self.objs[objref as usize] {
+            Some(obj) => obj,
+            None => return
+        };
+        self.sizes.insert(objref, size);
+        let cost = self.transfer_cost(objref);
+        let consumers: Vec<NodeIndex> = self.graph.neighbors(obj).collect();
+        for op in consumers {
+            let edge = self.graph.find_edge(obj, op).unwrap();
+            *self.graph.edge_weight_mut(edge).unwrap() = cost;
+        }
+    }
+    /// Record when the call computing `objref` started and finished, in microseconds since the epoch.
+    /// The edge from the call to the object gets the duration of the call.
+    pub fn set_timing(self: &mut CompGraph, objref: ObjRef, started: u64, finished: u64) {
+        let obj = match self.objs[objref as usize] {
+            Some(obj) => obj,
+            None => return
+        };
+        self.timings.insert(objref, (started, finished));
+        let duration = if finished > started { finished - started } else { 0 };
+        let producers: Vec<NodeIndex> = self.graph.neighbors_directed(obj, EdgeDirection::Incoming).collect();
+        for op in producers {
+            let edge = self.graph.find_edge(op, obj).unwrap();
+            *self.graph.edge_weight_mut(edge).unwrap() = duration as f32;
+            let opid = self.opid(op).unwrap();
+            let entry = self.durations.entry(opid).or_insert((0, 0));
+            entry.0 += duration;
+            entry.1 += 1;
+        }
+    }
+    /// Record that a session uses an object, for example because it pulled it.
+    pub fn add_user(self: &mut CompGraph, objref: ObjRef, session: u64) {
+        if self.contains(objref) {
+            self.users.entry(objref).or_insert(HashSet::new()).insert(session);
+        }
+    }
+    /// Record that a session no longer uses an object. Once no session uses it, its lineage may be
+    /// pruned.
+    pub fn release(self: &mut CompGraph, objref: ObjRef, session: u64) {
+        let unused = match self.users.get_mut(&objref) {
+            Some(users) => {
+                users.remove(&session);
+                users.is_empty()
+            },
+            None => true
+        };
+        if unused {
+            self.release_all(objref);
+        }
+    }
+    /// Release an object for all sessions that use it, for example because it has been deleted.
+    pub fn release_all(self: &mut CompGraph, objref: ObjRef) {
+        self.users.remove(&objref);
+        if self.contains(objref) && self.released.insert(objref) {
+            self.released_since_prune += 1;
+        }
+    }
+    /// Release all objects a session uses, the session is closed. The session forgets the objects
+    /// it created, those that have been pruned already are forgotten completely.
+    pub fn release_session(self: &mut CompGraph, session: u64) {
+        for objref in self.session_objs.remove(&session).unwrap_or(BTreeSet::new()) {
+            if !self.contains(objref) {
+                self.sessions.remove(&objref);
+            }
+        }
+        let mut unused = Vec::new();
+        for (objref, users) in self.users.iter_mut() {
+            if users.remove(&session) && users.is_empty() {
+                unused.push(*objref);
+            }
+        }
+        for objref in unused {
+            self.release_all(objref);
+        }
+    }
+    /// Whether enough objects have been released since the last pruning to make another one worth
+    /// its time, which is linear in the size of the graph.
+    pub fn should_prune(self: &CompGraph) -> bool {
+        return self.released_since_prune >= cmp::max(MIN_PRUNE, self.graph.node_count() / 4);
+    }
+    /// Remove the calls and objects that only lead to released objects. Objects that are still
+    /// used or not computed yet keep their whole lineage. The number of pruned calls of each
+    /// operation is kept as a summary. Pruned objects that are still held stay with their session,
+    /// which frees them when it closes. Return the number of removed nodes.
+    pub fn prune<'b>(self: &mut CompGraph, exec: &ExecState<'b>) -> usize {
+        let n = self.graph.node_count();
+        let mut keep = vec![false; n];
+        let mut stack = Vec::new();
+        for (objref, obj) in self.objs.iter().enumerate() {
+            if let Some(obj) = *obj {
+                let objref = objref as ObjRef;
+                let state = exec.obj_state(objref);
+                if !self.released.contains(&objref) || state == State::Queued || state == State::Running {
+                    keep[obj.index()] = true;
+                    stack.push(obj);
+                }
+            }
+        }
+        while let Some(node) = stack.pop() {
+            for pred in self.graph.neighbors_directed(node, EdgeDirection::Incoming) {
+                if !keep[pred.index()] {
+                    keep[pred.index()] = true;
+                    stack.push(pred);
+                }
+            }
+        }
+        let mut graph = Graph::new();
+        let mut index = vec![None; n];
+        for i in 0..n {
+            let node = *self.graph.node_weight(NodeIndex::new(i)).unwrap();
+            if keep[i] {
+                index[i] = Some(graph.add_node(node));
+                continue;
+            }
+            match node {
+                Node::Obj { objref } => {
+                    self.sizes.remove(&objref);
+                    self.timings.remove(&objref);
+                    self.users.remove(&objref);
+                    self.released.remove(&objref);
+                    if exec.obj_state(objref) != State::Done {
+                        if let Some(session) = self.sessions.remove(&objref) {
+                            if let Some(objrefs) = self.session_objs.get_mut(&session) {
+                                objrefs.remove(&objref);
+                            }
+                        }
+                    }
+                },
+                Node::Map { opid } | Node::Reduce { opid } | Node::Op { opid } => {
+                    *self.pruned.entry(opid).or_insert(0) += 1;
+                }
+            }
+        }
+        for edge in self.graph.raw_edges() {
+            if let (Some(source), Some(target)) = (index[edge.source().index()], index[edge.target().index()]) {
+                graph.add_edge(source, target, edge.weight);
+            }
+        }
+        for obj in self.objs.iter_mut() {
+            *obj = obj.and_then(|obj| index[obj.index()]);
+        }
+        self.graph = graph;
+        self.released_since_prune = 0;
+        return n - self.graph.node_count();
+    }
+    /// Number of pruned calls of each operation.
+    fn pruned_calls(self: &CompGraph) -> Vec<(&str, usize)> {
+        let mut result: Vec<(&str, usize)> = self.pruned.iter().map(|(opid, count)| (&self.ops[*opid][..], *count)).collect();
+        result.sort();
+        return result;
+    }
+    /// Estimated time in microseconds to deliver `objref` to another worker, 0 if its size is unknown.
+    fn transfer_cost(self: &CompGraph, objref: ObjRef) -> f32 {
+        return self.sizes.get(&objref).map_or(0.0, |size| *size as f32 / BANDWIDTH);
+    }
+    fn opid(self: &CompGraph, idx: NodeIndex) -> Option<usize> {
+        match *self.graph.node_weight(idx).unwrap() {
+            Node::Map { opid } | Node::Reduce { opid } | Node::Op { opid } => Some(opid),
+            Node::Obj { .. } => None
+        }
+    }
+    fn op_name(self: &CompGraph, idx: NodeIndex) -> Option<&str> {
+        return self.opid(idx).map(|opid| &self.ops[opid][..]);
+    }
+    /// Cost of an edge in microseconds. Calls that have not finished yet are expected to take as
+    /// long as the finished calls of the same function took on average.
+    fn edge_cost(self: &CompGraph, source: NodeIndex, target: NodeIndex, weight: f32) -> f64 {
+        if let Node::Obj { objref } = *self.graph.node_weight(target).unwrap() {
+            if !self.timings.contains_key(&objref) {
+                let opid = self.opid(source).unwrap();
+                return self.durations.get(&opid).map_or(0.0, |&(total, count)| total as f64 / count as f64);
+            }
+        }
+        return weight as f64;
+    }
+    /// Find the chain of calls and transfers with the highest total cost, which bounds how fast the
+    /// whole graph can be computed.
+    pub fn critical_path(self: &CompGraph) -> CriticalPath {
+        let n = self.graph.node_count();
+        // visit the nodes in topological order and keep the costliest path leading to each of them
+        let mut indegree: Vec<usize> = (0..n).map(|i| self.graph.neighbors_directed(NodeIndex::new(i), EdgeDirection::Incoming).count()).collect();
+        let mut ready: Vec<NodeIndex> = (0..n).filter(|i| indegree[*i] == 0).map(NodeIndex::new).collect();
+        let mut length = vec![0.0; n];
+        let mut pred: Vec<Option<NodeIndex>> = vec![None; n];
+        while let Some(node) = ready.pop() {
+            let targets: Vec<NodeIndex> = self.graph.neighbors(node).collect();
+            for target in targets {
+                let weight = *self.graph.edge_weight(self.graph.find_edge(node, target).unwrap()).unwrap();
+                let candidate = length[node.index()] + self.edge_cost(node, target, weight);
+                if pred[target.index()].is_none() || candidate > length[target.index()] {
+                    length[target.index()] = candidate;
+                    pred[target.index()] = Some(node);
+                }
+                indegree[target.index()] -= 1;
+                if indegree[target.index()] == 0 {
+                    ready.push(target);
+                }
+            }
+        }
+        let mut path = Vec::new();
+        let mut end = None;
+        let mut total = 0.0;
+        for i in 0..n {
+            if end.is_none() || length[i] > total {
+                end = Some(NodeIndex::new(i));
+                total = length[i];
+            }
+        }
+        while let Some(node) = end {
+            path.push(node);
+            end = pred[node.index()];
+        }
+        path.reverse();
+        return CriticalPath { length: total, nodes: path };
+    }
+    /// Objects on the critical path whose calls have not finished yet.
+    pub fn critical_objrefs(self: &CompGraph, path: &CriticalPath) -> Vec<ObjRef> {
+        let mut result = Vec::new();
+        for node in path.nodes.iter() {
+            if let Node::Obj { objref } = *self.graph.node_weight(*node).unwrap() {
+                if !self.timings.contains_key(&objref) {
+                    result.push(objref);
+                }
+            }
+        }
+        return result;
+    }
+    /// Describe the critical path for post-mortems, one call or object per line.
+    pub fn critical_path_report(self: &CompGraph) -> String {
+        let path = self.critical_path();
+        let mut out = format!("expected makespan: {:.0} us\n", path.length);
+        for (i, node) in path.nodes.iter().enumerate() {
+            let cost = if i > 0 {
+                let prev = path.nodes[i - 1];
+                let weight = *self.graph.edge_weight(self.graph.find_edge(prev, *node).unwrap()).unwrap();
+                self.edge_cost(prev, *node, weight)
+            } else {
+                0.0
+            };
+            match *self.graph.node_weight(*node).unwrap() {
+                Node::Obj { objref } => {
+                    let state = if self.timings.contains_key(&objref) { "" } else { " (estimated)" };
+                    if i > 0 {
+                        out.push_str(&format!("  {:>12.0} us{}\n", cost, state));
+                    }
+                    out.push_str(&format!("object {}{}\n", objref, self.sizes.get(&objref).map_or(String::new(), |size| format!(" ({} bytes)", size))));
+                },
+                _ => {
+                    if i > 0 {
+                        out.push_str(&format!("  {:>12.0} us transfer\n", cost));
+                    }
+                    out.push_str(&format!("{}\n", self.op_name(*node).unwrap()));
+                }
+            }
+        }
+        return out;
+    }
+}
+
+/// The costliest chain of nodes through the computation graph.
+pub struct CriticalPath {
+    /// Total cost of the path in microseconds, the expected makespan of the computation.
+    pub length: f64,
+    /// Nodes along the path in the order they are computed.
+    nodes: Vec<NodeIndex>
 }
 
 pub struct DotBuilder {
@@ -82,6 +491,10 @@ impl DotBuilder {
         DotBuilder{buf: format!("digraph \"{}\" {}", name, "{\n")}
     }
 
+    pub fn set_graph_attrs(&mut self, attrs: &str) {
+        self.buf.push_str(&format!("graph [{}];\n", attrs));
+    }
+
     pub fn set_node_attrs(&mut self, node: &str, attrs: &str) {
         self.buf.push_str(&format!("\"{}\" [{}];\n", node, attrs));
     }
@@ -95,20 +508,120 @@ impl DotBuilder {
     }
 }
 
-pub fn to_dot<'a>(graph: &CompGraph<'a>) -> String {
+/// Execution state of a call or of the object it computes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum State {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Freed
+}
+
+impl State {
+    pub fn name(self: State) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done => "done",
+            State::Failed => "failed",
+            State::Freed => "freed"
+        }
+    }
+
+    fn color(self: State) -> &'static str {
+        match self {
+            State::Queued => "lightgrey",
+            State::Running => "gold",
+            State::Done => "palegreen",
+            State::Failed => "tomato",
+            State::Freed => "white"
+        }
+    }
+}
+
+/// What the server knows about the progress of the calls in a graph.
+pub struct ExecState<'b> {
+    /// For each object, the workers that hold it.
+    pub holders: &'b ObjTable,
+    /// Results of the calls that are running on a worker.
+    pub running: &'b HashSet<ObjRef>,
+    /// Results of the calls that raised an error.
+    pub failed: &'b HashSet<ObjRef>,
+    /// Objects that have been deleted on all workers that held them.
+    pub freed: &'b HashSet<ObjRef>
+}
+
+impl<'b> ExecState<'b> {
+    pub fn obj_state(self: &ExecState<'b>, objref: ObjRef) -> State {
+        if self.failed.contains(&objref) {
+            return State::Failed;
+        }
+        if self.freed.contains(&objref) {
+            return State::Freed;
+        }
+        if self.holders.get(objref as usize).map_or(false, |holders| holders.len() > 0) {
+            return State::Done;
+        }
+        if self.running.contains(&objref) {
+            return State::Running;
+        }
+        return State::Queued;
+    }
+}
+
+impl CompGraph {
+    /// State of a node; an operation is done once all its results are done and failed if one of
+    /// them failed.
+    fn state<'b>(self: &CompGraph, idx: NodeIndex, exec: &ExecState<'b>) -> State {
+        if let Node::Obj { objref } = *self.graph.node_weight(idx).unwrap() {
+            return exec.obj_state(objref);
+        }
+        // a freed result has been computed before
+        let states: Vec<State> = self.graph.neighbors(idx).map(|res| match self.state(res, exec) { State::Freed => State::Done, state => state }).collect();
+        if states.iter().any(|state| *state == State::Failed) {
+            return State::Failed;
+        }
+        if states.len() > 0 && states.iter().all(|state| *state == State::Done) {
+            return State::Done;
+        }
+        if states.iter().any(|state| *state == State::Running || *state == State::Done) {
+            return State::Running;
+        }
+        return State::Queued;
+    }
+}
+
+/// Render the graph in the dot format. Nodes are colored by their execution state and objects are
+/// labeled with the workers holding them; the graph label counts the operations in each state.
+pub fn to_dot<'b>(graph: &CompGraph, exec: &ExecState<'b>) -> String {
     let mut builder = DotBuilder::new_digraph("");
+    let mut counts = [0; 5]; // indexed by `State`, operations are never freed
     for i in 0..graph.graph.node_count() {
         let idx = NodeIndex::new(i);
         let id = i.to_string();
         let weight = graph.graph.node_weight(idx).unwrap();
+        let state = graph.state(idx, exec);
         let label = match *weight {
-            Node::Op { opid } => format!("label=\"{}\"", &graph.ops[opid]),
-            Node::Obj { objref, hosts } => format!("label=\"{}\"", objref),
-            Node::Map { opid } => format!("label=\"{}\"", &graph.ops[opid]),
-            Node::Reduce { opid } => format!("label=\"reduce {}\"", &graph.ops[opid])
+            Node::Op { opid } => format!("label=\"{}\", shape=box", &graph.ops[opid]),
+            Node::Obj { objref } => {
+                let holders = exec.holders.get(objref as usize).map_or(String::new(), |holders| {
+                    holders.iter().map(|workerid| workerid.to_string()).collect::<Vec<String>>().join(",")
+                });
+                format!("label=\"{}\\n[{}]\"", objref, holders)
+            },
+            Node::Map { opid } => format!("label=\"{}\", shape=box", &graph.ops[opid]),
+            Node::Reduce { opid } => format!("label=\"reduce {}\", shape=box", &graph.ops[opid])
         };
-        builder.set_node_attrs(&id, &label);
+        match *weight {
+            Node::Obj { .. } => {},
+            _ => counts[state as usize] += 1
+        }
+        builder.set_node_attrs(&id, &format!("{}, style=filled, fillcolor={}, tooltip=\"{}\"", label, state.color(), state.name()));
     }
+    let pruned = graph.pruned.values().fold(0, |total, count| total + count);
+    let pruned = if pruned > 0 { format!(", pruned {}", pruned) } else { String::new() };
+    builder.set_graph_attrs(&format!("label=\"queued {}, running {}, done {}, failed {}{}\"", counts[0], counts[1], counts[2], counts[3], pruned));
     for edge in graph.graph.raw_edges() {
         let src = edge.source().index().to_string();
         let target = edge.target().index().to_string();
@@ -117,3 +630,298 @@ pub fn to_dot<'a>(graph: &CompGraph<'a>) -> String {
     builder.finish();
     return builder.buf;
 }
+
+/// Formats the computation graph can be exported in.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Format {
+    Dot,
+    Json,
+    GraphML
+}
+
+impl Format {
+    /// Parse the name of a format, the empty name selects dot.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "" | "dot" => Some(Format::Dot),
+            "json" => Some(Format::Json),
+            "graphml" => Some(Format::GraphML),
+            _ => None
+        }
+    }
+}
+
+/// Value of a node attribute in an exported graph.
+enum Attr {
+    Str(String),
+    Num(u64),
+    List(Vec<u64>)
+}
+
+impl CompGraph {
+    /// Attributes of a node: its kind, the operation name or objref, its session, the execution
+    /// state and, for objects, their holders, size and the timing of the call that computed them.
+    fn node_attrs<'b>(self: &CompGraph, idx: NodeIndex, exec: &ExecState<'b>) -> Vec<(&'static str, Attr)> {
+        let node = self.graph.node_weight(idx).unwrap();
+        let mut attrs = vec![("kind", Attr::Str(node.kind().into()))];
+        match *node {
+            Node::Map { opid } | Node::Reduce { opid } | Node::Op { opid } => {
+                attrs.push(("name", Attr::Str(self.ops[opid].clone())));
+                let session = self.graph.neighbors(idx).next().and_then(|result| match *self.graph.node_weight(result).unwrap() {
+                    Node::Obj { objref } => self.session(objref),
+                    _ => None
+                });
+                if let Some(session) = session {
+                    attrs.push(("session", Attr::Num(session)));
+                }
+            },
+            Node::Obj { objref } => {
+                attrs.push(("objref", Attr::Num(objref)));
+                if let Some(session) = self.session(objref) {
+                    attrs.push(("session", Attr::Num(session)));
+                }
+                let holders = exec.holders.get(objref as usize).map_or(vec![], |holders| holders.iter().map(|w| *w as u64).collect());
+                attrs.push(("holders", Attr::List(holders)));
+                if let Some(size) = self.sizes.get(&objref) {
+                    attrs.push(("size", Attr::Num(*size)));
+                }
+                if let Some(&(started, finished)) = self.timings.get(&objref) {
+                    attrs.push(("started", Attr::Num(started)));
+                    attrs.push(("finished", Attr::Num(finished)));
+                }
+            }
+        }
+        attrs.push(("state", Attr::Str(self.state(idx, exec).name().into())));
+        return attrs;
+    }
+}
+
+/// Render the graph as JSON with a list of nodes and a list of weighted edges.
+pub fn to_json<'b>(graph: &CompGraph, exec: &ExecState<'b>) -> String {
+    let mut out = String::from("{\"nodes\":[\n");
+    for i in 0..graph.graph.node_count() {
+        if i > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str(&format!("{{\"id\":{}", i));
+        for (key, value) in graph.node_attrs(NodeIndex::new(i), exec) {
+            let value = match value {
+                Attr::Str(value) => json_string(&value),
+                Attr::Num(value) => value.to_string(),
+                Attr::List(values) => format!("[{}]", values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(","))
+            };
+            out.push_str(&format!(",\"{}\":{}", key, value));
+        }
+        out.push_str("}");
+    }
+    out.push_str("\n],\"edges\":[\n");
+    for (i, edge) in graph.graph.raw_edges().iter().enumerate() {
+        if i > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str(&format!("{{\"source\":{},\"target\":{},\"weight\":{}}}", edge.source().index(), edge.target().index(), edge.weight));
+    }
+    out.push_str("\n]");
+    let pruned = graph.pruned_calls();
+    if pruned.len() > 0 {
+        let counts: Vec<String> = pruned.iter().map(|&(name, count)| format!("{}:{}", json_string(name), count)).collect();
+        out.push_str(&format!(",\"pruned\":{{{}}}", counts.join(",")));
+    }
+    out.push_str("}\n");
+    return out;
+}
+
+/// Escape text for use in XML.
+fn xml_escape(value: &str) -> String {
+    let mut result = String::new();
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            c => result.push(c)
+        }
+    }
+    return result;
+}
+
+/// Render the graph in the GraphML format.
+pub fn to_graphml<'b>(graph: &CompGraph, exec: &ExecState<'b>) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+    for &(key, kind) in [("kind", "string"), ("name", "string"), ("objref", "long"), ("holders", "string"), ("size", "long"), ("started", "long"), ("finished", "long"), ("session", "long"), ("state", "string")].iter() {
+        out.push_str(&format!("  <key id=\"{}\" for=\"node\" attr.name=\"{}\" attr.type=\"{}\"/>\n", key, key, kind));
+    }
+    out.push_str("  <key id=\"weight\" for=\"edge\" attr.name=\"weight\" attr.type=\"double\"/>\n");
+    out.push_str("  <graph id=\"G\" edgedefault=\"directed\">\n");
+    for i in 0..graph.graph.node_count() {
+        out.push_str(&format!("    <node id=\"n{}\">", i));
+        for (key, value) in graph.node_attrs(NodeIndex::new(i), exec) {
+            let value = match value {
+                Attr::Str(value) => xml_escape(&value),
+                Attr::Num(value) => value.to_string(),
+                Attr::List(values) => values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")
+            };
+            out.push_str(&format!("<data key=\"{}\">{}</data>", key, value));
+        }
+        out.push_str("</node>\n");
+    }
+    for edge in graph.graph.raw_edges() {
+        out.push_str(&format!("    <edge source=\"n{}\" target=\"n{}\"><data key=\"weight\">{}</data></edge>\n", edge.source().index(), edge.target().index(), edge.weight));
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    return out;
+}
+
+/// Export the graph in the given format.
+pub fn export<'b>(graph: &CompGraph, exec: &ExecState<'b>, format: Format) -> String {
+    match format {
+        Format::Dot => to_dot(graph, exec),
+        Format::Json => to_json(graph, exec),
+        Format::GraphML => to_graphml(graph, exec)
+    }
+}
+
+#[test]
+fn test_to_dot_state() {
+    let mut graph = CompGraph::new();
+    graph.add_obj();
+    graph.add_obj();
+    graph.add_op("f".into(), &[0], 1);
+    let holders = vec![vec![2], vec![]];
+    let mut running = HashSet::new();
+    let none = HashSet::new();
+    running.insert(1);
+    let dot = to_dot(&graph, &ExecState { holders: &holders, running: &running, failed: &none, freed: &none });
+    assert!(dot.contains("\"0\" [label=\"0\\n[2]\", style=filled, fillcolor=palegreen, tooltip=\"done\"];"));
+    assert!(dot.contains("\"2\" [label=\"f\", shape=box, style=filled, fillcolor=gold, tooltip=\"running\"];"));
+    assert!(dot.contains("graph [label=\"queued 0, running 1, done 0, failed 0\"];"));
+    assert!(dot.contains("\"2\" -> \"1\";"));
+}
+
+#[test]
+fn test_to_json() {
+    let mut graph = CompGraph::new();
+    graph.add_obj();
+    graph.add_obj();
+    graph.add_op("f".into(), &[0], 1);
+    graph.set_size(0, 80);
+    graph.set_timing(1, 10, 25);
+    let holders = vec![vec![2], vec![1]];
+    let none = HashSet::new();
+    let json = to_json(&graph, &ExecState { holders: &holders, running: &none, failed: &none, freed: &none });
+    assert_eq!(json, "{\"nodes\":[\n\
+        {\"id\":0,\"kind\":\"Obj\",\"objref\":0,\"holders\":[2],\"size\":80,\"state\":\"done\"},\n\
+        {\"id\":1,\"kind\":\"Obj\",\"objref\":1,\"holders\":[1],\"started\":10,\"finished\":25,\"state\":\"done\"},\n\
+        {\"id\":2,\"kind\":\"Op\",\"name\":\"f\",\"state\":\"done\"}\n\
+        ],\"edges\":[\n\
+        {\"source\":0,\"target\":2,\"weight\":0},\n\
+        {\"source\":2,\"target\":1,\"weight\":0}\n]}\n");
+}
+
+#[test]
+fn test_lineage() {
+    let mut graph = CompGraph::new();
+    for _ in 0..4 {
+        graph.add_obj();
+    }
+    graph.add_op("f".into(), &[0], 1);
+    graph.add_op("g".into(), &[1], 2);
+    graph.add_op("h".into(), &[0], 3);
+    assert_eq!(graph.lineage(2, 0, false), vec![0, 1]);
+    assert_eq!(graph.lineage(2, 1, false), vec![1]);
+    assert_eq!(graph.lineage(0, 0, true), vec![1, 2, 3]);
+    assert_eq!(graph.lineage(3, 0, true), Vec::<ObjRef>::new());
+    assert_eq!(graph.producer(2), Some("g"));
+    assert_eq!(graph.producer(0), None);
+}
+
+#[test]
+fn test_critical_path() {
+    let mut graph = CompGraph::new();
+    for _ in 0..4 {
+        graph.add_obj();
+    }
+    graph.add_op("f".into(), &[0], 1);
+    graph.add_op("g".into(), &[0], 2);
+    graph.add_op("f".into(), &[1], 3);
+    graph.set_size(0, 1000);
+    graph.set_timing(1, 100, 400);
+    graph.set_timing(2, 100, 200);
+    // object 3 is still being computed and is expected to take as long as the first call of f
+    let path = graph.critical_path();
+    assert_eq!(path.length, 10.0 + 300.0 + 300.0);
+    assert_eq!(graph.critical_objrefs(&path), vec![3]);
+}
+
+#[test]
+fn test_prune() {
+    let mut graph = CompGraph::new();
+    for objref in 0..5 {
+        graph.add_obj();
+        graph.set_session(objref, 1);
+    }
+    graph.add_op("f".into(), &[0], 1);
+    graph.add_op("f".into(), &[1], 2);
+    graph.add_op("g".into(), &[0], 3);
+    graph.add_op("f".into(), &[3], 4);
+    assert_eq!(graph.ops.len(), 2);
+    for objref in 0..4 {
+        graph.release(objref, 1);
+    }
+    // object 4 is still used, so objects 0 and 3 and the calls computing them stay; freed objects
+    // are not computed anymore
+    let holders = vec![vec![0], vec![], vec![0], vec![0], vec![0]];
+    let none = HashSet::new();
+    let mut freed = HashSet::new();
+    freed.insert(1);
+    assert_eq!(graph.prune(&ExecState { holders: &holders, running: &none, failed: &none, freed: &freed }), 4);
+    assert!(!graph.contains(1) && !graph.contains(2));
+    assert_eq!(graph.lineage(4, 0, false), vec![0, 3]);
+    assert_eq!(graph.pruned_calls(), vec![("f", 2)]);
+    // the freed object is forgotten, the one that is still held stays with its session
+    assert_eq!(graph.session(1), None);
+    assert_eq!(graph.session_objects(1), vec![0, 2, 3, 4]);
+    graph.release_session(1);
+    assert!(graph.session_objs.is_empty());
+    assert_eq!(graph.session(2), None);
+    assert_eq!(graph.session(0), Some(1));
+    // a pruned object can still be used as an argument
+    graph.add_obj();
+    graph.add_op("g".into(), &[2], 5);
+    assert_eq!(graph.lineage(5, 0, false), vec![2]);
+}
+
+#[test]
+fn test_release() {
+    let mut graph = CompGraph::new();
+    for objref in 0..2 {
+        graph.add_obj();
+        graph.set_session(objref, 1);
+    }
+    graph.add_user(0, 2);
+    graph.add_user(1, 2);
+    // an object is released once all sessions that use it released it or closed
+    graph.release(0, 1);
+    assert!(!graph.released.contains(&0));
+    graph.release(0, 2);
+    assert!(graph.released.contains(&0));
+    graph.release(1, 1);
+    graph.release_session(2);
+    assert!(graph.released.contains(&1));
+}
+
+#[test]
+fn test_sessions() {
+    let mut graph = CompGraph::new();
+    for objref in 0..4 {
+        graph.add_obj();
+        graph.set_session(objref, if objref < 3 { 1 } else { 2 });
+    }
+    graph.add_op("f".into(), &[0], 1);
+    graph.add_op("g".into(), &[1], 3);
+    assert_eq!(graph.session_objects(1), vec![0, 1, 2]);
+    assert!(!graph.used_by_other_session(0, 1));
+    assert!(graph.used_by_other_session(1, 1));
+    assert!(!graph.used_by_other_session(2, 1));
+}
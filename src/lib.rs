@@ -4,27 +4,41 @@
 #![feature(ip_addr)]
 #![feature(convert)]
 #![feature(box_syntax)]
+#![feature(custom_derive)]
+#![feature(deque_extras)]
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate protobuf;
+extern crate crypto;
 extern crate libc;
 extern crate rand;
+extern crate petgraph;
 
 extern crate zmq;
 
 pub mod comm;
 pub mod client;
+pub mod trace;
 pub mod utils;
+pub mod transport;
+mod graph;
+mod metrics;
+mod scheduler;
+pub mod server;
+pub mod cluster;
 
-use libc::{size_t, c_char, uint8_t};
+use libc::{size_t, c_char, c_int, uint8_t, int32_t, int64_t};
 use std::slice;
 use client::{Context};
+use utils::{ResourceMap, resources_from_proto};
 use std::ffi::CStr;
 use std::mem::transmute;
 use std::str;
 use std::str::FromStr;
 use std::net::IpAddr;
+use std::fs::File;
+use std::io::Write;
 use protobuf::{CodedInputStream, Message};
 
 fn string_from_c(string: *const c_char) -> String {
@@ -39,8 +53,40 @@ pub struct Slice {
     data: *const uint8_t
 }
 
+/// Resources are passed as a serialized `comm::Resources` message; if `resourceslen` is 0, the
+/// defaults are used.
+pub fn resources_from_c(resources: *const uint8_t, resourceslen: size_t) -> Option<ResourceMap> {
+    if resourceslen == 0 {
+        return None;
+    }
+    let bytes = unsafe { slice::from_raw_parts::<u8>(resources, resourceslen as usize) };
+    let mut result = comm::Resources::new();
+    let mut is = CodedInputStream::from_bytes(bytes);
+    result.merge_from(&mut is).unwrap();
+    return Some(resources_from_proto(&result));
+}
+
+/// The server may assign up to `num_slots` calls to this client at the same time, 0 counts as 1.
+#[no_mangle]
+pub extern "C" fn orchestra_create_context(server_addr: *const c_char, reply_port: u16, publish_port: u16, client_addr: *const c_char, client_port: u16, num_slots: size_t, resources: *const uint8_t, resourceslen: size_t) -> *mut Context {
+    let server_string = string_from_c(server_addr);
+    let server_addr = IpAddr::from_str(&server_string).unwrap(); // TODO: Proper error handling
+    let client_string = string_from_c(client_addr);
+    let client_addr = IpAddr::from_str(&client_string).unwrap(); // TODO: Proper error handling
+
+    match env_logger::init() {
+        Ok(()) => {},
+        SetLoggerError => {} // logging framework already initialized
+    }
+
+    let resources = resources_from_c(resources, resourceslen);
+    let res = unsafe { transmute(box Context::new(&server_addr, reply_port, publish_port, &client_addr, client_port, num_slots as usize, resources)) };
+    return res;
+}
+
+/// Connect to the server as a driver, which submits calls but never gets any assigned.
 #[no_mangle]
-pub extern "C" fn orchestra_create_context(server_addr: *const c_char, reply_port: u16, publish_port: u16, client_addr: *const c_char, client_port: u16) -> *mut Context {
+pub extern "C" fn orchestra_create_driver_context(server_addr: *const c_char, reply_port: u16, publish_port: u16, client_addr: *const c_char, client_port: u16) -> *mut Context {
     let server_string = string_from_c(server_addr);
     let server_addr = IpAddr::from_str(&server_string).unwrap(); // TODO: Proper error handling
     let client_string = string_from_c(client_addr);
@@ -51,13 +97,15 @@ pub extern "C" fn orchestra_create_context(server_addr: *const c_char, reply_por
         SetLoggerError => {} // logging framework already initialized
     }
 
-    let res = unsafe { transmute(box Context::new(&server_addr, reply_port, publish_port, &client_addr, client_port)) };
+    let res = unsafe { transmute(box Context::new_driver(&server_addr, reply_port, publish_port, &client_addr, client_port)) };
     return res;
 }
 
+/// Close the session of the client and free the context.
 #[no_mangle]
 pub extern "C" fn orchestra_destroy_context(context: *mut Context) {
-    let _drop_me: Box<Context> = unsafe { transmute(context) };
+    let mut context: Box<Context> = unsafe { transmute(context) };
+    context.close_session();
 }
 
 /*
@@ -81,16 +129,60 @@ pub extern "C" fn orchestra_get_type(context: *mut Context, name: *const c_char)
 
 */
 
+/// Calls of a `pure` function with the same arguments are only evaluated once, later calls get the
+/// result of the first one.
 #[no_mangle]
-pub extern "C" fn orchestra_register_function(context: *mut Context, name: *const c_char) -> usize {
+pub extern "C" fn orchestra_register_function(context: *mut Context, name: *const c_char, resources: *const uint8_t, resourceslen: size_t, pure: c_int) -> int64_t {
     let name = string_from_c(name);
-    unsafe { return (*context).add_function(name) };
+    let resources = resources_from_c(resources, resourceslen);
+    unsafe {
+        match (*context).add_function(name, resources, pure != 0) {
+            Ok(idx) => return idx as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
+}
+
+/// Register the functions of a serialized `comm::Manifest` in one message. Return the index of
+/// the first function, the others follow in the order of the manifest, or -1 if the client is a
+/// driver.
+#[no_mangle]
+pub extern "C" fn orchestra_register_manifest(context: *mut Context, manifest: *const uint8_t, manifestlen: size_t) -> int64_t {
+    let bytes = unsafe { slice::from_raw_parts::<u8>(manifest, manifestlen as usize) };
+    let mut result = comm::Manifest::new();
+    let mut is = CodedInputStream::from_bytes(bytes);
+    result.merge_from(&mut is).unwrap();
+    unsafe {
+        match (*context).add_manifest(result) {
+            Ok(first) => return first as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn orchestra_store_result(context: *mut Context, objref: size_t, data: *const uint8_t, datalen: size_t) {
     let data = unsafe { slice::from_raw_parts(data, datalen as usize) };
-    unsafe { (*context).add_object(objref, data.to_vec()) };
+    unsafe { (*context).store_result(objref, data.to_vec()) };
+}
+
+/// Tell the server that the call computing `objref` is done, its result has to be stored.
+#[no_mangle]
+pub extern "C" fn orchestra_finish(context: *mut Context, objref: size_t) {
+    unsafe { (*context).finish_request(objref) };
+}
+
+/// Report that the call computing `objref` raised `error` instead of storing its result.
+#[no_mangle]
+pub extern "C" fn orchestra_fail(context: *mut Context, objref: size_t, error: *const c_char) {
+    let error = string_from_c(error);
+    unsafe { (*context).fail_request(objref, error) };
 }
 
 pub fn args_from_c(args: *const uint8_t, argslen: size_t) -> comm::Args {
@@ -110,38 +202,167 @@ pub fn result_to_c(context: *mut Context, result: comm::Args) -> Slice {
 }
 */
 
+/// Calls with a higher `priority` are scheduled before calls with a lower one, the default is 0.
+/// Return the objref of the result or -1 if no worker registered the function.
 #[no_mangle]
-pub extern "C" fn orchestra_call(context: *mut Context, name: *const c_char, args: *const uint8_t, argslen: size_t) -> size_t {
+pub extern "C" fn orchestra_call(context: *mut Context, name: *const c_char, args: *const uint8_t, argslen: size_t, priority: int32_t, resources: *const uint8_t, resourceslen: size_t) -> int64_t {
     let name = string_from_c(name);
     let arguments = args_from_c(args, argslen);
+    let resources = resources_from_c(resources, resourceslen);
     unsafe {
-        return (*context).remote_call_function(name, arguments);
+        match (*context).remote_call_function(name, arguments, priority, resources) {
+            Ok(objref) => return objref as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
     }
 }
 
-/// retlist needs to be preallocated on caller side
+/// retlist needs to be preallocated on caller side. Return 0 on success and -1 if no worker
+/// registered the function.
 #[no_mangle]
-pub extern "C" fn orchestra_map(context: *mut Context, name: *const c_char, args: *const uint8_t, argslen: size_t, retlist: *mut size_t) {
+pub extern "C" fn orchestra_map(context: *mut Context, name: *const c_char, args: *const uint8_t, argslen: size_t, retlist: *mut size_t, priority: int32_t, resources: *const uint8_t, resourceslen: size_t) -> c_int {
     let name = string_from_c(name);
     let arguments = args_from_c(args, argslen);
+    let resources = resources_from_c(resources, resourceslen);
     unsafe {
-        let result = (*context).remote_call_map(name, arguments);
-        for (i, elem) in result.iter().enumerate() {
-            *retlist.offset(i as isize) = *elem;
+        match (*context).remote_call_map(name, arguments, priority, resources) {
+            Ok(result) => {
+                for (i, elem) in result.iter().enumerate() {
+                    *retlist.offset(i as isize) = *elem;
+                }
+                return 0;
+            },
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
         }
-    };
+    }
 }
 
+/// Create an actor with constructor `name`, the objref of the constructor's result is written to
+/// `result`. Return the id of the actor or -1 if no worker registered the constructor.
 #[no_mangle]
-pub extern "C" fn orchestra_pull(context: *mut Context, objref: size_t) -> size_t {
-    unsafe { return (*context).pull_remote_object(objref); }
+pub extern "C" fn orchestra_create_actor(context: *mut Context, name: *const c_char, args: *const uint8_t, argslen: size_t, result: *mut size_t) -> int64_t {
+    let name = string_from_c(name);
+    let arguments = args_from_c(args, argslen);
+    unsafe {
+        match (*context).remote_create_actor(name, arguments) {
+            Ok((actorid, objref)) => {
+                *result = objref;
+                return actorid as int64_t;
+            },
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
 }
 
+/// Call the method `name` on an actor, methods are registered as "module.Class.method". Return the
+/// objref of the result or -1 if the actor doesn't exist or its worker has been drained.
+#[no_mangle]
+pub extern "C" fn orchestra_call_actor(context: *mut Context, actorid: u64, name: *const c_char, args: *const uint8_t, argslen: size_t) -> int64_t {
+    let name = string_from_c(name);
+    let arguments = args_from_c(args, argslen);
+    unsafe {
+        match (*context).remote_call_actor(actorid, name, arguments) {
+            Ok(objref) => return objref as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
+}
+
+/// Ask for an object and return the objref of the object that will be delivered, wait for it with
+/// `orchestra_wait`. The slot of the call that is evaluated is lent to other calls until then.
+/// Return -1 if the object failed.
+#[no_mangle]
+pub extern "C" fn orchestra_pull(context: *mut Context, objref: size_t) -> int64_t {
+    unsafe {
+        match (*context).request_object(objref, true) {
+            Ok(objref) => return objref as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
+}
+
+/// Wait for an object requested with `orchestra_pull`. Return its objref once it arrived, or the
+/// objref of the result of a call that was assigned to the lent slot. That call has to be
+/// evaluated and finished with `orchestra_finish` before waiting again. Return -1 if the object
+/// failed.
+#[no_mangle]
+pub extern "C" fn orchestra_wait(context: *mut Context, objref: size_t) -> int64_t {
+    unsafe {
+        match (*context).wait_for_object(objref) {
+            Ok(ready) => return ready as int64_t,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
+}
+
+/// Allocate an object whose data `orchestra_store_result` stores, the server learns about it only
+/// then. Deprecated: use `orchestra_put` instead.
 #[no_mangle]
 pub extern "C" fn orchestra_push(context: *mut Context) -> size_t {
     unsafe { return (*context).push_remote_object(); }
 }
 
+/// Store `data` in the cluster and return its objref.
+#[no_mangle]
+pub extern "C" fn orchestra_put(context: *mut Context, data: *const uint8_t, datalen: size_t) -> size_t {
+    let data = unsafe { slice::from_raw_parts(data, datalen as usize) };
+    unsafe { return (*context).put_object(data.to_vec()); }
+}
+
+/// Bind `name` to an object. Return 0 on success and -1 if the name is already bound and
+/// `overwrite` is false.
+#[no_mangle]
+pub extern "C" fn orchestra_put_name(context: *mut Context, name: *const c_char, objref: size_t, overwrite: c_int) -> c_int {
+    let name = string_from_c(name);
+    unsafe {
+        match (*context).put_name(name, objref as u64, overwrite != 0) {
+            Ok(()) => return 0,
+            Err(error) => {
+                error!("{}", error);
+                return -1;
+            }
+        }
+    }
+}
+
+/// Return the object bound to `name` or -1 if the name is not bound.
+#[no_mangle]
+pub extern "C" fn orchestra_get_name(context: *mut Context, name: *const c_char) -> int64_t {
+    let name = string_from_c(name);
+    unsafe { (*context).get_name(name).map_or(-1, |objref| objref as int64_t) }
+}
+
+/// Return the names starting with `prefix` as serialized `comm::Names`, the data is valid until the
+/// next query.
+#[no_mangle]
+pub extern "C" fn orchestra_list_names(context: *mut Context, prefix: *const c_char) -> Slice {
+    let prefix = string_from_c(prefix);
+    unsafe {
+        let names = (*context).list_names(prefix);
+        (*context).result.clear();
+        names.write_to_writer(&mut (*context).result).unwrap();
+        return Slice { len: (*context).result[..].len(), data: (*context).result[..].as_ptr() }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn orchestra_debug_info(context: *mut Context) {
     unsafe {
@@ -162,11 +383,93 @@ pub extern "C" fn orchestra_debug_info(context: *mut Context) {
     }
 }
 
+/// Write `contents` to `filename`, return 0 on success and -1 otherwise.
+fn write_file(filename: &str, contents: &str) -> c_int {
+    match File::create(filename).and_then(|mut file| file.write_all(contents.as_bytes())) {
+        Ok(()) => return 0,
+        Err(err) => {
+            error!("could not write {}: {}", filename, err);
+            return -1;
+        }
+    }
+}
+
+/// If `enabled` is nonzero, record a timeline of the calls evaluated by this client from now on.
+#[no_mangle]
+pub extern "C" fn orchestra_set_tracing(context: *mut Context, enabled: c_int) {
+    unsafe { (*context).set_tracing(enabled != 0) };
+}
+
+/// Write the timeline of the calls evaluated by this client to `filename` as Chrome trace JSON,
+/// it is empty unless tracing was enabled with `orchestra_set_tracing`. Return 0 on success and
+/// -1 otherwise.
+#[no_mangle]
+pub extern "C" fn orchestra_write_trace(context: *mut Context, filename: *const c_char) -> c_int {
+    let filename = string_from_c(filename);
+    let json = unsafe { (*context).trace_json() };
+    return write_file(&filename, &json);
+}
+
+/// Write the timeline recorded by the server to `filename` as Chrome trace JSON. Return 0 on
+/// success and -1 otherwise.
+#[no_mangle]
+pub extern "C" fn orchestra_write_server_trace(context: *mut Context, filename: *const c_char) -> c_int {
+    let filename = string_from_c(filename);
+    let json = unsafe { (*context).server_trace_json() };
+    return write_file(&filename, &json);
+}
+
+/// If `enabled` is nonzero, objects stored from now on are hashed, and objects with the same
+/// content share their data on this client and are deduplicated by the server.
+#[no_mangle]
+pub extern "C" fn orchestra_set_dedup(context: *mut Context, enabled: c_int) {
+    unsafe { (*context).set_dedup(enabled != 0) };
+}
+
+/// Tell the server that the driver no longer uses `objref`, so its lineage may be pruned.
+#[no_mangle]
+pub extern "C" fn orchestra_release(context: *mut Context, objref: size_t) {
+    unsafe { (*context).release(objref) };
+}
+
+/// Query the lineage of `objref`: the objects it was computed from, or the objects computed from it
+/// if `descendants` is nonzero, following at most `depth` calls (0 follows all of them). Returns a
+/// serialized Lineage message that stays valid until the next query, it is empty if the object
+/// does not exist.
+#[no_mangle]
+pub extern "C" fn orchestra_lineage(context: *mut Context, objref: size_t, depth: size_t, descendants: c_int) -> Slice {
+    unsafe {
+        (*context).result.clear();
+        match (*context).lineage(objref, depth as u64, descendants != 0) {
+            Ok(lineage) => lineage.write_to_writer(&mut (*context).result).unwrap(),
+            Err(error) => error!("{}", error)
+        }
+        return Slice { len: (*context).result[..].len(), data: (*context).result[..].as_ptr() }
+    }
+}
+
+/// Export the computation graph in `format` (dot, json or graphml). The slice is empty if the
+/// format is unknown and stays valid until the next query.
+#[no_mangle]
+pub extern "C" fn orchestra_export_graph(context: *mut Context, format: *const c_char) -> Slice {
+    let format = string_from_c(format);
+    unsafe {
+        (*context).result.clear();
+        match (*context).export_graph(format) {
+            Ok(graph) => (*context).result.extend(graph.as_bytes()),
+            Err(error) => error!("{}", error)
+        }
+        return Slice { len: (*context).result[..].len(), data: (*context).result[..].as_ptr() }
+    }
+}
+
+/// Finish the calls handed out by previous steps and return the next call that is ready, or -1
+/// once the server shut down this worker.
 #[no_mangle]
-pub extern "C" fn orchestra_step(context: *mut Context) -> size_t {
+pub extern "C" fn orchestra_step(context: *mut Context) -> int64_t {
     unsafe {
-        (*context).finish_request();
-        return (*context).client_step();
+        (*context).finish_all_requests();
+        return (*context).client_step().map_or(-1, |objref| objref as int64_t);
     }
 }
 
@@ -175,6 +478,12 @@ pub extern "C" fn orchestra_function_index(context: *mut Context) -> usize {
     unsafe { (*context).get_function() }
 }
 
+/// Return the actor the current function is called on or -1 if it is not an actor call.
+#[no_mangle]
+pub extern "C" fn orchestra_actor_id(context: *mut Context) -> int64_t {
+    unsafe { (*context).get_actor().map_or(-1, |actorid| actorid as int64_t) }
+}
+
 #[no_mangle]
 pub extern "C" fn orchestra_get_args(context: *mut Context) -> Slice {
     unsafe { return Slice { len: (*context).args[..].len(), data: (*context).args[..].as_ptr() } }
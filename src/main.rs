@@ -10,30 +10,46 @@ extern crate env_logger;
 extern crate rand;
 extern crate petgraph;
 extern crate protobuf;
+extern crate crypto;
 extern crate zmq;
 
 pub mod comm;
 mod types;
 mod graph;
+pub mod metrics;
+mod trace;
 pub mod server;
 pub mod scheduler;
 pub mod utils;
+pub mod transport;
 
-use argparse::{ArgumentParser, Store};
+use argparse::{ArgumentParser, Store, StoreTrue};
+use metrics::start_http_thread;
 
 fn main() {
     let mut incoming_port = 0;
     let mut publish_port = 0;
     let mut setup_port = 0;
+    let mut metrics_port = 0;
+    let mut tracing = false;
+    let mut critical_path = false;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Orchestra server");
         ap.refer(&mut incoming_port).add_argument("incoming_port", Store, "port for incoming requests");
         ap.refer(&mut publish_port).add_argument("publish_port", Store, "port for message broadcasting");
         ap.refer(&mut setup_port).add_argument("setup_port", Store, "port for setting up broadcasting");
+        ap.refer(&mut metrics_port).add_option(&["--metrics-port"], Store, "port for serving Prometheus metrics over HTTP");
+        ap.refer(&mut tracing).add_option(&["--trace"], StoreTrue, "record a timeline of all calls");
+        ap.refer(&mut critical_path).add_option(&["--critical-path"], StoreTrue, "prefer calls on the critical path of the computation graph");
         ap.parse_args_or_exit();
     }
     env_logger::init().unwrap();
     let mut server = server::Server::new(publish_port);
+    server.set_tracing(tracing);
+    server.set_critical_path_scheduling(critical_path);
+    if metrics_port != 0 {
+        start_http_thread(server.metrics(), metrics_port);
+    }
     server.main_loop(incoming_port, setup_port);
 }
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Counters and gauges describing the state of the server. They are updated by the server, the
+/// worker pool and the scheduler and can be scraped in the Prometheus text format.
+pub struct Metrics {
+  /// Number of registered workers.
+  pub workers: AtomicUsize,
+  /// Number of registered drivers.
+  pub drivers: AtomicUsize,
+  /// Number of jobs waiting in the scheduler's queues.
+  pub job_queue: AtomicUsize,
+  /// Number of pull requests waiting for their object.
+  pub pull_queue: AtomicUsize,
+  /// Number of objects in the object table.
+  pub objects: AtomicUsize,
+  /// Number of delivery requests sent to workers.
+  pub deliveries: AtomicUsize,
+  /// Number of bytes delivered between workers, as reported by the receiving workers.
+  pub bytes_transferred: AtomicUsize,
+  /// Number of calls of pure functions that got the result of an earlier call.
+  pub memoized_calls: AtomicUsize,
+  /// Number of objects whose content was already stored under a different objref.
+  pub deduplicated_objects: AtomicUsize,
+  /// Number of objects freed because the session that created them was closed.
+  pub freed_objects: AtomicUsize,
+  /// Number of calls submitted for each function.
+  calls_submitted: Mutex<HashMap<String, usize>>,
+  /// Number of calls completed for each function.
+  calls_completed: Mutex<HashMap<String, usize>>,
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    return Metrics {
+      workers: AtomicUsize::new(0),
+      drivers: AtomicUsize::new(0),
+      job_queue: AtomicUsize::new(0),
+      pull_queue: AtomicUsize::new(0),
+      objects: AtomicUsize::new(0),
+      deliveries: AtomicUsize::new(0),
+      bytes_transferred: AtomicUsize::new(0),
+      memoized_calls: AtomicUsize::new(0),
+      deduplicated_objects: AtomicUsize::new(0),
+      freed_objects: AtomicUsize::new(0),
+      calls_submitted: Mutex::new(HashMap::new()),
+      calls_completed: Mutex::new(HashMap::new())
+    }
+  }
+
+  pub fn call_submitted(self: &Metrics, fnname: &str) {
+    *self.calls_submitted.lock().unwrap().entry(fnname.into()).or_insert(0) += 1;
+  }
+
+  pub fn call_completed(self: &Metrics, fnname: &str) {
+    *self.calls_completed.lock().unwrap().entry(fnname.into()).or_insert(0) += 1;
+  }
+
+  /// Render all metrics in the Prometheus text exposition format.
+  pub fn render(self: &Metrics) -> String {
+    let mut out = String::new();
+    write_metric(&mut out, "orchestra_workers", "gauge", "Number of registered workers.", self.workers.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_drivers", "gauge", "Number of registered drivers.", self.drivers.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_job_queue_length", "gauge", "Number of jobs waiting for a worker.", self.job_queue.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_pull_queue_length", "gauge", "Number of pulls waiting for their object.", self.pull_queue.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_objects", "gauge", "Number of objects in the object table.", self.objects.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_deliveries_total", "counter", "Number of delivery requests sent to workers.", self.deliveries.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_transferred_bytes_total", "counter", "Number of bytes delivered between workers.", self.bytes_transferred.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_memoized_calls_total", "counter", "Number of calls of pure functions answered with an earlier result.", self.memoized_calls.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_deduplicated_objects_total", "counter", "Number of objects whose content was already stored under a different objref.", self.deduplicated_objects.load(Ordering::Relaxed));
+    write_metric(&mut out, "orchestra_freed_objects_total", "counter", "Number of objects freed because the session that created them was closed.", self.freed_objects.load(Ordering::Relaxed));
+    write_per_function(&mut out, "orchestra_calls_submitted_total", "Number of calls submitted per function.", &self.calls_submitted.lock().unwrap());
+    write_per_function(&mut out, "orchestra_calls_completed_total", "Number of calls completed per function.", &self.calls_completed.lock().unwrap());
+    return out;
+  }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+  write!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value).unwrap();
+}
+
+fn write_per_function(out: &mut String, name: &str, help: &str, values: &HashMap<String, usize>) {
+  write!(out, "# HELP {} {}\n# TYPE {} counter\n", name, help, name).unwrap();
+  let mut fnnames: Vec<&String> = values.keys().collect();
+  fnnames.sort();
+  for fnname in fnnames {
+    write!(out, "{}{{function=\"{}\"}} {}\n", name, escape_label(fnname), values[fnname]).unwrap();
+  }
+}
+
+/// Escape a label value as required by the Prometheus text format.
+fn escape_label(value: &str) -> String {
+  let mut result = String::new();
+  for c in value.chars() {
+    match c {
+      '\\' => result.push_str("\\\\"),
+      '"' => result.push_str("\\\""),
+      '\n' => result.push_str("\\n"),
+      c => result.push(c)
+    }
+  }
+  return result;
+}
+
+#[test]
+fn test_escape_label() {
+  assert_eq!(escape_label("mapreduce.sum"), "mapreduce.sum");
+  assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+}
+
+/// Seconds a scraper may take to send its request or read the answer before it is dropped.
+const TIMEOUT_SECS: u64 = 5;
+
+/// Answer a single HTTP request; `GET /metrics` returns the metrics, everything else is not found.
+fn serve(stream: &mut TcpStream, metrics: &Metrics) {
+  // requests are answered one at a time, a client that never sends must not stall the others
+  let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+  if let Err(err) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+    warn!("could not set metrics timeout: {}", err);
+    return;
+  }
+  let mut buf = [0; 1024];
+  let len = match stream.read(&mut buf) {
+    Ok(len) => len,
+    Err(_) => return
+  };
+  let request = String::from_utf8_lossy(&buf[..len]);
+  let (status, body) = if request.starts_with("GET /metrics ") || request.starts_with("GET / ") {
+    ("200 OK", metrics.render())
+  } else {
+    ("404 Not Found", "not found\n".to_string())
+  };
+  let response = format!("HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body);
+  match stream.write_all(response.as_bytes()) {
+    Ok(()) => {},
+    Err(err) => warn!("could not send metrics: {}", err)
+  }
+}
+
+/// Start the thread that serves the metrics over HTTP on `port`.
+pub fn start_http_thread(metrics: Arc<Metrics>, port: u16) {
+  let listener = match TcpListener::bind(("0.0.0.0", port)) {
+    Ok(listener) => listener,
+    Err(err) => panic!("Could not bind metrics port {}. {}", port, err)
+  };
+  info!("serving metrics on port {}", port);
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      match stream {
+        Ok(mut stream) => serve(&mut stream, &metrics),
+        Err(err) => warn!("metrics connection failed: {}", err)
+      }
+    }
+  });
+}
@@ -1,76 +1,469 @@
 use std::iter::FromIterator;
-use std::collections::VecDeque;
+use std::collections::{VecDeque, BTreeMap, HashMap, HashSet};
 use std::thread;
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
-use std::sync::{Arc, RwLock, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
 use comm;
-use utils::{WorkerID, ObjRef, ObjTable, FnTable};
-use server::Worker;
+use utils::{WorkerID, ObjRef, ActorID, ObjTable, FnTable, ResourceMap, ResourceTable};
+use utils::{default_resources, resources_from_proto, fits, acquire, release};
+use metrics::Metrics;
+use trace::{Trace, now_us, worker_pid};
+use std::sync::atomic::Ordering;
 use protobuf::RepeatedField;
 
 /// Notify the scheduler that something happened
 pub enum Event {
-  /// A worker becomes available for computation.
+  /// A slot of a worker becomes available for computation.
   Worker(WorkerID),
-  /// An object becomes available.
-  Obj(ObjRef),
+  /// A worker finished the call that computes the given object and releases its resources.
+  Done(WorkerID, ObjRef),
+  /// The call computing the given object blocks on a pull, so its slot can be used by other calls.
+  Blocked(WorkerID, ObjRef),
+  /// The call computing the given object continues after being blocked.
+  Unblocked(WorkerID, ObjRef),
+  /// A worker holds a copy of an object, the object is available once the first worker holds it.
+  Obj(ObjRef, WorkerID),
+  /// An object is deleted on all workers that hold it.
+  Free(ObjRef),
+  /// A client is gone, and so are its copies of objects.
+  Lost(WorkerID),
   /// A job is being scheduled.
   Job(comm::Call),
   /// A pull request was issued.
   Pull(WorkerID, ObjRef),
-  /// A new worker has been added.
-  Register(WorkerID, Sender<comm::Message>),
+  /// Messages for a new worker are sent through the channel.
+  Connect(WorkerID, Sender<comm::Message>),
+  /// A new worker with the given resources has been added.
+  Register(WorkerID, ResourceMap),
+  /// A worker can evaluate the named function. If the function declared the resources a call
+  /// needs, they are given as well.
+  Function(WorkerID, String, Option<ResourceMap>),
   /// Dump status of the scheduler.
-  Debug(WorkerID)
+  Debug(WorkerID),
+  /// Send the status of the scheduler through the channel.
+  Info(Sender<comm::SchedulerInfo>),
+  /// Stop assigning calls to a worker. If the flag is set, the worker is shut down once its
+  /// running calls are done. The actors living on the worker are lost, their ids and the results
+  /// of their dropped method calls are sent through the channel.
+  Drain(WorkerID, bool, Sender<(Vec<ActorID>, Vec<ObjRef>)>),
+  /// A drained worker with the given number of slots gets calls again.
+  Resume(WorkerID, usize),
+  /// Results of the calls on the critical path of the computation graph, queued calls computing
+  /// them are preferred over other calls of the same priority.
+  CriticalPath(HashSet<ObjRef>),
+  /// Set the weight of a client in the fair sharing of the workers.
+  Weight(WorkerID, f64),
+  /// The session of a client is closed, its queued calls and pulls are dropped. The results of the
+  /// dropped calls are sent through the channel.
+  CloseSession(WorkerID, Sender<Vec<ObjRef>>),
+  /// The given objects never become available. Queued calls that take one of them as an argument
+  /// are dropped and their results are sent through the channel, pulls of them fail.
+  Failed(Vec<ObjRef>, Sender<Vec<ObjRef>>)
 }
 
-/// A scheduler assigns incoming jobs to workers. It communicates with the worker pool through
-/// channels. If a job is scheduled or a worker becomes available, this is signaled to the
-/// Scheduler using the channel returned by the `Scheduler::start` method. The scheduler signals the
-/// execution of a function call to the appropriate worker thread via a channel that is registered
-/// using `Event::Register`.
-pub struct Scheduler {
-  objtable: Arc<Mutex<ObjTable>>,
-  fntable: Arc<RwLock<FnTable>>,
+/// Position of a job in the `JobQueue`, given by its priority level and its index in that level.
+pub type JobIdx = (i32, usize);
+
+/// The queue of jobs that wait for a worker. Jobs are grouped by priority; levels are visited
+/// from the highest to the lowest priority and within a level jobs are kept in submission order.
+///
+/// The clients that submit jobs share the workers by weighted fair sharing: each client is charged
+/// for the calls it gets, and the jobs of the client with the lowest charge relative to its weight
+/// come first, whatever their priority. Priorities only order the jobs of one client, so a client
+/// can't take the workers from the others by raising the priority of its calls. A client that
+/// starts submitting again is charged at least as much as the clients that are waiting already, so
+/// it can't catch up on the time it was idle and starve them.
+pub struct JobQueue {
+  levels: BTreeMap<i32, VecDeque<comm::Call>>,
+  len: usize,
+  /// Number of queued jobs of each client.
+  queued: HashMap<WorkerID, usize>,
+  /// Weight of each client that doesn't have weight 1.
+  weights: HashMap<WorkerID, f64>,
+  /// Calls each client was charged for.
+  usage: HashMap<WorkerID, f64>
 }
 
-impl Scheduler {
-  /// Start the scheduling thread.
-  pub fn start(objtable: Arc<Mutex<ObjTable>>, fntable: Arc<RwLock<FnTable>>) -> Sender<Event> {
-    let (event_sender, event_receiver) = mpsc::channel(); // notify the scheduler that a worker, job or object becomes available
-    let scheduler = Scheduler { objtable: objtable.clone(), fntable: fntable.clone() };
-    scheduler.start_dispatch_thread(event_receiver);
-    return event_sender
+impl JobQueue {
+  pub fn new() -> JobQueue {
+    return JobQueue { levels: BTreeMap::new(), len: 0, queued: HashMap::new(), weights: HashMap::new(), usage: HashMap::new() };
   }
 
-  fn send_function_call(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID, job: comm::Call) {
-    info!("scheduling function call {} on worker {}", job.get_name(), workerid);
-    let mut msg = comm::Message::new();
-    msg.set_field_type(comm::MessageType::INVOKE);
-    msg.set_call(job);
-    workers[workerid].send(msg).unwrap();
+  /// Set the weight of a client, a client with twice the weight gets twice as many calls.
+  pub fn set_weight(self: &mut JobQueue, submitter: WorkerID, weight: f64) {
+    self.weights.insert(submitter, weight);
   }
 
-  fn send_pull_request(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID, objref: ObjRef) {
-    let mut msg = comm::Message::new();
-    msg.set_field_type(comm::MessageType::PULL);
-    msg.set_workerid(workerid as u64);
-    msg.set_objref(objref);
-    workers[workerid].send(msg).unwrap();
+  /// The charge of a client relative to its weight.
+  fn share(self: &JobQueue, submitter: WorkerID) -> f64 {
+    let weight = *self.weights.get(&submitter).unwrap_or(&1.0);
+    return *self.usage.get(&submitter).unwrap_or(&0.0) / weight;
   }
 
-  fn send_debugging_info(self: &Scheduler, socket: &Sender<comm::Message>, worker_queue: &VecDeque<WorkerID>, job_queue: &VecDeque<comm::Call>) {
-    let mut scheduler_info = comm::SchedulerInfo::new();
-    scheduler_info.set_worker_queue(worker_queue.iter().map(|x| *x as u64).collect());
+  /// Charge the client that submitted `job` for one call.
+  pub fn charge(self: &mut JobQueue, job: &comm::Call) {
+    *self.usage.entry(job.get_submitter() as WorkerID).or_insert(0.0) += 1.0;
+  }
+
+  /// Append a job at the end of its priority level.
+  pub fn push(self: &mut JobQueue, job: comm::Call) {
+    let submitter = job.get_submitter() as WorkerID;
+    if *self.queued.get(&submitter).unwrap_or(&0) == 0 {
+      // the lowest share of the clients that wait
+      let mut lowest = None;
+      for (other, count) in self.queued.iter() {
+        let share = self.share(*other);
+        if *count > 0 && lowest.map_or(true, |lowest| share < lowest) {
+          lowest = Some(share);
+        }
+      }
+      if let Some(lowest) = lowest {
+        let usage = lowest * *self.weights.get(&submitter).unwrap_or(&1.0);
+        let entry = self.usage.entry(submitter).or_insert(0.0);
+        if *entry < usage {
+          *entry = usage;
+        }
+      }
+    }
+    *self.queued.entry(submitter).or_insert(0) += 1;
+    let priority = job.get_priority();
+    self.levels.entry(priority).or_insert(VecDeque::new()).push_back(job);
+    self.len += 1;
+  }
+
+  /// Find the next job satisfying `pred`: a job of the client with the lowest share, highest
+  /// priority first and FIFO within a priority.
+  pub fn find<F : FnMut(&comm::Call) -> bool>(self: &JobQueue, pred: F) -> Option<JobIdx> {
+    return self.find_preferred(pred, |_| false);
+  }
+
+  /// Like `find`, but among the jobs of the client with the lowest share and the highest priority,
+  /// the first job that also satisfies `prefer` comes first.
+  pub fn find_preferred<F : FnMut(&comm::Call) -> bool, G : FnMut(&comm::Call) -> bool>(self: &JobQueue, mut pred: F, mut prefer: G) -> Option<JobIdx> {
+    let mut best = None; // share, priority, not preferred and index of the best job so far
+    for (priority, level) in self.levels.iter().rev() {
+      for (i, job) in level.iter().enumerate() {
+        if pred(job) {
+          let key = (self.share(job.get_submitter() as WorkerID), !prefer(job));
+          let better = match best {
+            None => true,
+            Some((share, best_priority, unpreferred, _)) => key.0 < share || (key.0 == share && best_priority == *priority && unpreferred && !key.1)
+          };
+          if better {
+            best = Some((key.0, *priority, key.1, i));
+          }
+        }
+      }
+    }
+    return best.map(|(_, priority, _, i)| (priority, i));
+  }
+
+  /// Remove the job at position `idx` from the queue; the order of the remaining jobs is kept.
+  pub fn remove(self: &mut JobQueue, idx: JobIdx) -> comm::Call {
+    let (priority, i) = idx;
+    let (job, empty) = {
+      let level = self.levels.get_mut(&priority).expect("priority level not found");
+      let job = level.remove(i).expect("job index out of range");
+      (job, level.len() == 0)
+    };
+    *self.queued.get_mut(&(job.get_submitter() as WorkerID)).expect("client has no queued jobs") -= 1;
+    if empty {
+      self.levels.remove(&priority);
+    }
+    self.len -= 1;
+    return job;
+  }
+
+  /// Remove all jobs a client submitted.
+  pub fn remove_submitter(self: &mut JobQueue, submitter: WorkerID) -> Vec<comm::Call> {
+    return self.remove_if(|job| job.get_submitter() as WorkerID == submitter);
+  }
+
+  /// Remove all jobs satisfying `pred`.
+  pub fn remove_if<F : FnMut(&comm::Call) -> bool>(self: &mut JobQueue, mut pred: F) -> Vec<comm::Call> {
+    let mut removed = Vec::new();
+    for (_, level) in self.levels.iter_mut() {
+      let mut kept = VecDeque::new();
+      while let Some(job) = level.pop_front() {
+        if pred(&job) {
+          removed.push(job);
+        } else {
+          kept.push_back(job);
+        }
+      }
+      *level = kept;
+    }
+    let empty: Vec<i32> = self.levels.iter().filter(|&(_, level)| level.len() == 0).map(|(priority, _)| *priority).collect();
+    for priority in empty {
+      self.levels.remove(&priority);
+    }
+    self.len -= removed.len();
+    for job in removed.iter() {
+      *self.queued.get_mut(&(job.get_submitter() as WorkerID)).unwrap() -= 1;
+    }
+    return removed;
+  }
+
+  /// Return the number of queued jobs.
+  pub fn len(self: &JobQueue) -> usize {
+    return self.len;
+  }
+
+  /// Copy all queued jobs in scheduling order.
+  pub fn to_vec(self: &JobQueue) -> Vec<comm::Call> {
     let mut jobs = Vec::new();
-    for job in job_queue.iter() {
-      jobs.push(job.clone());
+    for (_, level) in self.levels.iter().rev() {
+      for job in level.iter() {
+        jobs.push(job.clone());
+      }
+    }
+    return jobs;
+  }
+}
+
+/// Method calls of actors that wait for their actor. An actor is pinned to the worker its
+/// constructor was scheduled on and evaluates one call at a time in submission order.
+pub struct ActorQueues {
+  /// The worker each created actor lives on.
+  workers: HashMap<ActorID, WorkerID>,
+  /// Pending method calls of each actor in submission order.
+  queues: HashMap<ActorID, VecDeque<comm::Call>>,
+  /// For each actor that is evaluating a call, the objref of the call's result.
+  busy: HashMap<ObjRef, ActorID>
+}
+
+impl ActorQueues {
+  pub fn new() -> ActorQueues {
+    return ActorQueues { workers: HashMap::new(), queues: HashMap::new(), busy: HashMap::new() };
+  }
+
+  /// Append a method call to the queue of its actor.
+  pub fn push(self: &mut ActorQueues, job: comm::Call) {
+    self.queues.entry(job.get_actorid()).or_insert(VecDeque::new()).push_back(job);
+  }
+
+  /// Pin the actor to the worker that evaluates its constructor.
+  pub fn pin(self: &mut ActorQueues, actorid: ActorID, workerid: WorkerID) {
+    self.workers.insert(actorid, workerid);
+  }
+
+  /// Find an idle actor on worker `workerid` whose next call satisfies `pred`.
+  pub fn find<F : FnMut(&comm::Call) -> bool>(self: &ActorQueues, workerid: WorkerID, mut pred: F) -> Option<ActorID> {
+    for (actorid, queue) in self.queues.iter() {
+      if self.workers.get(actorid) != Some(&workerid) || self.busy.values().any(|a| a == actorid) {
+        continue;
+      }
+      if let Some(job) = queue.front() {
+        if pred(job) {
+          return Some(*actorid);
+        }
+      }
+    }
+    return None;
+  }
+
+  /// Remove the next call of an actor from its queue, and the queue once it is empty.
+  pub fn pop(self: &mut ActorQueues, actorid: ActorID) -> comm::Call {
+    let (job, empty) = {
+      let queue = self.queues.get_mut(&actorid).expect("no call queued for actor");
+      let job = queue.pop_front().expect("no call queued for actor");
+      (job, queue.is_empty())
+    };
+    if empty {
+      self.queues.remove(&actorid);
     }
-    scheduler_info.set_job_queue(RepeatedField::from_vec(jobs));
-    let objtable = self.objtable.lock().unwrap();
+    return job;
+  }
+
+  /// Mark an actor as busy until the call computing `objref` is done.
+  pub fn start(self: &mut ActorQueues, actorid: ActorID, objref: ObjRef) {
+    self.busy.insert(objref, actorid);
+  }
+
+  /// The call computing `objref` is done; if it belonged to an actor, the actor becomes idle.
+  pub fn done(self: &mut ActorQueues, objref: ObjRef) {
+    self.busy.remove(&objref);
+  }
+
+  /// Remove the actors pinned to worker `workerid` together with their queued method calls. Return
+  /// the ids of the removed actors and the removed calls.
+  pub fn remove_worker(self: &mut ActorQueues, workerid: WorkerID) -> (Vec<ActorID>, Vec<comm::Call>) {
+    let mut actorids: Vec<ActorID> = self.workers.iter().filter(|&(_, w)| *w == workerid).map(|(actorid, _)| *actorid).collect();
+    actorids.sort();
+    let mut removed = Vec::new();
+    for actorid in actorids.iter() {
+      self.workers.remove(actorid);
+      if let Some(queue) = self.queues.remove(actorid) {
+        removed.extend(queue.into_iter());
+      }
+    }
+    return (actorids, removed);
+  }
+
+  /// Remove all method calls a client submitted.
+  pub fn remove_submitter(self: &mut ActorQueues, submitter: WorkerID) -> Vec<comm::Call> {
+    return self.remove_if(|job| job.get_submitter() as WorkerID == submitter);
+  }
+
+  /// Remove all method calls satisfying `pred` and the queues that become empty.
+  pub fn remove_if<F : FnMut(&comm::Call) -> bool>(self: &mut ActorQueues, mut pred: F) -> Vec<comm::Call> {
+    let mut removed = Vec::new();
+    let mut empty = Vec::new();
+    for (actorid, queue) in self.queues.iter_mut() {
+      let mut kept = VecDeque::new();
+      while let Some(job) = queue.pop_front() {
+        if pred(&job) {
+          removed.push(job);
+        } else {
+          kept.push_back(job);
+        }
+      }
+      if kept.is_empty() {
+        empty.push(*actorid);
+      }
+      *queue = kept;
+    }
+    for actorid in empty {
+      self.queues.remove(&actorid);
+    }
+    return removed;
+  }
+
+  /// Return the number of queued method calls.
+  pub fn len(self: &ActorQueues) -> usize {
+    return self.queues.values().map(|queue| queue.len()).fold(0, |a, b| a + b);
+  }
+
+  /// Copy all queued method calls.
+  pub fn to_vec(self: &ActorQueues) -> Vec<comm::Call> {
+    let mut jobs = Vec::new();
+    for (_, queue) in self.queues.iter() {
+      for job in queue.iter() {
+        jobs.push(job.clone());
+      }
+    }
+    return jobs;
+  }
+}
+
+/// Something the scheduler asks a worker to do.
+pub enum Action {
+  /// Evaluate a call.
+  Invoke(WorkerID, comm::Call),
+  /// Receive an object that the worker pulled.
+  Pull(WorkerID, ObjRef),
+  /// Shut down, the worker has been drained and its calls are done.
+  Shutdown(WorkerID),
+  /// Tell the worker that an object it pulled never becomes available.
+  Fail(WorkerID, ObjRef)
+}
+
+/// The scheduling logic as a state machine: `handle` consumes one `Event` at a time and returns
+/// the actions that follow from it, without any threads or channels involved.
+///
+/// A job is only placed on a worker that has enough free resources for it; the resources stay
+/// allocated until the worker signals that the job is done. While a job blocks on a pull, its
+/// slot and resources are lent to other jobs and taken back once the pull returns; the client
+/// evaluates the jobs assigned to a lent slot while it waits for the pull. Method calls of
+/// actors are kept in per-actor queues next to the job queue and are preferred over other jobs.
+///
+/// The state owns its tables of objects, functions and resources; the server keeps them up to date
+/// with `Event::Obj`, `Event::Free`, `Event::Lost` and `Event::Function`.
+pub struct SchedulerState {
+  /// Workers holding each object that became available so far.
+  objtable: ObjTable,
+  /// Workers that can evaluate each function (sorted).
+  fntable: FnTable,
+  /// Resources needed by one call of each function that declared them.
+  restable: ResourceTable,
+  /// Free slots of workers that wait for work, one entry per slot.
+  worker_queue: VecDeque<WorkerID>,
+  job_queue: JobQueue,
+  /// Pulls whose object is not available yet.
+  pull_queue: VecDeque<(WorkerID, ObjRef)>,
+  /// Free resources of each worker.
+  available: Vec<ResourceMap>,
+  /// Resources held by running calls.
+  running: HashMap<ObjRef, (WorkerID, ResourceMap)>,
+  /// Number of lent slots each worker still has to get back.
+  owed: Vec<usize>,
+  actors: ActorQueues,
+  /// Workers that don't get new calls.
+  draining: Vec<bool>,
+  /// Drained workers that shut down once idle.
+  shutdown: Vec<bool>,
+  /// Results of the calls on the critical path.
+  critical: HashSet<ObjRef>
+}
+
+/// Resources needed by a job. These are the resources of the call if it declares any, otherwise
+/// the ones registered with the function and one CPU if the function didn't declare any.
+pub fn requirements(restable: &ResourceTable, job: &comm::Call) -> ResourceMap {
+  if job.has_resources() {
+    return resources_from_proto(job.get_resources());
+  }
+  return restable.get(job.get_name()).map_or_else(default_resources, |r| r.clone());
+}
+
+/// Check if all arguments of a job are available.
+fn can_run(job: &comm::Call, objtable: &ObjTable) -> bool {
+  for elem in job.get_args().get_objrefs() {
+    if *elem >= 0 {
+      if objtable.get(*elem as usize).map_or(true, |holders| holders.is_empty()) {
+        return false;
+      }
+    }
+  }
+  return true;
+}
+
+impl SchedulerState {
+  pub fn new() -> SchedulerState {
+    return SchedulerState {
+      objtable: Vec::new(),
+      fntable: HashMap::new(),
+      restable: HashMap::new(),
+      worker_queue: VecDeque::new(),
+      job_queue: JobQueue::new(),
+      pull_queue: VecDeque::new(),
+      available: Vec::new(),
+      running: HashMap::new(),
+      owed: Vec::new(),
+      actors: ActorQueues::new(),
+      draining: Vec::new(),
+      shutdown: Vec::new(),
+      critical: HashSet::new()
+    };
+  }
+
+  /// Number of jobs and method calls that wait for a worker.
+  pub fn queued_jobs(self: &SchedulerState) -> usize {
+    return self.job_queue.len() + self.actors.len();
+  }
+
+  /// Number of pulls that wait for their object.
+  pub fn queued_pulls(self: &SchedulerState) -> usize {
+    return self.pull_queue.len();
+  }
+
+  /// Describe the state of the scheduler.
+  pub fn info(self: &SchedulerState) -> comm::SchedulerInfo {
+    let mut scheduler_info = comm::SchedulerInfo::new();
+    scheduler_info.set_worker_queue(self.worker_queue.iter().map(|x| *x as u64).collect());
+    scheduler_info.set_job_queue(RepeatedField::from_vec(self.job_queue.to_vec()));
+    scheduler_info.set_actor_queue(RepeatedField::from_vec(self.actors.to_vec()));
+    let mut pulls = Vec::new();
+    for &(workerid, objref) in self.pull_queue.iter() {
+      let mut info = comm::PullInfo::new();
+      info.set_workerid(workerid as u64);
+      info.set_objref(objref);
+      pulls.push(info);
+    }
+    scheduler_info.set_pull_queue(RepeatedField::from_vec(pulls));
+    scheduler_info.set_running(self.running.keys().cloned().collect());
     let mut objs = Vec::new();
-    for (objref, workers) in objtable.iter().enumerate() {
+    for (objref, workers) in self.objtable.iter().enumerate() {
       let mut info = comm::ObjInfo::new();
       info.set_objref(objref as u64);
       let workers : &Vec<usize> = workers;
@@ -79,138 +472,755 @@ impl Scheduler {
     }
     scheduler_info.set_objtable(RepeatedField::from_vec(objs));
 
-    let fntable = self.fntable.read().unwrap();
     let mut fns = Vec::new();
-    for (fnname, workers) in fntable.iter() {
+    for (fnname, workers) in self.fntable.iter() {
       let mut info = comm::FnInfo::new();
       info.set_fnname(fnname.to_string());
       info.set_workerid((*workers).iter().map(|x| *x as u64).collect());
       fns.push(info);
     }
     scheduler_info.set_fntable(RepeatedField::from_vec(fns));
+    return scheduler_info;
+  }
 
-    let mut msg = comm::Message::new();
-    msg.set_field_type(comm::MessageType::DEBUG);
-    msg.set_scheduler_info(scheduler_info);
+  /// Drop the queued calls and pulls of a client whose session is closed and return the results
+  /// of the dropped calls.
+  pub fn close_session(self: &mut SchedulerState, session: WorkerID) -> Vec<ObjRef> {
+    let mut dropped = self.job_queue.remove_submitter(session);
+    dropped.extend(self.actors.remove_submitter(session));
+    self.pull_queue.retain(|&(workerid, _)| workerid != session);
+    info!("dropped {} calls of session {}", dropped.len(), session);
+    let mut results = Vec::new();
+    for job in dropped.iter() {
+      results.extend(job.get_result().iter().cloned());
+    }
+    return results;
+  }
 
-    socket.send(msg).unwrap();
+  /// Drop the queued calls that take one of the `failed` objects as an argument and return their
+  /// results, which fail as well.
+  pub fn drop_dependents(self: &mut SchedulerState, failed: &[ObjRef]) -> Vec<ObjRef> {
+    let depends = |job: &comm::Call| job.get_args().get_objrefs().iter().any(|elem| *elem >= 0 && failed.contains(&(*elem as ObjRef)));
+    let mut dropped = self.job_queue.remove_if(&depends);
+    dropped.extend(self.actors.remove_if(&depends));
+    let mut results = Vec::new();
+    for job in dropped.iter() {
+      results.extend(job.get_result().iter().cloned());
+    }
+    return results;
   }
 
-  /// Find job whose dependencies are met.
-  fn find_next_job(self: &Scheduler, workerid: WorkerID, job_queue: &VecDeque<comm::Call>) -> Option<usize> {
-    let objtable = &self.objtable.lock().unwrap();
-    for (i, job) in job_queue.iter().enumerate() {
-      if !self.fntable.read().unwrap().contains_key(job.get_name()) {
-          panic!("next job bailing");
-          return None;
-      }
-      if self.fntable.read().unwrap()[job.get_name()].binary_search(&workerid).is_ok() && self.can_run(job, objtable) {
+  /// Forget the actors of a drained worker, they can't evaluate calls anywhere else. Their queued
+  /// method calls are dropped. Return the ids of the actors and the results of the dropped calls.
+  pub fn remove_actors(self: &mut SchedulerState, workerid: WorkerID) -> (Vec<ActorID>, Vec<ObjRef>) {
+    let (actorids, dropped) = self.actors.remove_worker(workerid);
+    if actorids.len() > 0 {
+      info!("lost {} actors of worker {}, dropped {} method calls", actorids.len(), workerid, dropped.len());
+    }
+    let mut results = Vec::new();
+    for job in dropped.iter() {
+      results.extend(job.get_result().iter().cloned());
+    }
+    return (actorids, results);
+  }
+
+  /// Tell a worker to shut down if it is drained and all its calls are done.
+  fn shutdown_if_idle(self: &mut SchedulerState, workerid: WorkerID, actions: &mut Vec<Action>) {
+    if self.shutdown[workerid] && self.running.values().all(|&(w, _)| w != workerid) {
+      info!("shutting down worker {}", workerid);
+      actions.push(Action::Shutdown(workerid));
+      self.shutdown[workerid] = false; // only send the request once
+    }
+  }
+
+  /// Take the next job for a free slot of worker `workerid` out of the queues. Method calls of
+  /// actors that live on the worker come first, then the highest priority job whose dependencies
+  /// are met and that fits into the available resources of the worker.
+  fn next_job(self: &mut SchedulerState, workerid: WorkerID) -> Option<comm::Call> {
+    let (actorid, jobidx) = {
+      let objtable = &self.objtable;
+      let fntable = &self.fntable;
+      let restable = &self.restable;
+      let available = &self.available[workerid];
+      let critical = &self.critical;
+      let actorid = self.actors.find(workerid, |job| can_run(job, objtable) && fits(available, &requirements(restable, job)));
+      let jobidx = if actorid.is_some() { None } else {
+        self.job_queue.find_preferred(|job| {
+          fntable.get(job.get_name()).map_or(false, |workers| workers.binary_search(&workerid).is_ok()) && can_run(job, objtable) && fits(available, &requirements(restable, job))
+        }, |job| critical.contains(&job.get_result()[0]))
+      };
+      (actorid, jobidx)
+    };
+    match actorid {
+      Some(actorid) => return Some(self.actors.pop(actorid)),
+      None => {}
+    }
+    return jobidx.map(|jobidx| self.job_queue.remove(jobidx));
+  }
+
+  // TODO: replace fntable vector with bitfield
+  fn find_next_worker(self: &SchedulerState, job: &comm::Call) -> Option<usize> {
+    let required = requirements(&self.restable, job);
+    for (i, workerid) in self.worker_queue.iter().enumerate() {
+      if self.fntable.get(job.get_name()).map_or(false, |workers| workers.binary_search(workerid).is_ok()) && can_run(job, &self.objtable) && fits(&self.available[*workerid], &required) {
         return Some(i);
       }
     }
     return None;
   }
 
-  fn can_run(self: &Scheduler, job: &comm::Call, objtable: &MutexGuard<ObjTable>) -> bool {
-    for elem in job.get_args().get_objrefs() {
-      if *elem >= 0 {
-        if objtable[*elem as usize].len() == 0 {
-          return false;
+  /// Allocate the resources for `job` on worker `workerid` and send the job to the worker. If the job
+  /// creates an actor, the actor is pinned to the worker.
+  fn assign(self: &mut SchedulerState, workerid: WorkerID, job: comm::Call, actions: &mut Vec<Action>) {
+    let required = requirements(&self.restable, &job);
+    acquire(&mut self.available[workerid], &required);
+    for objref in job.get_result() {
+      self.running.insert(*objref, (workerid, required.clone()));
+    }
+    self.job_queue.charge(&job);
+    match job.get_field_type() {
+      comm::Call_Type::ACTOR_CREATE => {
+        self.actors.pin(job.get_actorid(), workerid);
+        self.actors.start(job.get_actorid(), job.get_result()[0]);
+      },
+      comm::Call_Type::ACTOR_CALL => {
+        self.actors.start(job.get_actorid(), job.get_result()[0]);
+      },
+      _ => {}
+    }
+    actions.push(Action::Invoke(workerid, job));
+  }
+
+  /// Try to hand out queued jobs to the workers that wait for work.
+  fn dispatch_queued(self: &mut SchedulerState, actions: &mut Vec<Action>) {
+    let mut workeridx = 0;
+    while workeridx < self.worker_queue.len() {
+      let workerid = *self.worker_queue.get(workeridx).unwrap();
+      match self.next_job(workerid) {
+        Some(job) => {
+          self.worker_queue.swap_remove_front(workeridx).unwrap();
+          self.assign(workerid, job, actions);
+        }
+        None => {
+            workeridx += 1;
         }
       }
     }
-    return true;
   }
 
-  // TODO: replace fntable vector with bitfield
-  fn find_next_worker(self: &Scheduler, job: &comm::Call, worker_queue: &VecDeque<usize>) -> Option<usize> {
-    let objtable = &self.objtable.lock().unwrap();
-    for (i, workerid) in worker_queue.iter().enumerate() {
-      if !self.fntable.read().unwrap().contains_key(job.get_name()) {
-          panic!("next worker bailing");
-          return None;
-      }
-      if self.fntable.read().unwrap()[job.get_name()].binary_search(workerid).is_ok() && self.can_run(job, objtable) {
-        return Some(i);
+  /// Process an event and return the resulting actions in the order they should be performed.
+  /// `Event::Debug` and `Event::Info` are answered by the caller using `info`, `Event::CloseSession`
+  /// using `close_session`, `Event::Drain` using `remove_actors` and `Event::Failed` using
+  /// `drop_dependents`.
+  pub fn handle(self: &mut SchedulerState, event: Event) -> Vec<Action> {
+    let mut actions = Vec::new();
+    // use the most simple algorithms for now
+    let event = match event {
+      Event::Done(workerid, objref) => {
+        self.actors.done(objref);
+        match self.running.remove(&objref) {
+          Some((_, required)) => release(&mut self.available[workerid], &required),
+          None => warn!("worker {} finished computing object {} which is not running", workerid, objref)
+        }
+        if self.owed[workerid] > 0 {
+          self.owed[workerid] -= 1; // the slot has been taken back by a call that was blocked
+          return actions;
+        }
+        Event::Worker(workerid) // the worker can take new work now
+      },
+      Event::Blocked(workerid, objref) => {
+        match self.running.get(&objref) {
+          Some(&(_, ref required)) => release(&mut self.available[workerid], required),
+          None => warn!("call computing object {} blocked on worker {} but is not running", objref, workerid)
+        }
+        Event::Worker(workerid) // lend the slot to another call
+      },
+      event => event
+    };
+    match event {
+      Event::Worker(workerid) if self.draining[workerid] => {
+        self.shutdown_if_idle(workerid, &mut actions);
+      },
+      Event::Worker(workerid) => {
+        match self.next_job(workerid) {
+          Some(job) => {
+            self.assign(workerid, job, &mut actions);
+          }
+          None => {
+            self.worker_queue.push_back(workerid);
+          }
+        }
+      },
+      Event::Done(_, _) | Event::Blocked(_, _) => {}, // turned into Event::Worker above
+      Event::Unblocked(workerid, objref) => {
+        match self.running.get(&objref) {
+          Some(&(_, ref required)) => {
+            if !fits(&self.available[workerid], required) {
+              // the call runs anyway; the resources stay negative and nothing else fits until enough calls are done
+              info!("worker {} is over its resources until the calls using the lent ones are done", workerid);
+            }
+            acquire(&mut self.available[workerid], required);
+          },
+          None => warn!("call computing object {} unblocked on worker {} but is not running", objref, workerid)
+        }
+        // take the lent slot back if nobody uses it, otherwise the next call that finishes returns it
+        match self.worker_queue.iter().position(|w| *w == workerid) {
+          Some(idx) => { self.worker_queue.remove(idx); },
+          None => { self.owed[workerid] += 1; }
+        }
+      },
+      Event::Job(ref job) if job.get_field_type() == comm::Call_Type::ACTOR_CALL => {
+        self.actors.push(job.clone());
+        self.dispatch_queued(&mut actions);
+      },
+      Event::Job(job) => {
+        match self.find_next_worker(&job) {
+          Some(workeridx) => {
+            let workerid = self.worker_queue.swap_remove_front(workeridx).unwrap();
+            self.assign(workerid, job, &mut actions);
+          }
+          None => {
+            self.job_queue.push(job);
+          }
+        }
+      },
+      Event::Obj(newobjref, workerid) => {
+        while self.objtable.len() <= newobjref as usize {
+          self.objtable.push(vec!());
+        }
+        let first = {
+          let holders = &mut self.objtable[newobjref as usize];
+          if !holders.contains(&workerid) {
+            holders.push(workerid);
+          }
+          holders.len() == 1
+        };
+        if !first {
+          return actions; // the object was available already
+        }
+        // TODO: do this with a binary search
+        for &(workerid, objref) in self.pull_queue.iter() {
+          if objref == newobjref {
+            actions.push(Action::Pull(workerid, objref));
+          }
+        }
+        self.pull_queue.retain(|&(_, objref)| objref != newobjref);
+        // see if we can evaluate one of the pending jobs now
+        self.dispatch_queued(&mut actions);
+      },
+      Event::Pull(workerid, objref) => {
+        if self.objtable.get(objref as usize).map_or(false, |holders| !holders.is_empty()) {
+          actions.push(Action::Pull(workerid, objref));
+        } else {
+          self.pull_queue.push_back((workerid, objref));
+        }
+      },
+      Event::Free(objref) => {
+        if let Some(holders) = self.objtable.get_mut(objref as usize) {
+          holders.clear();
+        }
+      },
+      Event::Lost(workerid) => {
+        for holders in self.objtable.iter_mut() {
+          holders.retain(|holder| *holder != workerid);
+        }
+      },
+      Event::Register(workerid, resources) => {
+        info!("worker {} has resources {:?}", workerid, resources);
+        while self.available.len() < workerid + 1 {
+          self.available.push(HashMap::new());
+          self.owed.push(0);
+          self.draining.push(false);
+          self.shutdown.push(false);
+        }
+        self.available[workerid] = resources;
+      },
+      Event::Function(workerid, fnname, resources) => {
+        {
+          let workers = self.fntable.entry(fnname.clone()).or_insert(vec!());
+          match workers.binary_search(&workerid) {
+            Ok(_) => {},
+            Err(idx) => workers.insert(idx, workerid)
+          }
+        }
+        if let Some(resources) = resources {
+          self.restable.insert(fnname, resources);
+        }
+        // queued calls of the function may be able to run now
+        self.dispatch_queued(&mut actions);
+      },
+      Event::Connect(_, _) | Event::Debug(_) | Event::Info(_) | Event::CloseSession(_, _) => {},
+      Event::Drain(workerid, stop, _) => {
+        info!("draining worker {}", workerid);
+        self.draining[workerid] = true;
+        self.shutdown[workerid] = stop;
+        self.worker_queue.retain(|w| *w != workerid);
+        self.shutdown_if_idle(workerid, &mut actions);
+      },
+      Event::Resume(workerid, num_slots) => {
+        info!("resuming worker {}", workerid);
+        self.draining[workerid] = false;
+        self.shutdown[workerid] = false;
+        // the slots of the running calls come back when they are done
+        let running = self.running.values().filter(|&&(w, _)| w == workerid).count();
+        for _ in running..num_slots {
+          self.worker_queue.push_back(workerid);
+        }
+        self.dispatch_queued(&mut actions);
+      },
+      Event::Failed(failed, _) => {
+        for &(workerid, objref) in self.pull_queue.iter() {
+          if failed.contains(&objref) {
+            actions.push(Action::Fail(workerid, objref));
+          }
+        }
+        self.pull_queue.retain(|&(_, objref)| !failed.contains(&objref));
+      },
+      Event::CriticalPath(objrefs) => {
+        self.critical = objrefs;
+      },
+      Event::Weight(submitter, weight) => {
+        self.job_queue.set_weight(submitter, weight);
       }
     }
-    return None;
+    return actions;
+  }
+}
+
+/// A scheduler assigns incoming jobs to workers. It communicates with the worker pool through
+/// channels. If a job is scheduled or a worker becomes available, this is signaled to the
+/// Scheduler using the channel returned by the `Scheduler::start` method. The scheduler signals the
+/// execution of a function call to the appropriate worker thread via a channel that is registered
+/// using `Event::Connect`. The decisions are made by a `SchedulerState`, the scheduler only
+/// passes events to it and delivers the resulting actions.
+pub struct Scheduler {
+  state: SchedulerState,
+  /// Channel to the thread of each worker.
+  workers: Vec<Sender<comm::Message>>,
+  metrics: Arc<Metrics>,
+  trace: Arc<Mutex<Trace>>,
+}
+
+impl Scheduler {
+  /// Start the scheduling thread.
+  pub fn start(metrics: Arc<Metrics>, trace: Arc<Mutex<Trace>>) -> Sender<Event> {
+    let (event_sender, event_receiver) = mpsc::channel(); // notify the scheduler that a worker, job or object becomes available
+    let scheduler = Scheduler { state: SchedulerState::new(), workers: Vec::new(), metrics: metrics, trace: trace };
+    scheduler.start_dispatch_thread(event_receiver);
+    return event_sender
+  }
+
+  fn send_function_call(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID, job: comm::Call) {
+    info!("scheduling function call {} on worker {}", job.get_name(), workerid);
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::INVOKE);
+    msg.set_call(job);
+    workers[workerid].send(msg).unwrap();
+  }
+
+  fn send_pull_request(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID, objref: ObjRef) {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::PULL);
+    msg.set_workerid(workerid as u64);
+    msg.set_objref(objref);
+    workers[workerid].send(msg).unwrap();
+  }
+
+  fn send_shutdown(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID) {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::SHUTDOWN);
+    msg.set_workerid(workerid as u64);
+    workers[workerid].send(msg).unwrap();
+  }
+
+  fn send_failed(workers: &Vec<Sender<comm::Message>>, workerid: WorkerID, objref: ObjRef) {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::FAILED);
+    msg.set_workerid(workerid as u64);
+    msg.set_objref(objref);
+    workers[workerid].send(msg).unwrap();
+  }
+
+  fn send_debugging_info(self: &Scheduler, socket: &Sender<comm::Message>, scheduler_info: comm::SchedulerInfo) {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::DEBUG);
+    msg.set_scheduler_info(scheduler_info);
+
+    socket.send(msg).unwrap();
+  }
+
+  /// Deliver an action of the scheduling state machine to the worker thread.
+  fn perform(self: &Scheduler, action: Action) {
+    match action {
+      Action::Invoke(workerid, job) => {
+        self.trace.lock().unwrap().instant("schedule", "server", now_us(), worker_pid(workerid), vec![("function", job.get_name().into()), ("objref", job.get_result()[0].to_string())]);
+        Scheduler::send_function_call(&self.workers, workerid, job);
+      },
+      Action::Pull(workerid, objref) => Scheduler::send_pull_request(&self.workers, workerid, objref),
+      Action::Shutdown(workerid) => Scheduler::send_shutdown(&self.workers, workerid),
+      Action::Fail(workerid, objref) => Scheduler::send_failed(&self.workers, workerid, objref)
+    }
   }
 
   // will be notified of workers or jobs that become available throught the worker_notify or job_notify channel
   fn start_dispatch_thread(self: Scheduler, event_notify: Receiver<Event>) {
+    let mut scheduler = self;
     thread::spawn(move || {
-      let mut workers = Vec::<Sender<comm::Message>>::new();
-      let mut worker_queue = VecDeque::<WorkerID>::new();
-      let mut job_queue = VecDeque::<comm::Call>::new();
-      let mut pull_queue = VecDeque::<(WorkerID, ObjRef)>::new();
-
       loop {
-        // use the most simple algorithms for now
-        match event_notify.recv().unwrap() {
-          Event::Worker(workerid) => {
-            match self.find_next_job(workerid, &job_queue) {
-              Some(jobidx) => {
-                let job = job_queue.swap_remove_front(jobidx).unwrap();
-                Scheduler::send_function_call(&mut workers, workerid, job);
-              }
-              None => {
-                worker_queue.push_back(workerid);
-              }
+        let event = event_notify.recv().unwrap();
+        match event {
+          Event::Connect(workerid, ref incoming) => {
+            while scheduler.workers.len() < workerid + 1 {
+              scheduler.workers.push(incoming.clone());
             }
+            scheduler.workers[workerid] = incoming.clone();
           },
-          Event::Job(job) => {
-            match self.find_next_worker(&job, &worker_queue) {
-              Some(workeridx) => {
-                let workerid = worker_queue.swap_remove_front(workeridx).unwrap();
-                Scheduler::send_function_call(&mut workers, workerid, job);
-              }
-              None => {
-                job_queue.push_back(job);
-              }
-            }
+          Event::Debug(workerid) => {
+            let info = scheduler.state.info();
+            scheduler.send_debugging_info(&scheduler.workers[workerid], info);
           },
-          Event::Obj(newobjref) => {
-            // TODO: do this with a binary search
-            for &(workerid, objref) in pull_queue.iter() {
-              if objref == newobjref {
-                Scheduler::send_pull_request(&mut workers, workerid, objref);
-              }
-            }
-            // see if we can evaluate one of the pending jobs now
-            let mut workeridx = 0;
-            while workeridx < worker_queue.len() {
-              let workerid = *worker_queue.get(workeridx).unwrap();
-              match self.find_next_job(workerid, &job_queue) {
-                Some(jobidx) => {
-                  let job = job_queue.swap_remove_front(jobidx).unwrap();
-                  worker_queue.swap_remove_front(workeridx).unwrap();
-                  Scheduler::send_function_call(&mut workers, workerid, job);
-                }
-                None => {
-                    workeridx += 1;
-                }
-              }
-            }
+          Event::Info(ref sender) => {
+            sender.send(scheduler.state.info()).unwrap();
           },
-          Event::Pull(workerid, objref) => {
-            if self.objtable.lock().unwrap()[objref as usize].len() > 0 {
-              Scheduler::send_pull_request(&mut workers, workerid, objref);
-            } else {
-              pull_queue.push_back((workerid, objref));
-            }
+          Event::CloseSession(session, ref sender) => {
+            sender.send(scheduler.state.close_session(session)).unwrap();
           },
-          Event::Register(workerid, incoming) => {
-            while workers.len() < workerid + 1 {
-              workers.push(incoming.clone());
-            }
-            workers[workerid] = incoming;
+          Event::Drain(workerid, _, ref sender) => {
+            sender.send(scheduler.state.remove_actors(workerid)).unwrap();
           },
-          Event::Debug(workerid) => {
-            self.send_debugging_info(&workers[workerid], &worker_queue, &job_queue);
-          }
+          Event::Failed(ref failed, ref sender) => {
+            sender.send(scheduler.state.drop_dependents(failed)).unwrap();
+          },
+          _ => {}
+        }
+        for action in scheduler.state.handle(event) {
+          scheduler.perform(action);
         }
+        scheduler.metrics.job_queue.store(scheduler.state.queued_jobs(), Ordering::Relaxed);
+        scheduler.metrics.pull_queue.store(scheduler.state.queued_pulls(), Ordering::Relaxed);
       }
     });
   }
 }
+
+#[test]
+fn test_find_preferred() {
+  let mut job_queue = JobQueue::new();
+  for (objref, priority) in vec![(0, 0), (1, 0), (2, 1), (3, 0)] {
+    let mut job = comm::Call::new();
+    job.set_result(vec![objref]);
+    job.set_priority(priority);
+    job_queue.push(job);
+  }
+  // a higher priority wins over the preference, within a priority the preferred job comes first
+  assert_eq!(job_queue.find_preferred(|_| true, |job| job.get_result()[0] == 3), Some((1, 0)));
+  assert_eq!(job_queue.find_preferred(|job| job.get_priority() == 0, |job| job.get_result()[0] == 3), Some((0, 2)));
+  assert_eq!(job_queue.find(|job| job.get_priority() == 0), Some((0, 0)));
+}
+
+#[cfg(test)]
+fn test_scheduler(num_workers: usize) -> SchedulerState {
+  let mut scheduler = SchedulerState::new();
+  for workerid in 0..num_workers {
+    scheduler.handle(Event::Register(workerid, default_resources()));
+    scheduler.handle(Event::Function(workerid, "f".into(), None));
+  }
+  return scheduler;
+}
+
+#[cfg(test)]
+fn test_job(args: Vec<i64>, result: ObjRef, priority: i32) -> comm::Call {
+  let mut job = comm::Call::new();
+  job.set_name("f".into());
+  let mut a = comm::Args::new();
+  a.set_objrefs(args);
+  job.set_args(a);
+  job.set_result(vec![result]);
+  job.set_priority(priority);
+  return job;
+}
+
+/// The (worker, result) pairs of the calls the actions invoke.
+#[cfg(test)]
+fn invoked(actions: &Vec<Action>) -> Vec<(WorkerID, ObjRef)> {
+  let mut result = Vec::new();
+  for action in actions.iter() {
+    if let Action::Invoke(workerid, ref job) = *action {
+      result.push((workerid, job.get_result()[0]));
+    }
+  }
+  return result;
+}
+
+/// The (worker, object) pairs of the pulls the actions answer.
+#[cfg(test)]
+fn pulled(actions: &Vec<Action>) -> Vec<(WorkerID, ObjRef)> {
+  let mut result = Vec::new();
+  for action in actions.iter() {
+    if let Action::Pull(workerid, objref) = *action {
+      result.push((workerid, objref));
+    }
+  }
+  return result;
+}
+
+#[test]
+fn test_scheduling_order() {
+  let mut scheduler = test_scheduler(1);
+  for &(result, priority) in [(0, 0), (1, 0), (2, 1)].iter() {
+    assert!(invoked(&scheduler.handle(Event::Job(test_job(vec![], result, priority)))).is_empty());
+  }
+  assert_eq!(scheduler.queued_jobs(), 3);
+  // the highest priority comes first, then the jobs of the same priority in submission order
+  assert_eq!(invoked(&scheduler.handle(Event::Worker(0))), vec![(0, 2)]);
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 2))), vec![(0, 0)]);
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 0))), vec![(0, 1)]);
+  assert!(invoked(&scheduler.handle(Event::Done(0, 1))).is_empty());
+  assert_eq!(scheduler.queued_jobs(), 0);
+  // the worker waits for work, a new job is assigned right away
+  assert_eq!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 3, 0)))), vec![(0, 3)]);
+}
+
+#[test]
+fn test_pull_queue() {
+  let mut scheduler = test_scheduler(2);
+  assert!(scheduler.handle(Event::Pull(1, 0)).is_empty());
+  assert_eq!(scheduler.queued_pulls(), 1);
+  // an unrelated object doesn't answer the pull
+  assert!(scheduler.handle(Event::Obj(1, 0)).is_empty());
+  assert_eq!(pulled(&scheduler.handle(Event::Obj(0, 0))), vec![(1, 0)]);
+  // another copy doesn't answer it again
+  assert!(scheduler.handle(Event::Obj(0, 1)).is_empty());
+  assert_eq!(scheduler.queued_pulls(), 0);
+  // the object is available, later pulls are answered right away
+  assert_eq!(pulled(&scheduler.handle(Event::Pull(0, 0))), vec![(0, 0)]);
+}
+
+#[test]
+fn test_dependency_waiting() {
+  let mut scheduler = test_scheduler(2);
+  assert!(scheduler.handle(Event::Worker(0)).is_empty());
+  assert!(scheduler.handle(Event::Worker(1)).is_empty());
+  // the job needs objects 0 and 1, negative objrefs refer to data in the call
+  assert!(scheduler.handle(Event::Job(test_job(vec![0, -1, 1], 2, 0))).is_empty());
+  assert!(scheduler.handle(Event::Obj(0, 1)).is_empty());
+  assert_eq!(scheduler.queued_jobs(), 1);
+  assert_eq!(invoked(&scheduler.handle(Event::Obj(1, 1))), vec![(0, 2)]);
+  assert_eq!(scheduler.queued_jobs(), 0);
+  // a freed object or one whose only holder is gone is not available anymore
+  scheduler.handle(Event::Free(0));
+  scheduler.handle(Event::Lost(1));
+  assert!(scheduler.handle(Event::Job(test_job(vec![0], 3, 0))).is_empty());
+  assert!(scheduler.handle(Event::Job(test_job(vec![1], 4, 0))).is_empty());
+  assert_eq!(scheduler.queued_jobs(), 2);
+}
+
+#[test]
+fn test_register_function() {
+  let mut scheduler = test_scheduler(2);
+  scheduler.handle(Event::Worker(0));
+  scheduler.handle(Event::Worker(1));
+  let mut job = test_job(vec![], 0, 0);
+  job.set_name("g".into());
+  assert!(scheduler.handle(Event::Job(job)).is_empty());
+  // the call runs on the first worker that registers the function, with the declared resources
+  let mut resources = HashMap::new();
+  resources.insert("CPU".into(), 0.5);
+  assert_eq!(invoked(&scheduler.handle(Event::Function(1, "g".into(), Some(resources)))), vec![(1, 0)]);
+  assert_eq!(scheduler.available[1]["CPU"], 0.5);
+  assert_eq!(scheduler.info().get_fntable().len(), 2);
+}
+
+#[test]
+fn test_failed_dependency() {
+  let mut scheduler = test_scheduler(1);
+  scheduler.handle(Event::Job(test_job(vec![0], 1, 0)));
+  scheduler.handle(Event::Job(test_job(vec![-1], 2, 0)));
+  scheduler.handle(Event::Pull(0, 0));
+  // object 0 fails: the call that takes it is dropped and the pull fails
+  assert_eq!(scheduler.drop_dependents(&[0]), vec![1]);
+  let (sender, _) = mpsc::channel();
+  let actions = scheduler.handle(Event::Failed(vec![0], sender));
+  assert_eq!(actions.len(), 1);
+  match actions[0] {
+    Action::Fail(workerid, objref) => assert_eq!((workerid, objref), (0, 0)),
+    _ => panic!("the pull of a failed object must fail")
+  }
+  assert_eq!(scheduler.queued_jobs(), 1);
+  assert_eq!(scheduler.queued_pulls(), 0);
+}
+
+#[test]
+fn test_blocked_call() {
+  let mut scheduler = test_scheduler(1);
+  scheduler.handle(Event::Worker(0));
+  assert_eq!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 0, 0)))), vec![(0, 0)]);
+  assert!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 1, 0)))).is_empty());
+  // the call computing 0 blocks on a pull, its slot and its CPU are lent to the next call
+  assert_eq!(invoked(&scheduler.handle(Event::Blocked(0, 0))), vec![(0, 1)]);
+  scheduler.handle(Event::Job(test_job(vec![], 2, 0)));
+  // it continues while the other call runs, so the worker uses one CPU too many and owes a slot
+  assert!(scheduler.handle(Event::Unblocked(0, 0)).is_empty());
+  assert_eq!(scheduler.available[0]["CPU"], -1.0);
+  assert_eq!(scheduler.owed[0], 1);
+  // the first call that is done repays the slot, the second one frees it
+  assert!(invoked(&scheduler.handle(Event::Done(0, 1))).is_empty());
+  assert_eq!(scheduler.owed[0], 0);
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 0))), vec![(0, 2)]);
+  // a lent slot that nobody used is taken back right away
+  assert!(invoked(&scheduler.handle(Event::Blocked(0, 2))).is_empty());
+  assert!(scheduler.handle(Event::Unblocked(0, 2)).is_empty());
+  assert_eq!(scheduler.owed[0], 0);
+  assert!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 3, 0)))).is_empty());
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 2))), vec![(0, 3)]);
+}
+
+#[test]
+fn test_drain_actor() {
+  let mut scheduler = test_scheduler(2);
+  scheduler.handle(Event::Worker(0));
+  let mut create = test_job(vec![], 0, 0);
+  create.set_field_type(comm::Call_Type::ACTOR_CREATE);
+  create.set_actorid(7);
+  assert_eq!(invoked(&scheduler.handle(Event::Job(create))), vec![(0, 0)]);
+  // the actor is busy with its constructor, so its method calls wait
+  for result in 1..3 {
+    let mut call = test_job(vec![], result, 0);
+    call.set_field_type(comm::Call_Type::ACTOR_CALL);
+    call.set_actorid(7);
+    assert!(invoked(&scheduler.handle(Event::Job(call))).is_empty());
+  }
+  assert_eq!(scheduler.queued_jobs(), 2);
+  assert_eq!(scheduler.remove_actors(1), (vec![], vec![]));
+  // the worker of the actor is drained, its method calls can't be evaluated anywhere else
+  assert_eq!(scheduler.remove_actors(0), (vec![7], vec![1, 2]));
+  assert_eq!(scheduler.queued_jobs(), 0);
+  let (sender, _) = mpsc::channel();
+  scheduler.handle(Event::Drain(0, false, sender));
+  assert!(invoked(&scheduler.handle(Event::Done(0, 0))).is_empty());
+}
+
+#[test]
+fn test_actor_calls() {
+  let mut scheduler = test_scheduler(2);
+  scheduler.handle(Event::Worker(0));
+  scheduler.handle(Event::Worker(1));
+  let mut create = test_job(vec![], 0, 0);
+  create.set_field_type(comm::Call_Type::ACTOR_CREATE);
+  create.set_actorid(3);
+  assert_eq!(invoked(&scheduler.handle(Event::Job(create))), vec![(0, 0)]);
+  for result in 1..3 {
+    let mut call = test_job(vec![], result, 0);
+    call.set_field_type(comm::Call_Type::ACTOR_CALL);
+    call.set_actorid(3);
+    // worker 1 is free, but the method calls wait for the actor on worker 0
+    assert!(invoked(&scheduler.handle(Event::Job(call))).is_empty());
+  }
+  // the actor evaluates one method call at a time, in submission order
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 0))), vec![(0, 1)]);
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 1))), vec![(0, 2)]);
+  assert_eq!(scheduler.queued_jobs(), 0);
+  assert!(scheduler.actors.queues.is_empty());
+  // the queue of an actor is also dropped when the session that filled it closes
+  let mut call = test_job(vec![], 3, 0);
+  call.set_field_type(comm::Call_Type::ACTOR_CALL);
+  call.set_actorid(4);
+  call.set_submitter(5);
+  scheduler.actors.push(call);
+  assert_eq!(scheduler.close_session(5), vec![3]);
+  assert!(scheduler.actors.queues.is_empty());
+}
+
+#[test]
+fn test_drain_shutdown() {
+  let mut scheduler = test_scheduler(2);
+  scheduler.handle(Event::Worker(0));
+  assert_eq!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 0, 0)))), vec![(0, 0)]);
+  // the worker is still busy, so it is only shut down once its call is done
+  let (sender, _) = mpsc::channel();
+  assert!(scheduler.handle(Event::Drain(0, true, sender)).is_empty());
+  assert!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 1, 0)))).is_empty());
+  let actions = scheduler.handle(Event::Done(0, 0));
+  assert_eq!(actions.len(), 1);
+  match actions[0] {
+    Action::Shutdown(workerid) => assert_eq!(workerid, 0),
+    _ => panic!("a drained worker must be shut down once idle")
+  }
+  // the queued call goes to the other worker, which isn't shut down when it is only drained
+  assert_eq!(invoked(&scheduler.handle(Event::Worker(1))), vec![(1, 1)]);
+  let (sender, _) = mpsc::channel();
+  assert!(scheduler.handle(Event::Drain(1, false, sender)).is_empty());
+  assert!(scheduler.handle(Event::Done(1, 1)).is_empty());
+  assert!(invoked(&scheduler.handle(Event::Job(test_job(vec![], 2, 0)))).is_empty());
+  // a resumed worker gets calls again
+  assert_eq!(invoked(&scheduler.handle(Event::Resume(1, 1))), vec![(1, 2)]);
+}
+
+#[test]
+fn test_fair_share() {
+  let mut scheduler = test_scheduler(1);
+  scheduler.handle(Event::Weight(0, 2.0));
+  for &(submitter, result) in [(0, 0), (0, 1), (0, 2), (0, 3), (1, 10), (1, 11)].iter() {
+    let mut job = test_job(vec![], result, 0);
+    job.set_submitter(submitter);
+    scheduler.handle(Event::Job(job));
+  }
+  // client 0 has twice the weight of client 1, so it gets two calls for each call of client 1
+  let mut order = invoked(&scheduler.handle(Event::Worker(0)));
+  for &result in [0, 10, 1, 2, 11].iter() {
+    order.extend(invoked(&scheduler.handle(Event::Done(0, result))));
+  }
+  assert_eq!(order, vec![(0, 0), (0, 10), (0, 1), (0, 2), (0, 11), (0, 3)]);
+}
+
+#[test]
+fn test_fair_share_priority() {
+  let mut scheduler = test_scheduler(1);
+  for &(submitter, result, priority) in [(0, 0, 0), (0, 1, 0), (1, 10, 5), (1, 11, 5), (1, 12, 6)].iter() {
+    let mut job = test_job(vec![], result, priority);
+    job.set_submitter(submitter);
+    scheduler.handle(Event::Job(job));
+  }
+  // client 1 raised the priority of its calls, that only orders them but doesn't starve client 0
+  let mut order = invoked(&scheduler.handle(Event::Worker(0)));
+  for &result in [12, 0, 10].iter() {
+    order.extend(invoked(&scheduler.handle(Event::Done(0, result))));
+  }
+  assert_eq!(order, vec![(0, 12), (0, 0), (0, 10), (0, 1)]);
+}
+
+#[test]
+fn test_fair_share_late_submitter() {
+  let mut scheduler = test_scheduler(1);
+  for result in 0..6 {
+    scheduler.handle(Event::Job(test_job(vec![], result, 0)));
+  }
+  let mut order = invoked(&scheduler.handle(Event::Worker(0)));
+  order.extend(invoked(&scheduler.handle(Event::Done(0, 0))));
+  // client 1 starts with the charge of client 0 instead of getting the worker until it caught up
+  for result in 10..12 {
+    let mut job = test_job(vec![], result, 0);
+    job.set_submitter(1);
+    scheduler.handle(Event::Job(job));
+  }
+  for &result in [1, 2, 10, 3].iter() {
+    order.extend(invoked(&scheduler.handle(Event::Done(0, result))));
+  }
+  assert_eq!(order, vec![(0, 0), (0, 1), (0, 2), (0, 10), (0, 3), (0, 11)]);
+}
+
+#[test]
+fn test_close_session() {
+  let mut scheduler = test_scheduler(1);
+  for &(submitter, result) in [(0, 1), (1, 2), (0, 3), (1, 4)].iter() {
+    let mut job = test_job(vec![], result, 0);
+    job.set_submitter(submitter);
+    scheduler.handle(Event::Job(job));
+  }
+  scheduler.handle(Event::Pull(1, 0));
+  assert_eq!(scheduler.close_session(1), vec![2, 4]);
+  assert_eq!(scheduler.queued_jobs(), 2);
+  assert_eq!(scheduler.queued_pulls(), 0);
+  assert_eq!(invoked(&scheduler.handle(Event::Worker(0))), vec![(0, 1)]);
+  assert_eq!(invoked(&scheduler.handle(Event::Done(0, 1))), vec![(0, 3)]);
+}
@@ -2,36 +2,63 @@ use comm;
 use graph;
 use scheduler;
 use scheduler::{Scheduler, Event};
-use utils::{send_message, receive_message, receive_ack, send_ack, bind_socket, push_objrefs};
-use utils::{WorkerID, ObjRef, ObjTable, FnTable};
+use utils::{send_message, receive_message, receive_ack, send_ack, push_objrefs};
+use utils::{WorkerID, ObjRef, ActorID, ObjTable, FnTable, ResourceMap, ResourceTable, SignatureTable};
+use utils::{default_resources, resources_from_proto, resources_to_proto, fits};
 use graph::CompGraph;
+use metrics::Metrics;
+use transport::{MessageSource, MessageTransport, Network};
+#[cfg(test)]
+use transport::{ChannelTransport, channel_pair};
+use trace::{Trace, now_us, worker_pid, SERVER_PID};
 use rand;
 use rand::distributions::{IndependentSample, Range};
 use std::io::{Read, Write};
 use std::collections::VecDeque;
-use zmq;
-use zmq::Socket;
 use std::process;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
 use std::thread;
 use std::sync::{Arc, RwLock, Mutex, MutexGuard, RwLockReadGuard};
+use std::sync::atomic::Ordering;
 use std::str::FromStr;
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::mem;
 use protobuf::{Message, RepeatedField};
 use std::iter::Iterator;
 
+/// Identifies a call of a pure function: its name, its arguments and the data of the arguments
+/// that are passed by value.
+type MemoKey = (String, Vec<i64>, Vec<Vec<u8>>);
+
+/// Maximum number of memoized calls, the oldest ones are forgotten first.
+const MEMO_CAPACITY: usize = 65536;
+
+/// Microseconds between two heartbeats of a client.
+pub const HEARTBEAT_INTERVAL: u64 = 1000000;
+
+/// Number of heartbeats a client can miss before it is considered gone.
+pub const MISSED_HEARTBEATS: u64 = 10;
+
+/// Microseconds without a heartbeat after which a client is considered gone.
+pub const HEARTBEAT_TIMEOUT: u64 = MISSED_HEARTBEATS * HEARTBEAT_INTERVAL;
+
 /// Contains informations about worker.
 pub struct Worker {
-  addr: String
+  addr: String,
+  num_slots: usize,
+  resources: ResourceMap,
+  draining: bool,
+  driver: bool // drivers submit calls and hold objects, but never evaluate calls
 }
 
 /// A group of workers that are managed and scheduled together. They are connected with the server
 /// using a zero mq `PUB` channel used for one-way communication from server to client.
 /// Furthermore, each client is connected to each other client using a REP/REQ socket pair; all
 /// data is transferred using these client side connections. It is the `WorkerPool`s task to
-/// establish the connections.
+/// establish the connections. Drivers are left out of this mesh, clients connect to them when
+/// they first deliver an object to them.
 pub struct WorkerPool {
   /// Workers that have been registered with this pool.
   workers: Arc<RwLock<Vec<Worker>>>,
@@ -39,32 +66,28 @@ pub struct WorkerPool {
   scheduler_notify: Sender<Event>,
   /// Send delivery requests to clients.
   publish_notify: Sender<(WorkerID, comm::Message)>,
+  /// Metrics of the server.
+  metrics: Arc<Metrics>,
 }
 
 impl WorkerPool {
   /// Create a new `WorkerPool`.
-  pub fn new(objtable: Arc<Mutex<ObjTable>>, fntable: Arc<RwLock<FnTable>>, publish_port: u16) -> WorkerPool {
+  pub fn new(metrics: Arc<Metrics>, trace: Arc<Mutex<Trace>>, network: &Network, publish_port: u16) -> WorkerPool {
     let (publish_sender, publish_receiver) = mpsc::channel();
-    let scheduler_notify = Scheduler::start(objtable, fntable);
-    WorkerPool::start_publisher_thread(publish_receiver, publish_port);
-    return WorkerPool { workers: Arc::new(RwLock::new(Vec::new())), publish_notify: publish_sender, scheduler_notify: scheduler_notify }
+    let scheduler_notify = Scheduler::start(metrics.clone(), trace);
+    WorkerPool::start_publisher_thread(publish_receiver, network, publish_port);
+    return WorkerPool { workers: Arc::new(RwLock::new(Vec::new())), publish_notify: publish_sender, scheduler_notify: scheduler_notify, metrics: metrics }
   }
 
   /// Start the thread that is used to feed the PUB/SUB network between the server and the workers.
-  pub fn start_publisher_thread(publish_notify: Receiver<(WorkerID, comm::Message)>, publish_port: u16) {
+  pub fn start_publisher_thread(publish_notify: Receiver<(WorkerID, comm::Message)>, network: &Network, publish_port: u16) {
+    // bind before returning, clients may connect as soon as the server answers requests
+    let localhost = IpAddr::from_str("0.0.0.0").unwrap();
+    let mut publisher = network.bind_publisher(&localhost, publish_port);
     thread::spawn(move || {
-      let mut zmq_ctx = zmq::Context::new();
-      let mut publisher = zmq_ctx.socket(zmq::PUB).unwrap();
-      let localhost = IpAddr::from_str("0.0.0.0").unwrap();
-      bind_socket(&mut publisher, &localhost, Some(publish_port));
       loop {
         match publish_notify.recv().unwrap() {
-          (workerid, msg) => {
-            let mut buf = Vec::new();
-            write!(buf, "{:0>#07}", workerid).unwrap();
-            msg.write_to_writer(&mut buf).unwrap();
-            publisher.send(buf.as_slice(), 0).unwrap();
-          }
+          (workerid, msg) => publisher.publish(workerid, &msg)
         }
       }
     });
@@ -80,25 +103,35 @@ impl WorkerPool {
     return self.workers.read().unwrap().len();
   }
 
-  /// Connect a new worker to the workers already present in the pool.
-  fn connect(self: &mut WorkerPool, zmq_ctx: &mut zmq::Context, addr: &str, workerid: WorkerID, setup_socket: &mut Socket) -> Socket {
+  /// Test if the client `workerid` registered as a driver.
+  pub fn is_driver(self: &WorkerPool, workerid: WorkerID) -> bool {
+    return self.workers.read().unwrap().get(workerid).map_or(false, |worker| worker.driver);
+  }
+
+  /// Connect a new worker to the workers already present in the pool. A new driver only does
+  /// the handshake with the server.
+  fn connect(self: &mut WorkerPool, network: &Network, addr: &str, workerid: WorkerID, driver: bool, setup_socket: &mut Box<MessageTransport>) -> Box<MessageTransport> {
     info!("connecting worker {}", workerid);
-    let mut socket = zmq_ctx.socket(zmq::REQ).unwrap();
-    socket.connect(addr).unwrap();
-    let mut buf = zmq::Message::new().unwrap();
+    let socket = network.connect_request(addr);
     loop {
       let mut hello = comm::Message::new();
       hello.set_field_type(comm::MessageType::HELLO);
       self.publish_notify.send((workerid, hello)).unwrap();
       thread::sleep_ms(10); // don't float the message queue
-      match setup_socket.recv(&mut buf, zmq::DONTWAIT) {
-        Ok(_) => break,
-        Err(_) => continue
+      match setup_socket.try_receive_message() {
+        Some(_) => break, // the client received the hello, so it gets the messages published for it
+        None => continue
       }
     }
+    if driver {
+      return socket;
+    }
     // connect new client with other clients that are already connected
     // and connect already connected clients with the new client
     for i in 0..self.len() {
+      if self.workers.read().unwrap()[i].driver {
+        continue;
+      }
       let mut message = comm::Message::new();
       message.set_field_type(comm::MessageType::REGISTER_CLIENT);
       let other_party =  &self.workers.read().unwrap()[i].addr;
@@ -113,6 +146,60 @@ impl WorkerPool {
     return socket;
   }
 
+  /// Describe the registered workers.
+  pub fn worker_info(self: &WorkerPool) -> Vec<comm::WorkerInfo> {
+    let mut result = Vec::new();
+    for (workerid, worker) in self.workers.read().unwrap().iter().enumerate() {
+      let mut info = comm::WorkerInfo::new();
+      info.set_workerid(workerid as u64);
+      info.set_address(worker.addr.clone());
+      info.set_num_slots(worker.num_slots as u64);
+      info.set_resources(resources_to_proto(&worker.resources));
+      info.set_draining(worker.draining);
+      info.set_driver(worker.driver);
+      result.push(info);
+    }
+    return result;
+  }
+
+  /// Test if one of the workers `workerids` still gets calls and has enough resources in total for
+  /// a call that needs `required`. Drained workers and workers that closed their session don't count.
+  pub fn fits_any(self: &WorkerPool, workerids: &[WorkerID], required: &ResourceMap) -> bool {
+    let workers = self.workers.read().unwrap();
+    return workerids.iter().any(|workerid| workers.get(*workerid).map_or(false, |worker| !worker.draining && fits(&worker.resources, required)));
+  }
+
+  /// Stop assigning calls to a worker and shut it down after its calls are done if `shutdown` is set.
+  /// Return the actors that lived on the worker and the results of their dropped method calls.
+  pub fn drain(self: &mut WorkerPool, workerid: WorkerID, shutdown: bool) -> Result<(Vec<ActorID>, Vec<ObjRef>), String> {
+    match self.workers.write().unwrap().get_mut(workerid) {
+      Some(ref worker) if worker.driver => return Err(format!("client {} is a driver, not a worker", workerid)),
+      Some(worker) => worker.draining = true,
+      None => return Err(format!("worker {} does not exist", workerid))
+    }
+    let (sender, receiver) = mpsc::channel();
+    self.scheduler_notify.send(scheduler::Event::Drain(workerid, shutdown, sender)).unwrap();
+    return Ok(receiver.recv().unwrap());
+  }
+
+  /// Let a drained worker get calls again, see `drain`. Its lost actors stay lost.
+  pub fn resume(self: &mut WorkerPool, workerid: WorkerID) {
+    let num_slots = {
+      let mut workers = self.workers.write().unwrap();
+      workers[workerid].draining = false;
+      workers[workerid].num_slots
+    };
+    self.scheduler_notify.send(scheduler::Event::Resume(workerid, num_slots)).unwrap();
+  }
+
+  /// Tell a worker to delete its copy of an object.
+  pub fn free(self: &WorkerPool, workerid: WorkerID, objref: ObjRef) {
+    let mut free = comm::Message::new();
+    free.set_field_type(comm::MessageType::FREE);
+    free.set_objref(objref);
+    self.publish_notify.send((workerid, free)).unwrap();
+  }
+
   /// Tell a client `pullid` to deliver an object to another client with address `addr`.
   pub fn send_deliver_request(pullid: WorkerID, addr: &str, objref: ObjRef, publish_notify: &Sender<(WorkerID, comm::Message)>) {
     let mut deliver = comm::Message::new();
@@ -123,7 +210,7 @@ impl WorkerPool {
   }
 
   /// Deliver the object with id `objref` to the worker with id `workerid`.
-  pub fn deliver_object(workerid: WorkerID, objref: ObjRef, workers: &Arc<RwLock<Vec<Worker>>>, objtable: &Arc<Mutex<ObjTable>>, publish_notify: &Sender<(WorkerID, comm::Message)>) {
+  pub fn deliver_object(workerid: WorkerID, objref: ObjRef, workers: &Arc<RwLock<Vec<Worker>>>, objtable: &Arc<Mutex<ObjTable>>, publish_notify: &Sender<(WorkerID, comm::Message)>, metrics: &Metrics) {
     if !objtable.lock().unwrap()[objref as usize].contains(&workerid) {
       // pick random worker
       let mut rng = rand::thread_rng(); // supposed to have no performance penalty
@@ -132,21 +219,44 @@ impl WorkerPool {
       let pullid = objtable.lock().unwrap()[objref as usize][idx];
       info!("delivering object {} from {} to {}, addr {}", objref, pullid, workerid, &workers.read().unwrap()[workerid].addr);
       WorkerPool::send_deliver_request(pullid, &workers.read().unwrap()[workerid].addr, objref, &publish_notify);
+      metrics.deliveries.fetch_add(1, Ordering::Relaxed);
     }
   }
 
-  /// Register a new worker with the worker pool.
-  pub fn register(self: &mut WorkerPool, zmq_ctx: &mut zmq::Context, addr: &str, objtable: Arc<Mutex<ObjTable>>, setup_socket: &mut Socket) -> WorkerID {
-    info!("registering new worker");
+  /// Add a worker that is not connected to a client, so the server can be tested without one.
+  /// The worker offers no slots, calls stay queued.
+  #[cfg(test)]
+  pub fn add_test_worker(self: &mut WorkerPool, resources: ResourceMap) -> WorkerID {
+    let workerid = self.len();
+    let (incoming, receiver) = mpsc::channel();
+    self.scheduler_notify.send(scheduler::Event::Connect(workerid, incoming)).unwrap();
+    self.scheduler_notify.send(scheduler::Event::Register(workerid, resources.clone())).unwrap();
+    thread::spawn(move || for _ in receiver.iter() {}); // answered pulls go nowhere
+    self.workers.write().unwrap().push(Worker {addr: format!("inproc://test-worker-{}", workerid), num_slots: 1, resources: resources, draining: false, driver: false});
+    return workerid;
+  }
+
+  /// Register a new worker that provides `resources` and can hold `num_slots` calls at the same
+  /// time with the worker pool. A driver has no slots and no resources, the scheduler only
+  /// delivers the objects it pulls.
+  pub fn register(self: &mut WorkerPool, network: &Network, addr: &str, num_slots: usize, resources: ResourceMap, driver: bool, objtable: Arc<Mutex<ObjTable>>, setup_socket: &mut Box<MessageTransport>) -> WorkerID {
+    info!("registering new {}", if driver { "driver" } else { "worker" });
     let (incoming, receiver) = mpsc::channel();
     let workerid = self.len();
+    // the scheduler must know the worker before the worker asks for work
+    let worker = Worker {addr: addr.into(), num_slots: num_slots, resources: resources.clone(), draining: false, driver: driver};
+    self.scheduler_notify.send(scheduler::Event::Connect(workerid, incoming)).unwrap();
+    self.scheduler_notify.send(scheduler::Event::Register(workerid, resources)).unwrap();
     let sender = self.scheduler_notify.clone();
     let publish_notify = self.publish_notify.clone();
-    let mut socket = self.connect(zmq_ctx, addr, workerid, setup_socket);
+    let mut socket = self.connect(network, addr, workerid, driver, setup_socket);
     let workers = self.workers.clone();
     let objtable = objtable.clone();
+    let metrics = self.metrics.clone();
     thread::spawn(move || {
-      sender.send(scheduler::Event::Worker(workerid)).unwrap(); // pull for new work
+      for _ in 0..num_slots {
+        sender.send(scheduler::Event::Worker(workerid)).unwrap(); // pull for new work
+      }
       loop {
         let request : comm::Message = receiver.recv().unwrap(); // get the item of work the scheduler chose for us
         match request.get_field_type() {
@@ -154,7 +264,7 @@ impl WorkerPool {
             // orchestrate packages being sent to worker node, start the work there
             let results = request.get_call().get_result();
             assert!(results.len() == 1);
-            send_function_call(&mut socket, request.get_call().get_name(), request.get_call().get_args(), results[0]);
+            send_function_call(&mut socket, request.get_call());
             receive_ack(&mut socket); // TODO: Avoid this round trip
             // deduplicate: (TODO: get rid of inefficiency):
             let mut args = Vec::new();
@@ -163,117 +273,517 @@ impl WorkerPool {
             args.dedup();
             info!("sending args {:?}", args);
             for objref in args.iter() {
-              WorkerPool::deliver_object(workerid, *objref, &workers, &objtable, &publish_notify)
+              WorkerPool::deliver_object(workerid, *objref, &workers, &objtable, &publish_notify, &metrics)
             }
           },
           comm::MessageType::PULL => {
             let objref = request.get_objref();
-            WorkerPool::deliver_object(workerid, objref, &workers, &objtable, &publish_notify);
+            WorkerPool::deliver_object(workerid, objref, &workers, &objtable, &publish_notify, &metrics);
         },
         comm::MessageType::DEBUG => {
           println!("pull through to {}", workerid);
           publish_notify.send((workerid, request)).unwrap(); // pull request through
+        },
+        comm::MessageType::SHUTDOWN | comm::MessageType::FAILED => {
+          publish_notify.send((workerid, request)).unwrap();
         },
           _ => {}
         }
       }
     });
-    self.workers.write().unwrap().push(Worker {addr: addr.into()});
-    self.scheduler_notify.send(scheduler::Event::Register(workerid, incoming));
+    self.workers.write().unwrap().push(worker);
+    if driver {
+      self.metrics.drivers.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.metrics.workers.fetch_add(1, Ordering::Relaxed);
+    }
     return workerid;
   }
 }
 
 /// The server orchestrates the computation.
-pub struct Server<'a> {
+pub struct Server {
   /// For each object reference, the `objtable` stores the list of workers that hold this object.
   objtable: Arc<Mutex<ObjTable>>,
   /// The `fntable` is the mapping from function names to workers that can execute the function (sorted).
-  fntable: Arc<RwLock<FnTable>>,
+  fntable: FnTable,
+  /// The `restable` is the mapping from function names to the resources needed by one call.
+  restable: ResourceTable,
+  /// The `signatures` are the mapping from function names to the declarations they were registered with.
+  signatures: SignatureTable,
+  /// Names that are bound to objects, sorted so they can be listed by prefix.
+  names: BTreeMap<String, ObjRef>,
+  /// Number of names bound to each object.
+  named: HashMap<ObjRef, usize>,
+  /// Metrics of this server, can be served over HTTP.
+  metrics: Arc<Metrics>,
+  /// Timeline of submitted, scheduled and evaluated calls.
+  trace: Arc<Mutex<Trace>>,
   /// Computation graph for this server.
-  graph: graph::CompGraph<'a>,
+  graph: graph::CompGraph,
+  /// Results of the calls that raised an error.
+  failed: HashSet<ObjRef>,
+  /// Objects that have been deleted on all workers that held them, calls and pulls of them are
+  /// rejected.
+  freed: HashSet<ObjRef>,
+  /// Functions that were registered as pure.
+  pure: HashSet<String>,
+  /// Results of the calls of pure functions.
+  memo: HashMap<MemoKey, ObjRef>,
+  /// The key under which each result is memoized.
+  memoized: HashMap<ObjRef, MemoKey>,
+  /// Memoized results in the order they were added, results that have been forgotten since are
+  /// skipped.
+  memo_order: VecDeque<ObjRef>,
+  /// For each content hash, the first object that was stored with this content.
+  contents: HashMap<Vec<u8>, ObjRef>,
+  /// The content hash of each object in `contents`.
+  hashes: HashMap<ObjRef, Vec<u8>>,
+  /// Objects whose content was already stored under a different objref, mapped to that objref.
+  aliases: HashMap<ObjRef, ObjRef>,
+  /// The aliases of each object in `aliases`.
+  aliased: HashMap<ObjRef, HashSet<ObjRef>>,
+  /// Memoized results that were handed to a session other than the one that created them.
+  shared: HashSet<ObjRef>,
+  /// Sessions that have been closed, results of their calls that were still running are freed
+  /// when they arrive.
+  closed_sessions: HashSet<u64>,
+  /// When each client that sends heartbeats was last heard from, in microseconds.
+  last_seen: HashMap<WorkerID, u64>,
+  /// Sessions that have been closed because their client stopped sending heartbeats, they are
+  /// resumed if it sends one again.
+  timed_out: HashSet<WorkerID>,
+  /// Actors whose worker has been drained, calls of their methods are rejected.
+  lost_actors: HashSet<ActorID>,
   /// A pool of workers that are managed by this server.
   workerpool: WorkerPool,
-  /// The ZeroMQ context for this server.
-  zmq_ctx: zmq::Context
+  /// Creates the connections to the clients.
+  network: Network,
+  /// Number of actors that have been created, used to assign actor ids.
+  num_actors: ActorID,
+  /// Tell the scheduler about the critical path whenever a call finishes.
+  critical_path_scheduling: bool
 }
 
-impl<'a> Server<'a> {
-  /// Create a new server.
-  pub fn new(publish_port: u16) -> Server<'a> {
-    let mut ctx = zmq::Context::new();
+impl Server {
+  /// Create a new server that workers connect to over TCP.
+  pub fn new(publish_port: u16) -> Server {
+    return Server::with_network(Network::tcp(), publish_port);
+  }
 
-    let objtable = Arc::new(Mutex::new(Vec::new()));
-    let fntable = Arc::new(RwLock::new(HashMap::new()));
+  /// Create a new server whose connections to the clients are created by `network`.
+  pub fn with_network(network: Network, publish_port: u16) -> Server {
+    let metrics = Arc::new(Metrics::new());
+    let trace = Arc::new(Mutex::new(Trace::new(false)));
 
     Server {
-      workerpool: WorkerPool::new(objtable.clone(), fntable.clone(), publish_port),
-      objtable: objtable,
-      fntable: fntable,
+      workerpool: WorkerPool::new(metrics.clone(), trace.clone(), &network, publish_port),
+      metrics: metrics,
+      trace: trace,
+      objtable: Arc::new(Mutex::new(Vec::new())),
+      fntable: HashMap::new(),
+      restable: HashMap::new(),
+      signatures: HashMap::new(),
+      names: BTreeMap::new(),
+      named: HashMap::new(),
       graph: CompGraph::new(),
-      zmq_ctx: ctx
+      failed: HashSet::new(),
+      freed: HashSet::new(),
+      pure: HashSet::new(),
+      memo: HashMap::new(),
+      memoized: HashMap::new(),
+      memo_order: VecDeque::new(),
+      contents: HashMap::new(),
+      hashes: HashMap::new(),
+      aliases: HashMap::new(),
+      aliased: HashMap::new(),
+      shared: HashSet::new(),
+      closed_sessions: HashSet::new(),
+      last_seen: HashMap::new(),
+      timed_out: HashSet::new(),
+      lost_actors: HashSet::new(),
+      network: network,
+      num_actors: 0,
+      critical_path_scheduling: false
     }
   }
 
+  /// Return the metrics of this server.
+  pub fn metrics<'b>(self: &'b Server) -> Arc<Metrics> {
+    return self.metrics.clone();
+  }
+
+  /// Record a timeline of all calls that can be exported in the Chrome trace format.
+  pub fn set_tracing<'b>(self: &'b mut Server, enabled: bool) {
+    self.trace.lock().unwrap().set_enabled(enabled);
+  }
+
+  /// Let the scheduler prefer calls on the critical path of the computation graph. The path is
+  /// recomputed each time a call finishes, which takes time linear in the size of the graph.
+  pub fn set_critical_path_scheduling<'b>(self: &'b mut Server, enabled: bool) {
+    self.critical_path_scheduling = enabled;
+  }
+
+  /// Recompute the critical path and hand the calls on it to the scheduler.
+  fn update_critical_path<'b>(self: &'b mut Server) {
+    let path = self.graph.critical_path();
+    let objrefs = self.graph.critical_objrefs(&path).into_iter().collect();
+    self.workerpool.scheduler_notify.send(scheduler::Event::CriticalPath(objrefs)).unwrap();
+  }
+
   /// Start the server's main loop.
-  pub fn main_loop<'b>(self: &'b mut Server<'a>, incoming_port: u16, setup_port: u16) {
-    let mut socket = self.zmq_ctx.socket(zmq::REP).ok().unwrap();
+  pub fn main_loop<'b>(self: &'b mut Server, incoming_port: u16, setup_port: u16) {
+    let mut socket = self.bind(incoming_port);
+    self.serve(&mut socket, setup_port);
+  }
+
+  /// Bind the connection for incoming requests, clients can connect once this returns.
+  pub fn bind<'b>(self: &'b mut Server, incoming_port: u16) -> Box<MessageTransport> {
     let localhost = IpAddr::from_str("0.0.0.0").unwrap();
-    bind_socket(&mut socket, &localhost, Some(incoming_port));
+    let (socket, _) = self.network.bind_reply(&localhost, Some(incoming_port));
+    return socket;
+  }
+
+  /// Answer the requests arriving on `socket`.
+  pub fn serve<'b, T: MessageTransport + ?Sized>(self: &'b mut Server, socket: &'b mut T, setup_port: u16) {
     loop {
-      self.process_request(&mut socket, setup_port);
+      self.process_request(socket, setup_port);
     }
   }
 
   /// Add new object to the computation graph and the object pool.
-  pub fn register_new_object<'b>(self: &'b mut Server<'a>) -> ObjRef {
+  pub fn register_new_object<'b>(self: &'b mut Server) -> ObjRef {
     let (objref, _) = self.graph.add_obj();
     assert!(objref as usize == self.objtable.lock().unwrap().len());
     self.objtable.lock().unwrap().push(vec!());
+    self.metrics.objects.store(objref as usize + 1, Ordering::Relaxed);
     return objref;
   }
 
   /// Tell the server that a worker holds a certain object.
-  pub fn register_result<'b>(self: &'b mut Server<'a>, objref: ObjRef, workerid: WorkerID) {
+  /// Check that an object a client names exists.
+  fn check_objref<'b>(self: &'b Server, objref: ObjRef) -> Result<(), String> {
+    if objref as usize >= self.objtable.lock().unwrap().len() {
+      return Err(format!("object {} does not exist", objref));
+    }
+    return Ok(());
+  }
+
+  pub fn register_result<'b>(self: &'b mut Server, objref: ObjRef, workerid: WorkerID) {
     // TODO: Keep vector sorted while inserting
     self.objtable.lock().unwrap()[objref as usize].push(workerid);
   }
 
+  /// Record the content hash of an object a worker stored. If an object with the same content
+  /// exists, the object becomes an alias of it, so calls and pulls use the existing object.
+  pub fn register_content<'b>(self: &'b mut Server, objref: ObjRef, hash: &[u8]) {
+    match self.contents.get(hash).cloned() {
+      Some(canonical) if canonical != objref => {
+        info!("object {} has the same content as object {}", objref, canonical);
+        self.aliases.insert(objref, canonical);
+        self.aliased.entry(canonical).or_insert(HashSet::new()).insert(objref);
+        self.metrics.deduplicated_objects.fetch_add(1, Ordering::Relaxed);
+      },
+      Some(_) => {},
+      None => {
+        self.contents.insert(hash.to_vec(), objref);
+        self.hashes.insert(objref, hash.to_vec());
+      }
+    }
+  }
+
+  /// Return the object that holds the content of `objref`.
+  pub fn canonical<'b>(self: &'b Server, objref: ObjRef) -> ObjRef {
+    return *self.aliases.get(&objref).unwrap_or(&objref);
+  }
+
+  /// Remember that `objref` is the result of the call identified by `key`. If there are too many
+  /// memoized calls, the oldest one is forgotten.
+  fn memoize<'b>(self: &'b mut Server, key: MemoKey, objref: ObjRef) {
+    self.memo.insert(key.clone(), objref);
+    self.memoized.insert(objref, key);
+    self.memo_order.push_back(objref);
+    while self.memo.len() > MEMO_CAPACITY {
+      let oldest = self.memo_order.pop_front().unwrap();
+      self.forget_memoized(oldest);
+    }
+    if self.memo_order.len() > 2 * MEMO_CAPACITY {
+      let memoized = &self.memoized;
+      self.memo_order.retain(|objref| memoized.contains_key(objref));
+    }
+  }
+
+  /// Remove the memoized call whose result is `objref`.
+  fn forget_memoized<'b>(self: &'b mut Server, objref: ObjRef) {
+    if let Some(key) = self.memoized.remove(&objref) {
+      self.memo.remove(&key);
+    }
+  }
+
+  /// Whether an object of `session` is used outside of it: it is bound to a name, another
+  /// session got it as a memoized result or has an object with the same content, or a call of
+  /// another session takes it as an argument.
+  fn used_outside_session<'b>(self: &'b Server, objref: ObjRef, session: WorkerID) -> bool {
+    if self.named.contains_key(&objref) || self.shared.contains(&objref) {
+      return true;
+    }
+    if self.aliased.get(&objref).map_or(false, |aliases| aliases.iter().any(|alias| self.graph.session(*alias) != Some(session as u64))) {
+      return true;
+    }
+    return self.graph.used_by_other_session(objref, session as u64);
+  }
+
+  /// Delete an object on all workers that hold it. Later calls and pulls of it are rejected.
+  fn free_object<'b>(self: &'b mut Server, objref: ObjRef) {
+    self.freed.insert(objref);
+    let holders = mem::replace(&mut self.objtable.lock().unwrap()[objref as usize], vec!());
+    for workerid in holders {
+      self.workerpool.free(workerid, objref);
+    }
+    self.workerpool.scheduler_notify.send(scheduler::Event::Free(objref)).unwrap();
+    self.graph.release_all(objref);
+    self.forget_memoized(objref);
+    if let Some(canonical) = self.aliases.remove(&objref) {
+      if let Some(aliases) = self.aliased.get_mut(&canonical) {
+        aliases.remove(&objref);
+      }
+    }
+    if let Some(hash) = self.hashes.remove(&objref) {
+      self.contents.remove(&hash);
+    }
+  }
+
+  /// Forget the copies of objects a client held, it is gone. Objects that only it held are lost,
+  /// they fail like the results of failed calls.
+  fn remove_holder<'b>(self: &'b mut Server, workerid: WorkerID) {
+    let mut lost = Vec::new();
+    {
+      let mut objtable = self.objtable.lock().unwrap();
+      for (objref, holders) in objtable.iter_mut().enumerate() {
+        let held = holders.len();
+        holders.retain(|holder| *holder != workerid);
+        if held > 0 && holders.is_empty() {
+          lost.push(objref as ObjRef);
+        }
+      }
+    }
+    self.workerpool.scheduler_notify.send(scheduler::Event::Lost(workerid)).unwrap();
+    if lost.len() > 0 {
+      error!("lost {} objects that only client {} held", lost.len(), workerid);
+    }
+    self.fail_objects(lost);
+  }
+
+  /// Record that objects never become available. The calls that depend on them fail as well:
+  /// their queued calls are dropped, and clients that pull any of these objects get an error.
+  fn fail_objects<'b>(self: &'b mut Server, objrefs: Vec<ObjRef>) {
+    let mut failed: Vec<ObjRef> = objrefs.into_iter().filter(|objref| !self.failed.contains(objref)).collect();
+    while failed.len() > 0 {
+      for objref in failed.iter() {
+        self.failed.insert(*objref);
+        self.forget_memoized(*objref); // the next call with the same arguments is evaluated again
+      }
+      let (sender, receiver) = mpsc::channel();
+      self.workerpool.scheduler_notify.send(scheduler::Event::Failed(failed, sender)).unwrap();
+      failed = receiver.recv().unwrap().into_iter().filter(|objref| !self.failed.contains(objref)).collect();
+    }
+  }
+
+  /// Close the session of a client that disconnects: its queued calls are dropped and the
+  /// objects it created are freed on all workers, unless they are used outside of the session.
+  pub fn close_session<'b>(self: &'b mut Server, session: WorkerID) {
+    self.closed_sessions.insert(session as u64);
+    self.last_seen.remove(&session);
+    let (sender, receiver) = mpsc::channel();
+    self.workerpool.scheduler_notify.send(scheduler::Event::CloseSession(session, sender)).unwrap();
+    self.fail_objects(receiver.recv().unwrap()); // the result of a dropped call never becomes available
+    // the client is gone, so it can't evaluate calls anymore
+    if session < self.workerpool.len() && !self.workerpool.is_driver(session) {
+      self.drain_worker(session, false).unwrap();
+    }
+    let mut freed = 0;
+    for objref in self.graph.session_objects(session as u64) {
+      if !self.used_outside_session(objref, session) {
+        self.free_object(objref);
+        freed += 1;
+      }
+    }
+    self.remove_holder(session);
+    self.graph.release_session(session as u64);
+    info!("closed session {}, freed {} objects", session, freed);
+    self.metrics.freed_objects.fetch_add(freed, Ordering::Relaxed);
+    if self.graph.should_prune() {
+      self.prune_graph();
+    }
+  }
+
+  /// Record a heartbeat of a client that was received at time `received`. The sessions of clients
+  /// that missed `MISSED_HEARTBEATS` heartbeats are closed, they disconnected without closing them.
+  /// A client whose session was closed that way resumes it when it sends a heartbeat again; the
+  /// objects that were freed stay freed and the actors that were lost stay lost.
+  pub fn heartbeat<'b>(self: &'b mut Server, workerid: WorkerID, received: u64) {
+    if self.timed_out.remove(&workerid) {
+      info!("client {} sends heartbeats again, resuming its session", workerid);
+      self.closed_sessions.remove(&(workerid as u64));
+      if workerid < self.workerpool.len() && !self.workerpool.is_driver(workerid) {
+        self.workerpool.resume(workerid);
+      }
+    }
+    if !self.closed_sessions.contains(&(workerid as u64)) {
+      self.last_seen.insert(workerid, received);
+    }
+    let silent: Vec<WorkerID> = self.last_seen.iter().filter(|&(_, seen)| received > seen + HEARTBEAT_TIMEOUT).map(|(workerid, _)| *workerid).collect();
+    for workerid in silent {
+      warn!("client {} missed {} heartbeats, closing its session", workerid, MISSED_HEARTBEATS);
+      self.close_session(workerid);
+      self.timed_out.insert(workerid);
+    }
+  }
+
+  /// Don't count the time the server spent on a request received at time `received` against the
+  /// clients, their heartbeats wait until the server listens again.
+  fn postpone_heartbeats<'b>(self: &'b mut Server, received: u64) {
+    let busy = now_us().saturating_sub(received);
+    if busy > HEARTBEAT_INTERVAL {
+      for (_, seen) in self.last_seen.iter_mut() {
+        *seen += busy;
+      }
+    }
+  }
+
+  /// Stop assigning calls to a worker, see `WorkerPool::drain`. The actors living on the worker
+  /// are lost: their queued method calls fail and later calls of their methods are rejected.
+  pub fn drain_worker<'b>(self: &'b mut Server, workerid: WorkerID, shutdown: bool) -> Result<(), String> {
+    let (actorids, results) = match self.workerpool.drain(workerid, shutdown) {
+      Ok(lost) => lost,
+      Err(error) => return Err(error)
+    };
+    for actorid in actorids {
+      self.lost_actors.insert(actorid);
+    }
+    self.fail_objects(results); // the method calls were dropped, their results never become available
+    return Ok(());
+  }
+
   /// Add a new call to the computation graph.
-  pub fn add_call<'b>(self: &'b mut Server<'a>, fnname: String, args: &'b [ObjRef]) -> ObjRef {
+  pub fn add_call<'b>(self: &'b mut Server, fnname: String, args: &'b [ObjRef]) -> ObjRef {
     let result = self.register_new_object();
     self.graph.add_op(fnname, args, result);
     return result;
   }
 
-  /// Add a map call to the computation graph.
-  pub fn add_map<'b>(self: &'b mut Server<'a>, fnname: String, args: &'b comm::Args) -> Vec<ObjRef> {
+  /// Add a map call to the computation graph, with one result for each element of `args`.
+  pub fn add_map<'b>(self: &'b mut Server, fnname: String, args: &'b comm::Args) -> Vec<ObjRef> {
     // TODO: Do this with only one lock
     let mut result = Vec::new();
-    for arg in args.get_objrefs() {
-      let objref = self.register_new_object();
-      result.push(objref);
+    for _ in args.get_objrefs() {
+      result.push(self.register_new_object());
     }
-    // TODO: add the op here
+    let mut objrefs = Vec::new();
+    push_objrefs(args, &mut objrefs); // elements passed by value are not in the graph
+    self.graph.add_map(fnname, &objrefs[..], &result[..]);
     return result;
   }
 
   /// Add a reduce call to the computation graph.
-  pub fn add_reduce<'b>(self: &'b mut Server<'a>, fname: String, args: &'b [ObjRef]) -> ObjRef {
+  pub fn add_reduce<'b>(self: &'b mut Server, fname: String, args: &'b [ObjRef]) -> ObjRef {
       let objref = self.register_new_object();
       // TODO: add the op here
       return objref;
   }
 
+  /// Hand a job to the scheduler and record its submission. If one of its arguments failed, the
+  /// job would never run and its result fails right away.
+  fn submit_job<'b>(self: &'b mut Server, job: comm::Call) {
+    if job.get_args().get_objrefs().iter().any(|elem| *elem >= 0 && self.failed.contains(&(*elem as ObjRef))) {
+      info!("call of {} depends on a failed object", job.get_name());
+      self.fail_objects(job.get_result().to_vec());
+      return;
+    }
+    self.metrics.call_submitted(job.get_name());
+    self.trace.lock().unwrap().instant("submit", "server", now_us(), SERVER_PID, vec![("function", job.get_name().into()), ("objref", job.get_result()[0].to_string())]);
+    self.workerpool.queue_job(job);
+  }
+
+  /// Compute the key under which the result of a call of a pure function is memoized.
+  fn memo_key(call: &comm::Call) -> MemoKey {
+    return (call.get_name().into(), call.get_args().get_objrefs().to_vec(), call.get_args().get_data().to_vec());
+  }
+
   /// Add a worker's request for evaluation to the computation graph and notify the scheduler.
-  pub fn add_request<'b>(self: &'b mut Server<'a>, call: &'b comm::Call) -> comm::Message {
+  pub fn add_request<'b>(self: &'b mut Server, call: &'b comm::Call) -> Result<comm::Message, String> {
+    // methods of actors are registered like functions, but the actor's worker evaluates them
+    if call.get_field_type() != comm::Call_Type::ACTOR_CALL {
+      let workers = match self.fntable.get(call.get_name()) {
+        Some(workers) => workers.clone(),
+        None => return Err(format!("function {} is not registered by any worker", call.get_name()))
+      };
+      // the call would wait in the queue forever
+      let required = scheduler::requirements(&self.restable, call);
+      if !self.workerpool.fits_any(&workers, &required) {
+        return Err(format!("call of {} needs {:?}, more than any worker that registered it and still gets calls has", call.get_name(), required));
+      }
+    } else if call.get_actorid() >= self.num_actors {
+      return Err(format!("call of method {} on unknown actor {}", call.get_name(), call.get_actorid()));
+    } else if self.lost_actors.contains(&call.get_actorid()) {
+      return Err(format!("call of method {} on actor {}, whose worker has been drained", call.get_name(), call.get_actorid()));
+    } else if !self.fntable.contains_key(call.get_name()) {
+      return Err(format!("method {} is not registered by any worker", call.get_name()));
+    }
+    if let Some(signature) = self.signatures.get(call.get_name()) {
+      // each call of a map gets one element
+      let num_args = if call.get_field_type() == comm::Call_Type::MAP_CALL { 1 } else { call.get_args().get_objrefs().len() as u64 };
+      if signature.has_arity() && signature.get_arity() != num_args {
+        return Err(format!("function {} takes {} arguments, got {}", call.get_name(), signature.get_arity(), num_args));
+      }
+    }
     let mut call = call.clone();
+    if !self.aliases.is_empty() {
+      let objrefs: Vec<i64> = call.get_args().get_objrefs().iter().map(|&objref| if objref >= 0 { self.canonical(objref as ObjRef) as i64 } else { objref }).collect();
+      call.mut_args().set_objrefs(objrefs);
+    }
     let mut args = Vec::new();
     push_objrefs(call.get_args(), &mut args);
-    if call.get_field_type() == comm::Call_Type::INVOKE_CALL {
+    if let Some(objref) = args.iter().find(|objref| self.freed.contains(*objref)) {
+      return Err(format!("call of {} takes object {}, which has been freed", call.get_name(), objref));
+    }
+    if let Some(objref) = args.iter().find(|objref| self.forgotten(**objref)) {
+      return Err(format!("call of {} takes object {}, which failed or has been freed", call.get_name(), objref));
+    }
+    if call.get_field_type() == comm::Call_Type::INVOKE_CALL && self.pure.contains(call.get_name()) {
+      let key = Server::memo_key(&call);
+      match self.memo.get(&key).cloned() {
+        Some(objref) => {
+          info!("call of {} is memoized as object {}", call.get_name(), objref);
+          self.metrics.memoized_calls.fetch_add(1, Ordering::Relaxed);
+          if self.graph.session(objref) != Some(call.get_submitter()) {
+            self.shared.insert(objref);
+          }
+          call.set_result(vec!(objref));
+        },
+        None => {
+          let objref = self.add_call(call.get_name().into(), &args[..]);
+          call.set_result(vec!(objref));
+          self.submit_job(call.clone());
+          self.memoize(key, objref);
+        }
+      }
+    } else if call.get_field_type() == comm::Call_Type::INVOKE_CALL {
       let objref = self.add_call(call.get_name().into(), &args[..]);
       call.set_result(vec!(objref));
-      self.workerpool.queue_job(call.clone()); // can we get rid of this clone?
+      self.submit_job(call.clone()); // can we get rid of this clone?
+    }
+    if call.get_field_type() == comm::Call_Type::ACTOR_CREATE {
+      call.set_actorid(self.num_actors);
+      self.num_actors += 1;
+      let objref = self.add_call(call.get_name().into(), &args[..]);
+      call.set_result(vec!(objref));
+      self.submit_job(call.clone());
+    }
+    if call.get_field_type() == comm::Call_Type::ACTOR_CALL {
+      let objref = self.add_call(call.get_name().into(), &args[..]);
+      call.set_result(vec!(objref));
+      self.submit_job(call.clone());
     }
     if call.get_field_type() == comm::Call_Type::MAP_CALL {
       let objrefs = self.add_map(call.get_name().into(), call.get_args());
@@ -281,45 +791,182 @@ impl<'a> Server<'a> {
       for (arg, res) in call.get_args().get_objrefs().iter().zip(objrefs.iter()) {
         let mut c = comm::Call::new();
         let mut a = comm::Args::new();
-        a.set_objrefs(vec!((*arg).clone())); // TODO: copy needed?
+        if *arg < 0 {
+          // an element passed by value, it refers to the data of the map call
+          a.set_objrefs(vec!(-1));
+          a.set_data(RepeatedField::from_vec(vec!(call.get_args().get_data()[(-*arg - 1) as usize].clone())));
+        } else {
+          a.set_objrefs(vec!(*arg));
+        }
         c.set_args(a);
         c.set_result(vec!(*res));
         c.set_name(call.get_name().into());
+        c.set_priority(call.get_priority());
+        c.set_submitter(call.get_submitter());
+        if call.has_resources() {
+          c.set_resources(call.get_resources().clone());
+        }
         // INVOKE_CALL is already the default
-        self.workerpool.queue_job(c);
+        self.submit_job(c);
       }
       call.set_result(objrefs);
     }
     if call.get_field_type() == comm::Call_Type::REDUCE_CALL {
 
+    }
+    for objref in call.get_result() {
+      if self.graph.session(*objref).is_none() {
+        self.graph.set_session(*objref, call.get_submitter());
+      }
+      self.graph.add_user(*objref, call.get_submitter()); // a memoized result may come from another session
     }
     // add obj refs here
     let mut message = comm::Message::new();
     message.set_field_type(comm::MessageType::DONE);
     message.set_call(call);
-    return message;
+    return Ok(message);
+  }
+
+  /// Record that worker `workerid` can evaluate the function declared by `signature`.
+  pub fn register_function<'b>(self: &'b mut Server, workerid: WorkerID, signature: comm::FunctionSignature) {
+    let fnname = signature.get_fnname().to_string();
+    info!("function {} registered (worker {})", fnname, workerid);
+    {
+      let table = &mut self.fntable;
+      if !table.contains_key(&fnname) {
+        table.insert(fnname.clone(), vec!());
+      }
+      match table.get(&fnname).unwrap().binary_search(&workerid) {
+        Ok(_) => {},
+        Err(idx) => { table.get_mut(&fnname).unwrap().insert(idx, workerid); }
+      }
+    }
+    let resources = if signature.has_resources() { Some(resources_from_proto(signature.get_resources())) } else { None };
+    if let Some(ref resources) = resources {
+      self.restable.insert(fnname.clone(), resources.clone());
+    }
+    self.workerpool.scheduler_notify.send(scheduler::Event::Function(workerid, fnname.clone(), resources)).unwrap();
+    if signature.get_pure() {
+      self.pure.insert(fnname.clone());
+    }
+    self.signatures.insert(fnname, signature);
+  }
+
+  /// Bind `name` to an object. Fails if the name is already bound and `overwrite` is false.
+  pub fn put_name<'b>(self: &'b mut Server, name: &str, objref: ObjRef, overwrite: bool) -> Result<(), String> {
+    if let Err(error) = self.check_objref(objref) {
+      return Err(error);
+    }
+    if self.freed.contains(&objref) {
+      return Err(format!("object {} has been freed", objref));
+    }
+    if self.forgotten(objref) {
+      return Err(format!("object {} failed or has been freed", objref));
+    }
+    if !overwrite && self.names.contains_key(name) {
+      return Err(format!("name {} is already bound to object {}", name, self.names[name]));
+    }
+    info!("binding name {} to object {}", name, objref);
+    if let Some(previous) = self.names.insert(name.into(), objref) {
+      let unbound = {
+        let count = self.named.get_mut(&previous).unwrap();
+        *count -= 1;
+        *count == 0
+      };
+      if unbound {
+        self.named.remove(&previous);
+      }
+    }
+    *self.named.entry(objref).or_insert(0) += 1;
+    return Ok(());
+  }
+
+  /// Look up the object bound to `name`.
+  pub fn get_name<'b>(self: &'b Server, name: &str) -> Option<ObjRef> {
+    return self.names.get(name).map(|objref| *objref);
+  }
+
+  /// List all names starting with `prefix` in lexicographic order.
+  pub fn list_names<'b>(self: &'b Server, prefix: &str) -> comm::Names {
+    let mut names = comm::Names::new();
+    for (name, objref) in self.names.iter().skip_while(|&(name, _)| &name[..] < prefix).take_while(|&(name, _)| name.starts_with(prefix)) {
+      let mut info = comm::NameInfo::new();
+      info.set_name(name.clone());
+      info.set_objref(*objref);
+      names.mut_name_info().push(info);
+    }
+    return names;
+  }
+
+  /// Ask the scheduler for its queues and tables.
+  fn scheduler_info<'b>(self: &'b Server) -> comm::SchedulerInfo {
+    let (sender, receiver) = mpsc::channel();
+    self.workerpool.scheduler_notify.send(scheduler::Event::Info(sender)).unwrap();
+    return receiver.recv().unwrap();
+  }
+
+  /// Export the computation graph, annotated with the state of each call and the workers holding
+  /// each object.
+  pub fn export_graph<'b>(self: &'b Server, format: graph::Format) -> String {
+    let running = self.scheduler_info().get_running().iter().cloned().collect();
+    let objtable = self.objtable.lock().unwrap();
+    let exec = graph::ExecState { holders: &*objtable, running: &running, failed: &self.failed, freed: &self.freed };
+    return graph::export(&self.graph, &exec, format);
+  }
+
+  /// Remove the lineage of released objects from the computation graph.
+  fn prune_graph<'b>(self: &'b mut Server) {
+    let running = self.scheduler_info().get_running().iter().cloned().collect();
+    let removed = {
+      let objtable = self.objtable.lock().unwrap();
+      let exec = graph::ExecState { holders: &*objtable, running: &running, failed: &self.failed, freed: &self.freed };
+      self.graph.prune(&exec)
+    };
+    info!("pruned {} nodes from the computation graph", removed);
+    // pruned objects that failed or were freed are recognized by `forgotten` from now on
+    let forgotten: Vec<ObjRef> = self.failed.iter().chain(self.freed.iter()).filter(|objref| !self.graph.contains(**objref)).cloned().collect();
+    for objref in forgotten {
+      self.failed.remove(&objref);
+      self.freed.remove(&objref);
+    }
+  }
+
+  /// Whether an object failed or was freed and has been pruned from the computation graph since,
+  /// such objects are no longer recorded in `failed` and `freed`.
+  fn forgotten<'b>(self: &'b Server, objref: ObjRef) -> bool {
+    return !self.graph.contains(objref) && self.objtable.lock().unwrap().get(objref as usize).map_or(false, |holders| holders.is_empty());
   }
 
   /// Dump the computation graph to a .dot file.
-  pub fn dump<'b>(self: &'b mut Server<'a>, out: &'b mut Write) {
-    let res = graph::to_dot(&self.graph);
+  pub fn dump<'b>(self: &'b mut Server, out: &'b mut Write) {
+    let res = self.export_graph(graph::Format::Dot);
     out.write(res.as_bytes()).unwrap();
   }
 
   /// Establish the setup port that will be used for setting up the client server connection
-  fn bind_setup_socket(zmq_ctx: &mut zmq::Context) -> (Socket, u16) {
-    let mut setup_socket = zmq_ctx.socket(zmq::REP).ok().unwrap();
+  fn bind_setup_socket(network: &Network) -> (Box<MessageTransport>, u16) {
     let localhost = IpAddr::from_str("0.0.0.0").unwrap();
-    let port = bind_socket(&mut setup_socket, &localhost, None);
-    return (setup_socket, port)
+    return network.bind_reply(&localhost, None);
   }
 
   /// Process request by client.
-  pub fn process_request<'b>(self: &'b mut Server<'a>, socket: &'b mut Socket, setup_port: u16) {
+  pub fn process_request<'b, T: MessageTransport + ?Sized>(self: &'b mut Server, socket: &'b mut T, setup_port: u16) {
     let msg = receive_message(socket);
+    let received = now_us();
     match msg.get_field_type() {
       comm::MessageType::INVOKE => {
-        let mut message = self.add_request(msg.get_call());
+        let mut call = msg.get_call().clone();
+        call.set_submitter(msg.get_workerid());
+        let mut message = match self.add_request(&call) {
+          Ok(message) => message,
+          Err(error) => {
+            error!("rejected call from client {}: {}", msg.get_workerid(), error);
+            let mut answer = comm::Message::new();
+            answer.set_field_type(comm::MessageType::DONE);
+            answer.set_error(error);
+            answer
+          }
+        };
         // info!("add request {:?} {:?}, result {:?}", msg.get_call().get_field_type(), msg.get_call().get_name(), message.get_call().get_result());
         send_message(socket, &mut message);
       },
@@ -327,7 +974,8 @@ impl<'a> Server<'a> {
         let workerid = msg.get_workerid() as WorkerID;
         let objref = self.register_new_object();
         self.register_result(objref, workerid);
-        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(objref)).unwrap();
+        self.graph.set_session(objref, workerid as u64);
+        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(objref, workerid)).unwrap();
 
         let mut call = comm::Call::new();
         call.set_result(vec!(objref));
@@ -336,37 +984,100 @@ impl<'a> Server<'a> {
         message.set_call(call); // this is not really a call, just used to store the objref
         send_message(socket, &mut message);
       },
-      comm::MessageType::REGISTER_CLIENT => {
+      comm::MessageType::ALLOC => {
+        // the object is only announced once the client sends STORED
+        let objref = self.register_new_object();
+        self.graph.set_session(objref, msg.get_workerid());
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_objref(objref);
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::STORED => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        if let Err(error) = self.check_objref(msg.get_objref()) {
+          answer.set_error(error);
+          send_message(socket, &mut answer);
+          return;
+        }
+        send_message(socket, &mut answer);
+        let workerid = msg.get_workerid() as WorkerID;
+        self.register_result(msg.get_objref(), workerid); // this must happen before we notify the scheduler
+        if msg.has_hash() {
+          self.register_content(msg.get_objref(), msg.get_hash());
+        }
+        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(msg.get_objref(), workerid)).unwrap();
+      },
+      comm::MessageType::REGISTER_CLIENT | comm::MessageType::REGISTER_DRIVER => {
+        let driver = msg.get_field_type() == comm::MessageType::REGISTER_DRIVER;
         let workerid = self.workerpool.len();
-        let (mut setup_socket, setup_port) = Server::bind_setup_socket(&mut self.zmq_ctx);
+        let (mut setup_socket, setup_port) = Server::bind_setup_socket(&self.network);
         info!("chose port {}", setup_port);
         let mut ack = comm::Message::new();
         ack.set_field_type(comm::MessageType::ACK);
         ack.set_workerid(workerid as u64);
         ack.set_setup_port(setup_port as u64);
         send_message(socket, &mut ack);
-        self.workerpool.register(&mut self.zmq_ctx, msg.get_address(), self.objtable.clone(), &mut setup_socket);
+        let num_slots = if driver { 0 } else if msg.get_num_slots() > 0 { msg.get_num_slots() as usize } else { 1 };
+        let resources = if driver {
+          HashMap::new()
+        } else if msg.has_resources() {
+          resources_from_proto(msg.get_resources())
+        } else {
+          let mut resources = default_resources();
+          resources.insert("CPU".into(), num_slots as f64); // one CPU per slot
+          resources
+        };
+        self.workerpool.register(&self.network, msg.get_address(), num_slots, resources, driver, self.objtable.clone(), &mut setup_socket);
+      },
+      comm::MessageType::REGISTER_FUNCTION | comm::MessageType::REGISTER_MANIFEST if self.workerpool.is_driver(msg.get_workerid() as WorkerID) => {
+        // drivers never get calls assigned, the calls of their functions would wait forever
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_error(format!("client {} is a driver, it can't register functions", msg.get_workerid()));
+        send_message(socket, &mut answer);
       },
       comm::MessageType::REGISTER_FUNCTION => {
-        let workerid = msg.get_workerid() as WorkerID;
-        let fnname = msg.get_fnname();
-        info!("function {} registered (worker {})", fnname.to_string(), workerid);
-        let mut table = self.fntable.write().unwrap();
-        if !table.contains_key(fnname) {
-          table.insert(fnname.into(), vec!());
+        let mut signature = comm::FunctionSignature::new();
+        signature.set_fnname(msg.get_fnname().into());
+        if msg.has_resources() {
+          signature.set_resources(msg.get_resources().clone());
         }
-        match table.get(fnname).unwrap().binary_search(&workerid) {
-          Ok(_) => {},
-          Err(idx) => { table.get_mut(fnname).unwrap().insert(idx, workerid); }
+        signature.set_pure(msg.get_pure());
+        self.register_function(msg.get_workerid() as WorkerID, signature);
+        send_ack(socket);
+      }
+      comm::MessageType::REGISTER_MANIFEST => {
+        let workerid = msg.get_workerid() as WorkerID;
+        info!("registering {} functions (worker {})", msg.get_manifest().get_functions().len(), workerid);
+        for signature in msg.get_manifest().get_functions() {
+          self.register_function(workerid, signature.clone());
         }
         send_ack(socket);
       }
       comm::MessageType::PULL => {
         let workerid = msg.get_workerid() as WorkerID;
-        let objref = msg.get_objref();
+        let objref = self.canonical(msg.get_objref());
         info!("object {} pulled (worker {})", objref, workerid);
-        send_ack(socket);
-        self.workerpool.scheduler_notify.send(scheduler::Event::Pull(workerid, objref)).unwrap();
+        self.graph.add_user(msg.get_objref(), workerid as u64);
+        self.graph.add_user(objref, workerid as u64);
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_objref(objref);
+        if self.failed.contains(&objref) {
+          answer.set_error(format!("object {} failed, it never becomes available", objref));
+          send_message(socket, &mut answer);
+        } else if self.freed.contains(&objref) {
+          answer.set_error(format!("object {} has been freed", objref));
+          send_message(socket, &mut answer);
+        } else if self.forgotten(objref) {
+          answer.set_error(format!("object {} failed or has been freed", objref));
+          send_message(socket, &mut answer);
+        } else {
+          send_message(socket, &mut answer);
+          self.workerpool.scheduler_notify.send(scheduler::Event::Pull(workerid, objref)).unwrap();
+        }
       },
       comm::MessageType::DONE => {
         send_ack(socket);
@@ -374,14 +1085,184 @@ impl<'a> Server<'a> {
         assert!(result.len() == 1);
         let workerid = msg.get_workerid() as WorkerID;
         self.register_result(result[0], workerid); // this must happen before we notify the scheduler
-        self.workerpool.scheduler_notify.send(scheduler::Event::Worker(msg.get_workerid() as usize)).unwrap();
-        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(result[0])).unwrap();
+        if msg.has_hash() {
+          self.register_content(result[0], msg.get_hash());
+        }
+        self.metrics.call_completed(msg.get_call().get_name());
+        if msg.has_timing() {
+          let timing = msg.get_timing();
+          let pid = worker_pid(workerid);
+          let objref = result[0].to_string();
+          let mut trace = self.trace.lock().unwrap();
+          trace.instant("received", "task", timing.get_received(), pid, vec![("objref", objref.clone())]);
+          trace.instant("args ready", "task", timing.get_ready(), pid, vec![("objref", objref.clone())]);
+          trace.complete(msg.get_call().get_name(), "task", timing.get_started(), timing.get_finished(), pid, vec![("objref", objref)]);
+          self.graph.set_timing(result[0], timing.get_started(), timing.get_finished());
+        }
+        if self.critical_path_scheduling {
+          self.update_critical_path();
+        }
+        self.workerpool.scheduler_notify.send(scheduler::Event::Done(workerid, result[0])).unwrap();
+        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(result[0], workerid)).unwrap();
+        match self.graph.session(result[0]) {
+          Some(session) if self.closed_sessions.contains(&session) && !self.used_outside_session(result[0], session as WorkerID) => {
+            self.free_object(result[0]);
+          },
+          _ => {}
+        }
       },
-      comm::MessageType::ACC => {
+      comm::MessageType::FAILED => {
         send_ack(socket);
-        self.objtable.lock().unwrap()[msg.get_objref() as usize].push(msg.get_workerid() as usize);
+        let result = msg.get_call().get_result();
+        let workerid = msg.get_workerid() as WorkerID;
+        error!("call of {} on worker {} failed: {}", msg.get_call().get_name(), workerid, msg.get_error());
+        // the worker's slot is free again, but the result never becomes available
+        self.workerpool.scheduler_notify.send(scheduler::Event::Done(workerid, result[0])).unwrap();
+        self.fail_objects(vec!(result[0]));
+      },
+      comm::MessageType::ACC => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        if let Err(error) = self.check_objref(msg.get_objref()) {
+          answer.set_error(error);
+          send_message(socket, &mut answer);
+          return;
+        }
+        send_message(socket, &mut answer);
+        self.register_result(msg.get_objref(), msg.get_workerid() as WorkerID);
+        self.workerpool.scheduler_notify.send(scheduler::Event::Obj(msg.get_objref(), msg.get_workerid() as WorkerID)).unwrap();
+        self.metrics.bytes_transferred.fetch_add(msg.get_size() as usize, Ordering::Relaxed);
+        if msg.has_size() {
+          self.graph.set_size(msg.get_objref(), msg.get_size());
+        }
         info!("delivery of {} to {} successful", msg.get_objref(), msg.get_workerid());
       }
+      comm::MessageType::BLOCKED => {
+        send_ack(socket);
+        self.workerpool.scheduler_notify.send(scheduler::Event::Blocked(msg.get_workerid() as WorkerID, msg.get_objref())).unwrap();
+      },
+      comm::MessageType::UNBLOCKED => {
+        send_ack(socket);
+        self.workerpool.scheduler_notify.send(scheduler::Event::Unblocked(msg.get_workerid() as WorkerID, msg.get_objref())).unwrap();
+      },
+      comm::MessageType::PUT_NAME => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        match self.put_name(msg.get_name(), msg.get_objref(), msg.get_overwrite()) {
+          Ok(()) => {},
+          Err(error) => answer.set_error(error)
+        }
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::GET_NAME => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        match self.get_name(msg.get_name()) {
+          Some(objref) => {
+            self.graph.add_user(objref, msg.get_workerid());
+            answer.set_objref(objref);
+          },
+          None => answer.set_error(format!("name {} is not bound", msg.get_name()))
+        }
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::LIST_NAMES => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_names(self.list_names(msg.get_name()));
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::TRACE => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_trace(self.trace.lock().unwrap().to_json());
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::LIST_WORKERS => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_workers(RepeatedField::from_vec(self.workerpool.worker_info()));
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::SCHEDULER_INFO => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_scheduler_info(self.scheduler_info());
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::DUMP_GRAPH => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        match graph::Format::from_name(msg.get_format()) {
+          Some(format) => answer.set_graph(self.export_graph(format)),
+          None => answer.set_error(format!("unknown graph format {}", msg.get_format()))
+        }
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::LINEAGE => {
+        let objref = msg.get_objref();
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        if objref as usize >= self.objtable.lock().unwrap().len() {
+          answer.set_error(format!("object {} does not exist", objref));
+        } else if !self.graph.contains(objref) {
+          answer.set_error(format!("the lineage of object {} has been pruned", objref));
+        } else {
+          let mut lineage = comm::Lineage::new();
+          lineage.set_objrefs(self.graph.lineage(objref, msg.get_depth(), msg.get_descendants()));
+          // always set, so the serialized answer is never empty
+          lineage.set_fnname(self.graph.producer(objref).unwrap_or("").into());
+          answer.set_lineage(lineage);
+        }
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::CLOSE_SESSION => {
+        send_ack(socket);
+        self.close_session(msg.get_workerid() as WorkerID);
+      },
+      comm::MessageType::HEARTBEAT => {
+        send_ack(socket);
+        self.heartbeat(msg.get_workerid() as WorkerID, received);
+      },
+      comm::MessageType::RELEASE => {
+        send_ack(socket);
+        let session = msg.get_workerid();
+        let canonical = self.canonical(msg.get_objref());
+        self.graph.release(msg.get_objref(), session);
+        if canonical != msg.get_objref() {
+          self.graph.release(canonical, session);
+        }
+        if self.graph.should_prune() {
+          self.prune_graph();
+        }
+      },
+      comm::MessageType::CRITICAL_PATH => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        answer.set_graph(self.graph.critical_path_report());
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::DRAIN | comm::MessageType::SHUTDOWN => {
+        let workerid = msg.get_workerid() as WorkerID;
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        match self.drain_worker(workerid, msg.get_field_type() == comm::MessageType::SHUTDOWN) {
+          Ok(()) => {},
+          Err(error) => answer.set_error(error)
+        }
+        send_message(socket, &mut answer);
+      },
+      comm::MessageType::SET_WEIGHT => {
+        let mut answer = comm::Message::new();
+        answer.set_field_type(comm::MessageType::ACK);
+        if msg.get_weight() > 0.0 {
+          info!("client {} has weight {}", msg.get_workerid(), msg.get_weight());
+          self.workerpool.scheduler_notify.send(scheduler::Event::Weight(msg.get_workerid() as WorkerID, msg.get_weight())).unwrap();
+        } else {
+          answer.set_error(format!("weight must be positive, got {}", msg.get_weight()));
+        }
+        send_message(socket, &mut answer);
+      },
       comm::MessageType::DEBUG => {
         info!("received debug request");
         send_ack(socket);
@@ -392,18 +1273,382 @@ impl<'a> Server<'a> {
         process::exit(1);
       }
     }
+    self.postpone_heartbeats(received);
   }
 }
 
 /// Send request for function execution to a worker through the socket `socket`.
-pub fn send_function_call(socket: &mut Socket, name: &str, arguments: &comm::Args, result: ObjRef) {
+pub fn send_function_call<T: MessageTransport + ?Sized>(socket: &mut T, job: &comm::Call) {
   let mut message = comm::Message::new();
   message.set_field_type(comm::MessageType::INVOKE);
   let mut call = comm::Call::new();
-  call.set_field_type(comm::Call_Type::INVOKE_CALL);
-  call.set_name(name.into());
-  call.set_args(arguments.clone()); // TODO: get rid of this copy
-  call.set_result(vec!(result));
+  match job.get_field_type() {
+    comm::Call_Type::ACTOR_CREATE | comm::Call_Type::ACTOR_CALL => {
+      call.set_field_type(job.get_field_type());
+      call.set_actorid(job.get_actorid());
+    },
+    _ => call.set_field_type(comm::Call_Type::INVOKE_CALL)
+  }
+  call.set_name(job.get_name().into());
+  call.set_args(job.get_args().clone()); // TODO: get rid of this copy
+  call.set_result(vec!(job.get_result()[0]));
   message.set_call(call);
   send_message(socket, &mut message);
 }
+
+/// A client of a server in a test, connected to it by an in-memory transport.
+#[cfg(test)]
+struct TestClient {
+  client: ChannelTransport,
+  endpoint: ChannelTransport
+}
+
+#[cfg(test)]
+impl TestClient {
+  /// Send `message` to `server`, let the server process it and return the answer.
+  fn request(self: &mut TestClient, server: &mut Server, message: &mut comm::Message) -> comm::Message {
+    send_message(&mut self.client, message);
+    server.process_request(&mut self.endpoint, 0);
+    return receive_message(&mut self.client);
+  }
+
+  /// Like `request`, but the answer must be an acknowledgement without an error.
+  fn ack(self: &mut TestClient, server: &mut Server, message: &mut comm::Message) {
+    let answer = self.request(server, message);
+    assert!(answer.get_field_type() == comm::MessageType::ACK && !answer.has_error());
+  }
+}
+
+/// A server whose clients are threads of the test and a client connected to it.
+#[cfg(test)]
+fn test_server() -> (Server, TestClient) {
+  use utils::{SharedContext, Protocol};
+  let server = Server::with_network(Network::new(SharedContext::new(), Protocol::Inproc), 0);
+  let (client, endpoint) = channel_pair();
+  return (server, TestClient { client: client, endpoint: endpoint });
+}
+
+#[test]
+fn test_process_request() {
+  let (mut server, mut client) = test_server();
+
+  let mut alloc = comm::Message::new();
+  alloc.set_field_type(comm::MessageType::ALLOC);
+  let objref = client.request(&mut server, &mut alloc).get_objref();
+
+  let mut put = comm::Message::new();
+  put.set_field_type(comm::MessageType::PUT_NAME);
+  put.set_name("weights".into());
+  put.set_objref(objref);
+  assert!(!client.request(&mut server, &mut put).has_error());
+
+  let mut get = comm::Message::new();
+  get.set_field_type(comm::MessageType::GET_NAME);
+  get.set_name("weights".into());
+  assert_eq!(client.request(&mut server, &mut get).get_objref(), objref);
+
+  get.set_name("bias".into());
+  assert!(client.request(&mut server, &mut get).has_error());
+
+  // clients can only report objects the server handed out
+  for field_type in vec![comm::MessageType::STORED, comm::MessageType::ACC] {
+    let mut stored = comm::Message::new();
+    stored.set_field_type(field_type);
+    stored.set_objref(objref + 1);
+    assert!(client.request(&mut server, &mut stored).has_error());
+  }
+}
+
+#[test]
+fn test_unknown_function() {
+  let (mut server, mut client) = test_server();
+  server.workerpool.add_test_worker(default_resources());
+
+  let mut invoke = comm::Message::new();
+  invoke.set_field_type(comm::MessageType::INVOKE);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  invoke.set_call(call);
+  let answer = client.request(&mut server, &mut invoke);
+  assert!(answer.has_error());
+  assert!(answer.get_call().get_result().is_empty());
+
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+  register.set_fnname("f".into());
+  client.ack(&mut server, &mut register);
+
+  let answer = client.request(&mut server, &mut invoke);
+  assert!(!answer.has_error());
+  assert_eq!(answer.get_call().get_result().len(), 1);
+}
+
+#[test]
+fn test_register_manifest() {
+  let (mut server, mut client) = test_server();
+  server.workerpool.add_test_worker(default_resources());
+
+  let mut manifest = comm::Manifest::new();
+  let mut f = comm::FunctionSignature::new();
+  f.set_fnname("f".into());
+  f.set_arity(1);
+  manifest.mut_functions().push(f);
+  let mut g = comm::FunctionSignature::new();
+  g.set_fnname("g".into());
+  g.set_pure(true);
+  manifest.mut_functions().push(g);
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_MANIFEST);
+  register.set_manifest(manifest);
+  client.ack(&mut server, &mut register);
+  assert!(server.pure.contains("g"));
+
+  let mut invoke = comm::Message::new();
+  invoke.set_field_type(comm::MessageType::INVOKE);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  let mut args = comm::Args::new();
+  args.set_objrefs(vec!(-1, -2));
+  args.set_data(RepeatedField::from_vec(vec!(vec!(1), vec!(2))));
+  call.set_args(args.clone());
+  invoke.set_call(call.clone());
+  assert!(client.request(&mut server, &mut invoke).has_error());
+
+  args.set_objrefs(vec!(-1));
+  call.set_args(args.clone());
+  invoke.set_call(call.clone());
+  assert!(!client.request(&mut server, &mut invoke).has_error());
+
+  // functions without an arity take any number of arguments
+  call.set_name("g".into());
+  call.set_args(comm::Args::new());
+  invoke.set_call(call);
+  assert!(!client.request(&mut server, &mut invoke).has_error());
+}
+
+#[test]
+fn test_oversized_call() {
+  let (mut server, mut client) = test_server();
+  let mut resources = default_resources();
+  resources.insert("GPU".into(), 1.0);
+  let workerid = server.workerpool.add_test_worker(resources);
+
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+  register.set_fnname("f".into());
+  register.set_workerid(workerid as u64);
+  client.ack(&mut server, &mut register);
+
+  // no worker has two GPUs, the call could never be scheduled
+  let mut required = HashMap::new();
+  required.insert("GPU".into(), 2.0);
+  let mut invoke = comm::Message::new();
+  invoke.set_field_type(comm::MessageType::INVOKE);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  call.set_resources(resources_to_proto(&required));
+  invoke.set_call(call.clone());
+  assert!(client.request(&mut server, &mut invoke).has_error());
+
+  required.insert("GPU".into(), 1.0);
+  call.set_resources(resources_to_proto(&required));
+  invoke.set_call(call);
+  assert!(!client.request(&mut server, &mut invoke).has_error());
+}
+
+#[test]
+fn test_unknown_actor() {
+  let (mut server, mut client) = test_server();
+
+  let mut invoke = comm::Message::new();
+  invoke.set_field_type(comm::MessageType::INVOKE);
+  let mut call = comm::Call::new();
+  call.set_field_type(comm::Call_Type::ACTOR_CALL);
+  call.set_name("module.Counter.increment".into());
+  call.set_actorid(0);
+  invoke.set_call(call.clone());
+  let answer = client.request(&mut server, &mut invoke);
+  assert!(answer.has_error());
+  assert!(answer.get_call().get_result().is_empty());
+
+  // the actor exists, but its class has no such method
+  server.num_actors = 1;
+  call.set_name("module.Counter.decrement".into());
+  invoke.set_call(call);
+  assert!(client.request(&mut server, &mut invoke).has_error());
+}
+
+#[test]
+fn test_lost_objects() {
+  let (mut server, _) = test_server();
+  let workerid = server.workerpool.add_test_worker(default_resources());
+  let otherid = server.workerpool.add_test_worker(default_resources());
+  let alone = server.register_new_object();
+  let copied = server.register_new_object();
+  {
+    let mut objtable = server.objtable.lock().unwrap();
+    objtable[alone as usize].push(workerid);
+    objtable[copied as usize].push(workerid);
+    objtable[copied as usize].push(otherid);
+  }
+
+  // the worker stopped, no other client gets its objects delivered from it anymore
+  server.close_session(workerid);
+  assert_eq!(server.objtable.lock().unwrap()[alone as usize], vec!());
+  assert_eq!(server.objtable.lock().unwrap()[copied as usize], vec!(otherid));
+  assert!(server.failed.contains(&alone));
+  assert!(!server.failed.contains(&copied));
+}
+
+#[test]
+fn test_failed_call() {
+  let (mut server, mut client) = test_server();
+  let workerid = server.workerpool.add_test_worker(default_resources());
+
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+  register.set_fnname("f".into());
+  register.set_workerid(workerid as u64);
+  client.ack(&mut server, &mut register);
+
+  let mut invoke = comm::Message::new();
+  invoke.set_field_type(comm::MessageType::INVOKE);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  invoke.set_call(call.clone());
+  let result = client.request(&mut server, &mut invoke).get_call().get_result()[0];
+
+  let mut failed = comm::Message::new();
+  failed.set_field_type(comm::MessageType::FAILED);
+  failed.set_workerid(workerid as u64);
+  failed.mut_call().set_result(vec!(result));
+  client.ack(&mut server, &mut failed);
+
+  // a call that takes the failed object fails as well
+  let mut args = comm::Args::new();
+  args.set_objrefs(vec!(result as i64));
+  call.set_args(args);
+  invoke.set_call(call);
+  let dependent = client.request(&mut server, &mut invoke).get_call().get_result()[0];
+
+  let mut pull = comm::Message::new();
+  pull.set_field_type(comm::MessageType::PULL);
+  for objref in vec!(result, dependent) {
+    pull.set_objref(objref);
+    assert!(client.request(&mut server, &mut pull).has_error());
+  }
+}
+
+#[test]
+fn test_memoized_call() {
+  let (mut server, mut client) = test_server();
+  server.workerpool.add_test_worker(default_resources());
+
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+  register.set_fnname("g".into());
+  register.set_pure(true);
+  client.ack(&mut server, &mut register);
+
+  let mut results = Vec::new();
+  for data in vec!(vec!(1), vec!(1), vec!(2)) {
+    let mut invoke = comm::Message::new();
+    invoke.set_field_type(comm::MessageType::INVOKE);
+    let mut call = comm::Call::new();
+    call.set_name("g".into());
+    let mut args = comm::Args::new();
+    args.set_objrefs(vec!(-1));
+    args.set_data(RepeatedField::from_vec(vec!(data)));
+    call.set_args(args);
+    invoke.set_call(call);
+    results.push(client.request(&mut server, &mut invoke).get_call().get_result()[0]);
+  }
+  // only a call with the same data gets the memoized result
+  assert_eq!(results[0], results[1]);
+  assert!(results[0] != results[2]);
+  server.forget_memoized(results[0]);
+  assert_eq!(server.memo.len(), 1);
+  assert!(server.memoized.get(&results[0]).is_none());
+}
+
+#[test]
+fn test_freed_objects() {
+  let (mut server, _) = test_server();
+  let workerid = server.workerpool.add_test_worker(default_resources());
+  let mut signature = comm::FunctionSignature::new();
+  signature.set_fnname("f".into());
+  server.register_function(workerid, signature);
+  let objref = server.register_new_object();
+  server.graph.set_session(objref, 7);
+  server.register_result(objref, workerid);
+
+  // the session that created the object closes and nobody else uses it
+  server.close_session(7);
+  assert!(server.freed.contains(&objref));
+  let exec = graph::ExecState { holders: &vec!(vec!()), running: &HashSet::new(), failed: &server.failed, freed: &server.freed };
+  assert_eq!(exec.obj_state(objref), graph::State::Freed);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  call.mut_args().set_objrefs(vec!(objref as i64));
+  assert!(server.add_request(&call).is_err());
+  assert!(server.put_name("x", objref, false).is_err());
+  // once the object is pruned it is no longer recorded, but it is still rejected
+  server.prune_graph();
+  assert!(!server.freed.contains(&objref));
+  assert!(server.add_request(&call).is_err());
+  assert!(server.put_name("x", objref, false).is_err());
+}
+
+#[test]
+fn test_heartbeat() {
+  let (mut server, _) = test_server();
+  server.heartbeat(3, 0);
+  server.heartbeat(4, HEARTBEAT_TIMEOUT);
+  assert!(server.closed_sessions.is_empty());
+  // client 3 disconnected without closing its session
+  server.heartbeat(4, HEARTBEAT_TIMEOUT + 1);
+  assert!(server.closed_sessions.contains(&3));
+  assert!(!server.closed_sessions.contains(&4));
+  // the client was only cut off, it resumes its session when it sends a heartbeat again
+  server.heartbeat(3, HEARTBEAT_TIMEOUT + 2);
+  assert!(!server.closed_sessions.contains(&3));
+  assert!(server.last_seen.contains_key(&3));
+  // the time the server spent on a request doesn't count as missed heartbeats
+  server.postpone_heartbeats(now_us() - 2 * HEARTBEAT_TIMEOUT);
+  server.heartbeat(3, 3 * HEARTBEAT_TIMEOUT);
+  assert!(!server.closed_sessions.contains(&4));
+  // a session closed by its client stays closed
+  server.close_session(4);
+  server.heartbeat(4, 3 * HEARTBEAT_TIMEOUT + 1);
+  assert!(server.closed_sessions.contains(&4));
+  assert!(!server.last_seen.contains_key(&4));
+}
+
+#[test]
+fn test_driver_registration() {
+  let (mut server, mut client) = test_server();
+  let driverid = server.workerpool.add_test_worker(HashMap::new());
+  server.workerpool.workers.write().unwrap()[driverid].driver = true;
+
+  let mut register = comm::Message::new();
+  register.set_field_type(comm::MessageType::REGISTER_FUNCTION);
+  register.set_fnname("f".into());
+  register.set_workerid(driverid as u64);
+  assert!(client.request(&mut server, &mut register).has_error());
+  assert!(server.fntable.get("f").is_none());
+}
+
+#[test]
+fn test_drained_function() {
+  let (mut server, _) = test_server();
+  let workerid = server.workerpool.add_test_worker(default_resources());
+  let mut signature = comm::FunctionSignature::new();
+  signature.set_fnname("f".into());
+  server.register_function(workerid, signature);
+  let mut call = comm::Call::new();
+  call.set_name("f".into());
+  assert!(server.add_request(&call).is_ok());
+  // the only worker that registered the function is drained, a new call would never run
+  server.drain_worker(workerid, false).unwrap();
+  assert!(server.add_request(&call).is_err());
+}
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utils::{WorkerID, json_string};
+
+/// Maximum number of events in a trace, the oldest ones are dropped first.
+const TRACE_CAPACITY: usize = 1048576;
+
+/// Process id of the server in traces, workers use their worker id plus one.
+pub const SERVER_PID: u64 = 0;
+
+/// Process id of a worker in traces.
+pub fn worker_pid(workerid: WorkerID) -> u64 {
+  return workerid as u64 + 1;
+}
+
+/// Microseconds since the UNIX epoch.
+pub fn now_us() -> u64 {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+  return now.as_secs() * 1000000 + (now.subsec_nanos() / 1000) as u64;
+}
+
+/// An event in the Chrome trace event format. Instant events have phase 'i', events with a
+/// duration have phase 'X'.
+pub struct TraceEvent {
+  name: String,
+  category: &'static str,
+  phase: char,
+  ts: u64,
+  dur: u64,
+  pid: u64,
+  args: Vec<(&'static str, String)>
+}
+
+/// A timeline of task events that can be loaded into chrome://tracing or Perfetto. It keeps the
+/// last `TRACE_CAPACITY` events.
+pub struct Trace {
+  enabled: bool,
+  capacity: usize,
+  events: VecDeque<TraceEvent>
+}
+
+impl Trace {
+  pub fn new(enabled: bool) -> Trace {
+    return Trace::with_capacity(enabled, TRACE_CAPACITY);
+  }
+
+  pub fn with_capacity(enabled: bool, capacity: usize) -> Trace {
+    return Trace { enabled: enabled, capacity: capacity, events: VecDeque::new() };
+  }
+
+  fn push(self: &mut Trace, event: TraceEvent) {
+    if self.events.len() >= self.capacity {
+      self.events.pop_front();
+    }
+    self.events.push_back(event);
+  }
+
+  pub fn set_enabled(self: &mut Trace, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  /// Record an event that happened at time `ts` in process `pid`.
+  pub fn instant(self: &mut Trace, name: &str, category: &'static str, ts: u64, pid: u64, args: Vec<(&'static str, String)>) {
+    if self.enabled {
+      self.push(TraceEvent { name: name.into(), category: category, phase: 'i', ts: ts, dur: 0, pid: pid, args: args });
+    }
+  }
+
+  /// Record an event that lasted from `start` to `end` in process `pid`.
+  pub fn complete(self: &mut Trace, name: &str, category: &'static str, start: u64, end: u64, pid: u64, args: Vec<(&'static str, String)>) {
+    if self.enabled {
+      let dur = if end > start { end - start } else { 0 };
+      self.push(TraceEvent { name: name.into(), category: category, phase: 'X', ts: start, dur: dur, pid: pid, args: args });
+    }
+  }
+
+  /// Serialize the trace as Chrome trace JSON.
+  pub fn to_json(self: &Trace) -> String {
+    let mut out = String::from("{\"traceEvents\":[\n");
+    for (i, event) in self.events.iter().enumerate() {
+      if i > 0 {
+        out.push_str(",\n");
+      }
+      out.push_str(&format!("{{\"name\":{},\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":0", json_string(&event.name), event.category, event.phase, event.ts, event.pid));
+      if event.phase == 'X' {
+        out.push_str(&format!(",\"dur\":{}", event.dur));
+      } else {
+        out.push_str(",\"s\":\"p\"");
+      }
+      out.push_str(",\"args\":{");
+      for (j, &(key, ref value)) in event.args.iter().enumerate() {
+        if j > 0 {
+          out.push_str(",");
+        }
+        out.push_str(&format!("\"{}\":{}", key, json_string(value)));
+      }
+      out.push_str("}}");
+    }
+    out.push_str("\n]}\n");
+    return out;
+  }
+}
+
+#[test]
+fn test_trace_to_json() {
+  let mut trace = Trace::new(true);
+  trace.instant("submit", "server", 5, SERVER_PID, vec![("objref", "3".into())]);
+  trace.complete("f\"g", "task", 10, 25, worker_pid(0), vec![]);
+  assert_eq!(trace.to_json(), "{\"traceEvents\":[\n\
+    {\"name\":\"submit\",\"cat\":\"server\",\"ph\":\"i\",\"ts\":5,\"pid\":0,\"tid\":0,\"s\":\"p\",\"args\":{\"objref\":\"3\"}},\n\
+    {\"name\":\"f\\\"g\",\"cat\":\"task\",\"ph\":\"X\",\"ts\":10,\"pid\":1,\"tid\":0,\"dur\":15,\"args\":{}}\n]}\n");
+}
+
+#[test]
+fn test_trace_capacity() {
+  let mut trace = Trace::with_capacity(true, 2);
+  for ts in 0..3 {
+    trace.instant("submit", "server", ts, SERVER_PID, vec![]);
+  }
+  let ts: Vec<u64> = trace.events.iter().map(|event| event.ts).collect();
+  assert_eq!(ts, vec![1, 2]);
+}
@@ -0,0 +1,446 @@
+// Transports carry the protocol buffer messages between the server and the clients. Besides
+// ZeroMQ sockets there are in-memory transports: `ChannelTransport` lets tests drive the server
+// one message at a time, and a `Network` with `Protocol::Memory` connects a server and its clients
+// without any sockets. The rest of the code only sees the traits below; `Network` is the one place
+// that creates connections.
+
+use comm;
+use protobuf;
+use protobuf::Message;
+use zmq;
+use zmq::Socket;
+use rand;
+use rand::distributions::{IndependentSample, Range};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::mpsc::{Sender, Receiver};
+use utils::{WorkerID, SharedContext, Protocol};
+
+/// A connection that delivers messages in order.
+pub trait MessageSource: Send {
+  /// Block until the next message arrives and return it.
+  fn receive_message(self: &mut Self) -> comm::Message;
+  /// Return the next message if one has arrived already.
+  fn try_receive_message(self: &mut Self) -> Option<comm::Message>;
+}
+
+/// A connection that sends and receives messages in order.
+pub trait MessageTransport: MessageSource {
+  /// Send `message`, blocking until it is handed to the transport.
+  fn send_message(self: &mut Self, message: &mut comm::Message);
+}
+
+/// The server's end of the connections that push messages to the clients. Each message is only
+/// received by the client it is addressed to.
+pub trait Publisher: Send {
+  /// Send `message` to client `workerid`.
+  fn publish(self: &mut Self, workerid: WorkerID, message: &comm::Message);
+}
+
+impl<T: MessageSource + ?Sized> MessageSource for Box<T> {
+  fn receive_message(self: &mut Box<T>) -> comm::Message {
+    return (**self).receive_message();
+  }
+
+  fn try_receive_message(self: &mut Box<T>) -> Option<comm::Message> {
+    return (**self).try_receive_message();
+  }
+}
+
+impl<T: MessageTransport + ?Sized> MessageTransport for Box<T> {
+  fn send_message(self: &mut Box<T>, message: &mut comm::Message) {
+    (**self).send_message(message);
+  }
+}
+
+fn parse_message(bytes: &[u8]) -> comm::Message {
+  let mut input_stream = protobuf::CodedInputStream::from_bytes(bytes);
+  return protobuf::core::parse_from::<comm::Message>(&mut input_stream).unwrap();
+}
+
+fn serialize_message(message: &comm::Message) -> Vec<u8> {
+  let mut buf = Vec::new();
+  message.write_to_vec(&mut buf).unwrap();
+  return buf;
+}
+
+impl MessageSource for Socket {
+  fn receive_message(self: &mut Socket) -> comm::Message {
+    let mut msg = zmq::Message::new().unwrap();
+    self.recv(&mut msg, 0).unwrap();
+    return parse_message(msg.deref());
+  }
+
+  fn try_receive_message(self: &mut Socket) -> Option<comm::Message> {
+    let mut msg = zmq::Message::new().unwrap();
+    return self.recv(&mut msg, zmq::DONTWAIT).ok().map(|_| parse_message(msg.deref()));
+  }
+}
+
+impl MessageTransport for Socket {
+  fn send_message(self: &mut Socket, message: &mut comm::Message) {
+    self.send(serialize_message(message).as_slice(), 0).unwrap();
+  }
+}
+
+/// Length of the topic that addresses a published message to a client.
+const TOPIC_LEN: usize = 7;
+
+fn topic(workerid: WorkerID) -> String {
+  return format!("{:0>#07}", workerid);
+}
+
+/// A ZeroMQ PUB socket, messages are prefixed with the id of the client as the topic.
+struct ZmqPublisher {
+  socket: Socket
+}
+
+impl Publisher for ZmqPublisher {
+  fn publish(self: &mut ZmqPublisher, workerid: WorkerID, message: &comm::Message) {
+    let mut buf = Vec::new();
+    write!(buf, "{}", topic(workerid)).unwrap();
+    message.write_to_writer(&mut buf).unwrap();
+    self.socket.send(buf.as_slice(), 0).unwrap();
+  }
+}
+
+/// A ZeroMQ SUB socket that is subscribed to the messages of one client.
+struct ZmqSubscription {
+  socket: Socket
+}
+
+impl MessageSource for ZmqSubscription {
+  fn receive_message(self: &mut ZmqSubscription) -> comm::Message {
+    let mut msg = zmq::Message::new().unwrap();
+    self.socket.recv(&mut msg, 0).unwrap();
+    return parse_message(&msg.deref()[TOPIC_LEN..]);
+  }
+
+  fn try_receive_message(self: &mut ZmqSubscription) -> Option<comm::Message> {
+    let mut msg = zmq::Message::new().unwrap();
+    return self.socket.recv(&mut msg, zmq::DONTWAIT).ok().map(|_| parse_message(&msg.deref()[TOPIC_LEN..]));
+  }
+}
+
+/// A request on an in-memory connection and the channel its answer goes back through.
+type MemoryRequest = (Vec<u8>, Sender<Vec<u8>>);
+
+/// The channels of the clients subscribed to an in-memory publisher.
+type Subscribers = Arc<Mutex<HashMap<WorkerID, Sender<Vec<u8>>>>>;
+
+/// The in-memory endpoints of a network, by address. Like with ZeroMQ, either end may come first:
+/// connecting creates an endpoint and binding takes it over.
+struct MemoryEndpoints {
+  /// The ends that accept requests. The receiver is taken by the end that binds the address.
+  replies: HashMap<String, (Sender<MemoryRequest>, Option<Receiver<MemoryRequest>>)>,
+  publishers: HashMap<String, Subscribers>
+}
+
+impl MemoryEndpoints {
+  fn reply(self: &mut MemoryEndpoints, addr: &str) -> &mut (Sender<MemoryRequest>, Option<Receiver<MemoryRequest>>) {
+    return self.replies.entry(addr.into()).or_insert_with(|| {
+      let (sender, receiver) = mpsc::channel();
+      (sender, Some(receiver))
+    });
+  }
+
+  fn publisher(self: &mut MemoryEndpoints, addr: &str) -> Subscribers {
+    return self.publishers.entry(addr.into()).or_insert_with(|| Arc::new(Mutex::new(HashMap::new()))).clone();
+  }
+}
+
+/// The in-memory end that accepts requests, like a REP socket. Each request is answered through
+/// the channel it came with.
+struct MemoryReply {
+  requests: Receiver<MemoryRequest>,
+  answer: Option<Sender<Vec<u8>>>
+}
+
+impl MessageSource for MemoryReply {
+  fn receive_message(self: &mut MemoryReply) -> comm::Message {
+    let (bytes, answer) = self.requests.recv().unwrap();
+    self.answer = Some(answer);
+    return parse_message(&bytes[..]);
+  }
+
+  fn try_receive_message(self: &mut MemoryReply) -> Option<comm::Message> {
+    match self.requests.try_recv() {
+      Ok((bytes, answer)) => {
+        self.answer = Some(answer);
+        return Some(parse_message(&bytes[..]));
+      },
+      Err(_) => return None
+    }
+  }
+}
+
+impl MessageTransport for MemoryReply {
+  fn send_message(self: &mut MemoryReply, message: &mut comm::Message) {
+    let answer = self.answer.take().expect("a request has to be received before it is answered");
+    // like with ZeroMQ, the answer is lost if the requesting end is gone
+    let _ = answer.send(serialize_message(message));
+  }
+}
+
+/// The in-memory end that sends requests, like a REQ socket.
+struct MemoryRequester {
+  requests: Sender<MemoryRequest>,
+  answer: Sender<Vec<u8>>,
+  answers: Receiver<Vec<u8>>
+}
+
+impl MessageSource for MemoryRequester {
+  fn receive_message(self: &mut MemoryRequester) -> comm::Message {
+    return parse_message(&self.answers.recv().unwrap()[..]);
+  }
+
+  fn try_receive_message(self: &mut MemoryRequester) -> Option<comm::Message> {
+    return self.answers.try_recv().ok().map(|bytes| parse_message(&bytes[..]));
+  }
+}
+
+impl MessageTransport for MemoryRequester {
+  fn send_message(self: &mut MemoryRequester, message: &mut comm::Message) {
+    self.requests.send((serialize_message(message), self.answer.clone())).unwrap();
+  }
+}
+
+/// An in-memory publisher, messages to clients that are not subscribed are dropped.
+struct MemoryPublisher {
+  subscribers: Subscribers
+}
+
+impl Publisher for MemoryPublisher {
+  fn publish(self: &mut MemoryPublisher, workerid: WorkerID, message: &comm::Message) {
+    if let Some(subscriber) = self.subscribers.lock().unwrap().get(&workerid) {
+      let _ = subscriber.send(serialize_message(message));
+    }
+  }
+}
+
+/// The messages an in-memory publisher sends to one client.
+struct MemorySubscription {
+  messages: Receiver<Vec<u8>>
+}
+
+impl MessageSource for MemorySubscription {
+  fn receive_message(self: &mut MemorySubscription) -> comm::Message {
+    return parse_message(&self.messages.recv().unwrap()[..]);
+  }
+
+  fn try_receive_message(self: &mut MemorySubscription) -> Option<comm::Message> {
+    return self.messages.try_recv().ok().map(|bytes| parse_message(&bytes[..]));
+  }
+}
+
+/// Creates the connections between the server and the clients: ZeroMQ sockets from a shared
+/// context reaching each other over `protocol`, or in-memory channels with `Protocol::Memory`.
+/// Clones share the context and the in-memory endpoints.
+#[derive(Clone)]
+pub struct Network {
+  ctx: SharedContext,
+  protocol: Protocol,
+  endpoints: Arc<Mutex<MemoryEndpoints>>
+}
+
+impl Network {
+  pub fn new(ctx: SharedContext, protocol: Protocol) -> Network {
+    let endpoints = MemoryEndpoints { replies: HashMap::new(), publishers: HashMap::new() };
+    return Network { ctx: ctx, protocol: protocol, endpoints: Arc::new(Mutex::new(endpoints)) };
+  }
+
+  /// A network of processes that reach each other over TCP.
+  pub fn tcp() -> Network {
+    return Network::new(SharedContext::new(), Protocol::Tcp);
+  }
+
+  /// A network of threads that reach each other through in-memory channels.
+  pub fn memory() -> Network {
+    return Network::new(SharedContext::new(), Protocol::Memory);
+  }
+
+  /// The address of `port` on `host`.
+  pub fn address(self: &Network, host: &IpAddr, port: u16) -> String {
+    return self.protocol.address(host, port);
+  }
+
+  /// Bind a socket of `socket_type` to `port` on `host`, or to a free port if `port` is None.
+  /// Return the socket and the port.
+  fn bind(self: &Network, socket_type: zmq::SocketType, host: &IpAddr, port: Option<u16>) -> (Socket, u16) {
+    let mut socket = self.ctx.socket(socket_type);
+    match port {
+      None => {
+        loop {
+          let mut rng = rand::thread_rng();
+          let range = Range::new(2048, 65535);
+          let port = range.ind_sample(&mut rng);
+          match socket.bind(&self.address(host, port)[..]) {
+            Ok(()) => return (socket, port),
+            Err(_) => continue
+          }
+        }
+      }
+      Some(port) => {
+        match socket.bind(&self.address(host, port)[..]) {
+          Ok(()) => return (socket, port),
+          Err(err) => panic!("Could not bind socket. Make sure port {} is not used yet. {}", port, err)
+        }
+      }
+    }
+  }
+
+  /// Connect a socket of `socket_type` to `addr`.
+  fn connect(self: &Network, socket_type: zmq::SocketType, addr: &str) -> Socket {
+    let mut socket = self.ctx.socket(socket_type);
+    match socket.connect(addr) {
+      Ok(()) => return socket,
+      Err(err) => panic!("Could not connect socket to {}. {}", addr, err)
+    }
+  }
+
+  /// Accept requests on `port` of `host`, or on a free port if `port` is None. Each request must
+  /// be answered before the next one is received. Return the connection and the port.
+  pub fn bind_reply(self: &Network, host: &IpAddr, port: Option<u16>) -> (Box<MessageTransport>, u16) {
+    if self.protocol == Protocol::Memory {
+      let (reply, port) = self.bind_memory_reply(host, port);
+      return (Box::new(reply), port);
+    }
+    let (socket, port) = self.bind(zmq::REP, host, port);
+    return (Box::new(socket), port);
+  }
+
+  fn bind_memory_reply(self: &Network, host: &IpAddr, port: Option<u16>) -> (MemoryReply, u16) {
+    let mut endpoints = self.endpoints.lock().unwrap();
+    let port = match port {
+      Some(port) => port,
+      None => {
+        let mut rng = rand::thread_rng();
+        let range = Range::new(2048, 65535);
+        let mut port = range.ind_sample(&mut rng);
+        while endpoints.replies.contains_key(&self.address(host, port)) {
+          port = range.ind_sample(&mut rng);
+        }
+        port
+      }
+    };
+    match endpoints.reply(&self.address(host, port)).1.take() {
+      Some(requests) => return (MemoryReply { requests: requests, answer: None }, port),
+      None => panic!("Could not bind socket. Make sure port {} is not used yet.", port)
+    }
+  }
+
+  /// Connect to the end at `addr` that accepts requests. Each request must be followed by
+  /// receiving its answer.
+  pub fn connect_request(self: &Network, addr: &str) -> Box<MessageTransport> {
+    if self.protocol == Protocol::Memory {
+      let requests = self.endpoints.lock().unwrap().reply(addr).0.clone();
+      let (answer, answers) = mpsc::channel();
+      return Box::new(MemoryRequester { requests: requests, answer: answer, answers: answers });
+    }
+    return Box::new(self.connect(zmq::REQ, addr));
+  }
+
+  /// Publish messages to the clients on `port` of `host`.
+  pub fn bind_publisher(self: &Network, host: &IpAddr, port: u16) -> Box<Publisher> {
+    if self.protocol == Protocol::Memory {
+      let subscribers = self.endpoints.lock().unwrap().publisher(&self.address(host, port));
+      return Box::new(MemoryPublisher { subscribers: subscribers });
+    }
+    let (socket, _) = self.bind(zmq::PUB, host, Some(port));
+    return Box::new(ZmqPublisher { socket: socket });
+  }
+
+  /// Receive the messages the publisher at `addr` sends to client `workerid`.
+  pub fn subscribe(self: &Network, addr: &str, workerid: WorkerID) -> Box<MessageSource> {
+    if self.protocol == Protocol::Memory {
+      let subscribers = self.endpoints.lock().unwrap().publisher(addr);
+      let (sender, messages) = mpsc::channel();
+      subscribers.lock().unwrap().insert(workerid, sender);
+      return Box::new(MemorySubscription { messages: messages });
+    }
+    let mut socket = self.connect(zmq::SUB, addr);
+    socket.set_subscribe(topic(workerid).as_bytes()).unwrap();
+    return Box::new(ZmqSubscription { socket: socket });
+  }
+}
+
+/// One end of an in-memory connection. Messages are serialized like on a socket, so both ends
+/// see exactly what they would see over ZeroMQ.
+pub struct ChannelTransport {
+  sender: Sender<Vec<u8>>,
+  receiver: Receiver<Vec<u8>>
+}
+
+/// Create the two ends of an in-memory connection.
+pub fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+  let (left_sender, right_receiver) = mpsc::channel();
+  let (right_sender, left_receiver) = mpsc::channel();
+  let left = ChannelTransport { sender: left_sender, receiver: left_receiver };
+  let right = ChannelTransport { sender: right_sender, receiver: right_receiver };
+  return (left, right);
+}
+
+impl MessageSource for ChannelTransport {
+  fn receive_message(self: &mut ChannelTransport) -> comm::Message {
+    let bytes = self.receiver.recv().unwrap();
+    return parse_message(&bytes[..]);
+  }
+
+  fn try_receive_message(self: &mut ChannelTransport) -> Option<comm::Message> {
+    return self.receiver.try_recv().ok().map(|bytes| parse_message(&bytes[..]));
+  }
+}
+
+impl MessageTransport for ChannelTransport {
+  fn send_message(self: &mut ChannelTransport, message: &mut comm::Message) {
+    self.sender.send(serialize_message(message)).unwrap();
+  }
+}
+
+#[test]
+fn test_channel_pair() {
+  let (mut left, mut right) = channel_pair();
+  let mut msg = comm::Message::new();
+  msg.set_field_type(comm::MessageType::GET_NAME);
+  msg.set_name("x".into());
+  left.send_message(&mut msg);
+  assert!(left.try_receive_message().is_none());
+  let received = right.receive_message();
+  assert_eq!(received.get_field_type(), comm::MessageType::GET_NAME);
+  assert_eq!(received.get_name(), "x");
+  assert!(right.try_receive_message().is_none());
+}
+
+#[test]
+fn test_memory_network() {
+  use std::str::FromStr;
+  let network = Network::memory();
+  let localhost = IpAddr::from_str("127.0.0.1").unwrap();
+  // a client may connect before the server binds
+  let mut first = network.connect_request(&network.address(&localhost, 1));
+  let (mut reply, _) = network.bind_reply(&localhost, Some(1));
+  let mut second = network.connect_request(&network.address(&localhost, 1));
+  for (requester, name) in vec![(&mut first, "x"), (&mut second, "y")] {
+    let mut msg = comm::Message::new();
+    msg.set_field_type(comm::MessageType::GET_NAME);
+    msg.set_name(name.into());
+    requester.send_message(&mut msg);
+    let mut answer = reply.receive_message();
+    assert_eq!(answer.get_name(), name);
+    reply.send_message(&mut answer);
+    assert_eq!(requester.receive_message().get_name(), name);
+  }
+
+  // published messages only reach the client they are addressed to
+  let mut publisher = network.bind_publisher(&localhost, 2);
+  let mut subscription = network.subscribe(&network.address(&localhost, 2), 3);
+  let mut msg = comm::Message::new();
+  msg.set_field_type(comm::MessageType::HELLO);
+  publisher.publish(4, &msg);
+  assert!(subscription.try_receive_message().is_none());
+  publisher.publish(3, &msg);
+  assert_eq!(subscription.receive_message().get_field_type(), comm::MessageType::HELLO);
+}
@@ -4,21 +4,79 @@ use protobuf::Message;
 use protobuf::core::MessageStatic;
 use zmq;
 use zmq::{Socket};
-use std::io::Cursor;
-use std::ops::{Deref};
+use transport::{MessageSource, MessageTransport};
 use std::collections::HashMap;
 use std::net::IpAddr;
-use rand;
-use rand::distributions::{IndependentSample, Range};
+use std::sync::{Arc, Mutex};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 
 /// A unique identifier for an object stored on one of the workers.
 pub type ObjRef = u64;
 /// A unique identifier for a worker.
 pub type WorkerID = usize;
+/// A unique identifier for an actor.
+pub type ActorID = u64;
 /// For each object, contains a vector of worker ids that hold the object.
 pub type ObjTable = Vec<Vec<WorkerID>>;
 /// For each function, contains a sorted vector of worker ids that can execute the function.
 pub type FnTable = HashMap<String, Vec<WorkerID>>;
+/// Quantity of each named resource, for example "CPU" or "memory".
+pub type ResourceMap = HashMap<String, f64>;
+/// For each function, contains the resources that a single call of the function needs.
+pub type ResourceTable = HashMap<String, ResourceMap>;
+/// For each function, contains the signature it was registered with.
+pub type SignatureTable = HashMap<String, comm::FunctionSignature>;
+
+/// The resources a worker has or a call needs if nothing else is declared: one CPU.
+pub fn default_resources() -> ResourceMap {
+  let mut resources = HashMap::new();
+  resources.insert("CPU".into(), 1.0);
+  return resources;
+}
+
+pub fn resources_from_proto(resources: &comm::Resources) -> ResourceMap {
+  let mut result = HashMap::new();
+  for resource in resources.get_resource() {
+    *result.entry(resource.get_name().into()).or_insert(0.0) += resource.get_quantity();
+  }
+  return result;
+}
+
+pub fn resources_to_proto(resources: &ResourceMap) -> comm::Resources {
+  let mut result = comm::Resources::new();
+  for (name, quantity) in resources.iter() {
+    let mut resource = comm::Resource::new();
+    resource.set_name(name.clone());
+    resource.set_quantity(*quantity);
+    result.mut_resource().push(resource);
+  }
+  return result;
+}
+
+/// Test if `required` can be satisfied from the `available` resources.
+pub fn fits(available: &ResourceMap, required: &ResourceMap) -> bool {
+  for (name, quantity) in required.iter() {
+    if available.get(name).map_or(0.0, |x| *x) < *quantity {
+      return false;
+    }
+  }
+  return true;
+}
+
+/// Take `required` out of the `available` resources.
+pub fn acquire(available: &mut ResourceMap, required: &ResourceMap) {
+  for (name, quantity) in required.iter() {
+    *available.entry(name.clone()).or_insert(0.0) -= *quantity;
+  }
+}
+
+/// Give `required` back to the `available` resources.
+pub fn release(available: &mut ResourceMap, required: &ResourceMap) {
+  for (name, quantity) in required.iter() {
+    *available.entry(name.clone()).or_insert(0.0) += *quantity;
+  }
+}
 
 /// Given a predicate `absent` that can test if an object is unavailable on the client, compute
 /// which objects fom `args` still need to be send so the function call can be invoked.
@@ -49,6 +107,59 @@ fn test_args_to_send() {
   assert_eq!(res, vec!(3, 5));
 }
 
+#[test]
+fn test_resources() {
+  let mut available = default_resources();
+  available.insert("GPU".into(), 2.0);
+  let mut required: ResourceMap = HashMap::new();
+  required.insert("GPU".into(), 1.5);
+  assert!(fits(&available, &required));
+  acquire(&mut available, &required);
+  assert_eq!(available["GPU"], 0.5);
+  assert!(!fits(&available, &required));
+  assert!(fits(&available, &default_resources()));
+  release(&mut available, &required);
+  assert_eq!(available["GPU"], 2.0);
+  assert!(fits(&available, &required));
+  // a resource the worker doesn't have never fits, nothing always fits
+  let mut memory: ResourceMap = HashMap::new();
+  memory.insert("memory".into(), 1.0);
+  assert!(!fits(&available, &memory));
+  assert!(fits(&available, &HashMap::new()));
+}
+
+/// SHA-256 digest of the data of an object, used to find objects with the same content.
+pub fn content_hash(data: &[u8]) -> Vec<u8> {
+  let mut hasher = Sha256::new();
+  hasher.input(data);
+  let mut digest = vec![0; hasher.output_bytes()];
+  hasher.result(&mut digest);
+  return digest;
+}
+
+#[test]
+fn test_content_hash() {
+  assert_eq!(content_hash(&[1, 2, 3]).len(), 32);
+  assert_eq!(content_hash(&[1, 2, 3]), content_hash(&[1, 2, 3]));
+  assert!(content_hash(&[1, 2, 3]) != content_hash(&[1, 2, 4]));
+}
+
+/// Quote and escape a string for JSON.
+pub fn json_string(value: &str) -> String {
+  let mut result = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => result.push_str("\\\""),
+      '\\' => result.push_str("\\\\"),
+      '\n' => result.push_str("\\n"),
+      c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+      c => result.push(c)
+    }
+  }
+  result.push('"');
+  return result;
+}
+
 pub fn push_objrefs(args: &comm::Args, result: &mut Vec<ObjRef>) {
   for elem in args.get_objrefs() {
     if *elem >= 0 {
@@ -57,39 +168,25 @@ pub fn push_objrefs(args: &comm::Args, result: &mut Vec<ObjRef>) {
   }
 }
 
-/// Send a protocol buffer message on a socket.
-pub fn send_message(socket: &mut Socket, message: &mut comm::Message) {
-  let mut buf = Vec::new();
-  message.write_to_vec(&mut buf).unwrap();
-  socket.send(buf.as_slice(), 0).unwrap();
-}
-
-/// Receive a protocol buffer message over a socket.
-pub fn receive_message(socket: &mut Socket) -> comm::Message {
-  let mut msg = zmq::Message::new().unwrap();
-  socket.recv(&mut msg, 0).unwrap();
-  let mut input_stream = protobuf::CodedInputStream::from_bytes(msg.deref());
-  return protobuf::core::parse_from::<comm::Message>(&mut input_stream).unwrap();
+/// Send a protocol buffer message on a socket or another transport.
+pub fn send_message<T: MessageTransport + ?Sized>(socket: &mut T, message: &mut comm::Message) {
+  socket.send_message(message);
 }
 
-/// Receive a protocol buffer message through a subscription socket.
-pub fn receive_subscription(subscriber: &mut Socket) -> comm::Message {
-  let mut msg = zmq::Message::new().unwrap();
-  subscriber.recv(&mut msg, 0).unwrap();
-  let mut read_buf = Cursor::new(msg.as_mut());
-  read_buf.set_position(7);
-  return protobuf::parse_from_reader(&mut read_buf).unwrap();
+/// Receive a protocol buffer message over a socket or another transport.
+pub fn receive_message<T: MessageSource + ?Sized>(socket: &mut T) -> comm::Message {
+  return socket.receive_message();
 }
 
 /// Send an acknowledgement package.
-pub fn send_ack(socket: &mut Socket) {
+pub fn send_ack<T: MessageTransport + ?Sized>(socket: &mut T) {
   let mut ack = comm::Message::new();
   ack.set_field_type(comm::MessageType::ACK);
   send_message(socket, &mut ack);
 }
 
 /// Receive an acknowledgement package.
-pub fn receive_ack(socket: &mut Socket) {
+pub fn receive_ack<T: MessageSource + ?Sized>(socket: &mut T) {
   let ack = receive_message(socket);
   assert!(ack.get_field_type() == comm::MessageType::ACK);
 }
@@ -98,33 +195,45 @@ pub fn to_zmq_socket_addr(addr: &IpAddr, port: u16) -> String {
     return format!("tcp://{}:{}", addr, port).into();
 }
 
-/// Bind a ZeroMQ socket to specific address. If port is None, connect to a free port. Return port.
-pub fn bind_socket(socket: &mut Socket, host: &IpAddr, port: Option<u16>) -> u16 {
-  match port {
-    None => {
-      loop {
-        let mut rng = rand::thread_rng();
-        let range = Range::new(2048, 65535);
-        let port = range.ind_sample(&mut rng);
-        match socket.bind(&to_zmq_socket_addr(host, port)[..]) {
-          Ok(()) => { return port },
-          Err(err) => { continue }
-        }
-      }
-    }
-    Some(port) => {
-      match socket.bind(&to_zmq_socket_addr(host, port)[..]) {
-        Ok(()) => { return port },
-        Err(err) => { panic!("Could not bind socket. Make sure port {} is not used yet. {}", port, err) }
-      }
+/// How the server and the clients reach each other.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+  /// TCP sockets, the server and the clients can run on different machines.
+  Tcp,
+  /// ZeroMQ's inproc:// transport, the server and the clients are threads of one process and
+  /// create their sockets from the same `SharedContext`. Ports only tell endpoints apart.
+  Inproc,
+  /// In-memory channels instead of sockets, the server and the clients are threads of one process
+  /// and share one `Network`. Ports only tell endpoints apart.
+  Memory
+}
+
+impl Protocol {
+  /// The address of `port` on `host`.
+  pub fn address(self: &Protocol, host: &IpAddr, port: u16) -> String {
+    match *self {
+      Protocol::Tcp => to_zmq_socket_addr(host, port),
+      Protocol::Inproc => format!("inproc://orchestra-{}", port),
+      Protocol::Memory => format!("memory://orchestra-{}", port)
     }
   }
 }
 
-/// Connect a ZeroMQ socket to specific address
-pub fn connect_socket(socket: &mut Socket, host: &IpAddr, port: u16) {
-    match socket.connect(&to_zmq_socket_addr(host, port)[..]) {
-        Ok(()) => {},
-        Err(_) => { panic!("Could not connect socket. Make sure port is set correctly.") }
-    }
+/// A ZeroMQ context that can be shared between threads. Sockets can only reach each other over
+/// inproc:// if they were created from the same context.
+#[derive(Clone)]
+pub struct SharedContext {
+  ctx: Arc<Mutex<zmq::Context>>
+}
+
+impl SharedContext {
+  pub fn new() -> SharedContext {
+    return SharedContext { ctx: Arc::new(Mutex::new(zmq::Context::new())) };
+  }
+
+  /// Create a new socket of type `socket_type`.
+  pub fn socket(self: &SharedContext, socket_type: zmq::SocketType) -> Socket {
+    return self.ctx.lock().unwrap().socket(socket_type).unwrap();
+  }
 }
+
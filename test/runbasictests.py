@@ -89,7 +89,7 @@ class ClientTest(unittest.TestCase):
 
   def testConnect(self):
     self.client_port = get_unused_port()
-    op.context.connect("127.0.0.1", self.incoming_port, self.publish_port, "127.0.0.1", self.client_port)
+    op.context.connect_driver("127.0.0.1", self.incoming_port, self.publish_port, "127.0.0.1", self.client_port)
     op.context.debug_info()
 
     time.sleep(1.0) # todo(pcmoritz) fix this
@@ -24,7 +24,7 @@ class OrchestraTest(unittest.TestCase):
         self.master = subprocess.Popen(["cargo", "run", "--bin", "orchestra", "--", str(incoming_port), str(publish_port)], env=dict(os.environ, RUST_BACKTRACE="1"), preexec_fn=os.setsid)
         self.workers = map(lambda worker: subprocess.Popen(["python", "mapreduce.py", str(incoming_port), str(get_unused_port()), str(publish_port)], preexec_fn=os.setsid), range(numworkers))
         self.workers = map(lambda worker: subprocess.Popen(["python", "matmul.py", str(incoming_port), str(get_unused_port()), str(publish_port)], preexec_fn=os.setsid), range(numworkers))
-        op.context.connect("127.0.0.1", incoming_port, publish_port, "127.0.0.1", get_unused_port())
+        op.context.connect_driver("127.0.0.1", incoming_port, publish_port, "127.0.0.1", get_unused_port())
 
     def tearDown(self):
         os.killpg(self.master.pid, signal.SIGTERM)